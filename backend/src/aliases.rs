@@ -0,0 +1,462 @@
+use serde::{Deserialize, Serialize};
+
+use crate::indexing::InsertHandle;
+use crate::schemas::{AppError, ErrorKind, PostEntity};
+use crate::search::{ItemRepo, SearchCache, SearchDb};
+use crate::tokenizer::{analyze, Language};
+
+pub trait AliasStore
+where
+    Self: Sync + Send,
+{
+    // Every alias, sorted by phrase.
+    fn get_aliases(
+        &self,
+    ) -> impl std::future::Future<Output = Result<Vec<Alias>, AppError>> + std::marker::Send;
+}
+
+// A phrase searched as the given tags instead of its own words.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Alias {
+    pub phrase: String,
+    pub tags: Vec<String>,
+}
+
+// Phrases and tags are analyzed as queries and posts are, or they would never match.
+fn analyzed(text: &str, lang: Language) -> String {
+    analyze(text, lang).join(" ")
+}
+
+// Analyzes an alias, rejecting phrases searches never look up, being longer
+// than `phrase_max` words, and tags of more words than the pairs indexed.
+fn analyzed_alias(
+    phrase: &str,
+    tags: &[String],
+    lang: Language,
+    phrase_max: usize,
+) -> Result<Alias, AppError> {
+    let bad_request = |reason: String| AppError::new(ErrorKind::BadRequest, reason);
+    let phrase = analyzed(phrase, lang);
+    if phrase.split(' ').count() > phrase_max {
+        return Err(bad_request(format!(
+            "\"{phrase}\" is longer than the {phrase_max} words searched as a phrase"
+        )));
+    }
+    let mut analyzed_tags = vec![];
+    for tag in tags {
+        // A tag of two words stays a pair tag, matching them next to each other.
+        match analyze(tag, lang).len() {
+            0 => continue,
+            1 | 2 => analyzed_tags.push(analyzed(tag, lang)),
+            _ => {
+                return Err(bad_request(format!(
+                    "\"{tag}\" has more than the two words indexed together"
+                )))
+            }
+        }
+    }
+    analyzed_tags.sort();
+    analyzed_tags.dedup();
+    if phrase.is_empty() || analyzed_tags.is_empty() {
+        return Err(bad_request("an alias needs a phrase and tags".to_string()));
+    }
+    Ok(Alias {
+        phrase,
+        tags: analyzed_tags,
+    })
+}
+
+async fn save_alias(
+    db: &(impl ItemRepo<String, String, PostEntity, AppError>
+          + InsertHandle<String, String, PostEntity, AppError>),
+    alias: Alias,
+) -> Result<Alias, AppError> {
+    // Searches cached under what the phrase resolved to so far are stale.
+    let stale = db.get_db().get_tags_from_phrase(&alias.phrase).await?;
+    db.insert_alias(alias.phrase.clone(), alias.tags.clone())
+        .await?;
+    db.get_cache()
+        .invalidate_tags(stale.into_iter().chain(alias.tags.clone()).collect())
+        .await?;
+    Ok(alias)
+}
+
+pub async fn add_alias(
+    db: &(impl ItemRepo<String, String, PostEntity, AppError>
+          + InsertHandle<String, String, PostEntity, AppError>),
+    phrase: &str,
+    tags: Vec<String>,
+    lang: Language,
+    phrase_max: usize,
+) -> Result<Alias, AppError> {
+    save_alias(db, analyzed_alias(phrase, &tags, lang, phrase_max)?).await
+}
+
+pub async fn remove_alias(
+    db: &(impl ItemRepo<String, String, PostEntity, AppError>
+          + InsertHandle<String, String, PostEntity, AppError>),
+    phrase: &str,
+    lang: Language,
+) -> Result<(), AppError> {
+    let phrase = analyzed(phrase, lang);
+    let stale = db.get_db().get_tags_from_phrase(&phrase).await?;
+    db.remove_alias(phrase.clone()).await?;
+    db.get_cache()
+        .invalidate_tags(stale.into_iter().chain(std::iter::once(phrase)).collect())
+        .await
+}
+
+// Parses a Solr style synonyms file. "a, b => c" searches a and b as c,
+// "a, b, c" makes each of them search as all of them.
+pub fn parse_synonyms(text: &str) -> Result<Vec<(String, Vec<String>)>, AppError> {
+    let split = |terms: &str| -> Vec<String> {
+        terms
+            .split(',')
+            .map(|term| term.trim().to_string())
+            .filter(|term| !term.is_empty())
+            .collect()
+    };
+    let mut aliases = vec![];
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (phrases, tags) = match line.split_once("=>") {
+            Some((phrases, tags)) => (split(phrases), split(tags)),
+            None => (split(line), split(line)),
+        };
+        if phrases.is_empty() || tags.is_empty() {
+            return Err(AppError::new(
+                ErrorKind::BadRequest,
+                format!("line {}: expected \"a, b => c\" or \"a, b, c\"", number + 1),
+            ));
+        }
+        aliases.extend(phrases.into_iter().map(|phrase| (phrase, tags.clone())));
+    }
+    Ok(aliases)
+}
+
+// Returns the aliases added, the file is checked whole before adding any.
+pub async fn import_synonyms(
+    db: &(impl ItemRepo<String, String, PostEntity, AppError>
+          + InsertHandle<String, String, PostEntity, AppError>),
+    text: &str,
+    lang: Language,
+    phrase_max: usize,
+) -> Result<Vec<Alias>, AppError> {
+    let aliases = parse_synonyms(text)?
+        .into_iter()
+        .map(|(phrase, tags)| analyzed_alias(&phrase, &tags, lang, phrase_max))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut added = vec![];
+    for alias in aliases {
+        added.push(save_alias(db, alias).await?);
+    }
+    Ok(added)
+}
+
+pub const CLI_USAGE: &str = "usage: ribbit aliases [--lang en|fr] list
+       ribbit aliases [--lang en|fr] add <phrase> <tag>...
+       ribbit aliases [--lang en|fr] remove <phrase>
+       ribbit aliases [--lang en|fr] import <synonyms file>";
+
+// Runs `ribbit aliases ...`, args are the ones following "aliases".
+pub async fn run_cli(
+    db: &(impl ItemRepo<String, String, PostEntity, AppError>
+          + InsertHandle<String, String, PostEntity, AppError>),
+    store: &impl AliasStore,
+    phrase_max: usize,
+    mut args: Vec<String>,
+) -> Result<(), AppError> {
+    let mut lang = Language::default();
+    if args.first().map(String::as_str) == Some("--lang") && args.len() > 1 {
+        lang = Language::from_code(&args[1]);
+        args.drain(..2);
+    }
+    let usage = || AppError::new(ErrorKind::BadRequest, CLI_USAGE);
+    let print = |alias: &Alias| println!("{} => {}", alias.phrase, alias.tags.join(", "));
+    match args.split_first() {
+        Some((command, [])) if command == "list" => {
+            store.get_aliases().await?.iter().for_each(print);
+        }
+        Some((command, [phrase, tags @ ..])) if command == "add" && !tags.is_empty() => {
+            print(&add_alias(db, phrase, tags.to_vec(), lang, phrase_max).await?);
+        }
+        Some((command, [phrase])) if command == "remove" => {
+            remove_alias(db, phrase, lang).await?;
+        }
+        Some((command, [path])) if command == "import" => {
+            let text = std::fs::read_to_string(path)
+                .map_err(|err| AppError::new(ErrorKind::BadRequest, format!("{path}: {err}")))?;
+            import_synonyms(db, &text, lang, phrase_max)
+                .await?
+                .iter()
+                .for_each(print);
+        }
+        _ => return Err(usage()),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::body::Body;
+    use axum::http::header::{CONTENT_TYPE, COOKIE};
+    use axum::http::{Method, Request, StatusCode};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::backend::tests::{post, user};
+    use crate::backend::Backend;
+    use crate::config::Config;
+    use crate::memory::InMemoryRepository;
+    use crate::search::SearchOptions;
+    use crate::services::{find_posts, register_post};
+    use crate::users::UserStore;
+    use crate::{router, templates, Repositories};
+
+    async fn recipes() -> InMemoryRepository {
+        let db = InMemoryRepository::default();
+        let alice = user("alice");
+        for title in ["Homemade soft drink", "Soft cheese with a drink"] {
+            register_post(db.clone(), &db, post(&alice, title))
+                .await
+                .unwrap();
+        }
+        db
+    }
+
+    async fn search_titles(db: &InMemoryRepository, query: &str) -> Vec<String> {
+        find_posts(
+            db.clone(),
+            db,
+            db,
+            None,
+            query,
+            SearchOptions::default(),
+            1,
+            10,
+        )
+        .await
+        .unwrap()
+        .objects
+        .into_iter()
+        .map(|post| post.title)
+        .collect()
+    }
+
+    #[tokio::test]
+    async fn test_aliases_change_search_results() {
+        let db = recipes().await;
+        assert!(search_titles(&db, "soda").await.is_empty());
+
+        // The two words of the tag have to be next to each other.
+        let alias = add_alias(
+            &db,
+            "Soda",
+            vec!["soft drinks".to_string()],
+            Language::En,
+            1,
+        )
+        .await
+        .unwrap();
+        assert_eq!(alias.tags, vec!["soft drink"]);
+        assert_eq!(
+            search_titles(&db, "soda").await,
+            vec!["Homemade soft drink"]
+        );
+
+        remove_alias(&db, "soda", Language::En).await.unwrap();
+        assert!(search_titles(&db, "soda").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_aliases_invalidate_cached_searches() {
+        let db = recipes().await;
+        let key = SearchOptions::default().cache_key("soda");
+        search_titles(&db, "soda").await;
+        assert!(db.get_cached_page(&key, 0, 10).await.unwrap().is_some());
+
+        add_alias(&db, "soda", vec!["drink".to_string()], Language::En, 1)
+            .await
+            .unwrap();
+        assert_eq!(db.get_cached_page(&key, 0, 10).await.unwrap(), None);
+        assert_eq!(search_titles(&db, "soda").await.len(), 2);
+        assert!(db.get_cached_page(&key, 0, 10).await.unwrap().is_some());
+
+        remove_alias(&db, "soda", Language::En).await.unwrap();
+        assert_eq!(db.get_cached_page(&key, 0, 10).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_unsearchable_aliases_are_rejected() {
+        let db = InMemoryRepository::default();
+        let tags = vec!["soft drink".to_string()];
+        let err = add_alias(&db, "fizzy pop", tags.clone(), Language::En, 1)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadRequest);
+        add_alias(&db, "fizzy pop", tags, Language::En, 2)
+            .await
+            .unwrap();
+
+        let tags = vec!["sweet soft drink".to_string()];
+        let err = add_alias(&db, "soda", tags, Language::En, 2)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadRequest);
+    }
+
+    #[tokio::test]
+    async fn test_import_synonyms() {
+        let db = recipes().await;
+        let added = import_synonyms(
+            &db,
+            "soda, pop => soft drink\nfromage, cheese",
+            Language::En,
+            1,
+        )
+        .await
+        .unwrap();
+        assert_eq!(added.len(), 4);
+        assert_eq!(search_titles(&db, "pop").await, vec!["Homemade soft drink"]);
+        assert_eq!(
+            search_titles(&db, "fromage").await,
+            vec!["Soft cheese with a drink"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_import_checks_every_line_first() {
+        let db = InMemoryRepository::default();
+        let synonyms = "soda => soft drink\ncola => sweet soft drink";
+        let err = import_synonyms(&db, synonyms, Language::En, 1)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadRequest);
+        assert!(db.get_aliases().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_run_cli() {
+        let db = InMemoryRepository::default();
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect();
+        run_cli(&db, &db, 1, args(&["add", "Sodas", "soft drink", "pop"]))
+            .await
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("ribbit-{}.txt", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "tv, television").unwrap();
+        run_cli(
+            &db,
+            &db,
+            1,
+            args(&["--lang", "fr", "import", path.to_str().unwrap()]),
+        )
+        .await
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        run_cli(&db, &db, 1, args(&["list"])).await.unwrap();
+        let phrases: Vec<_> = db
+            .get_aliases()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|alias| alias.phrase)
+            .collect();
+        // Imported with the French stemmer.
+        assert_eq!(phrases, vec!["soda", "televis", "tv"]);
+
+        run_cli(&db, &db, 1, args(&["remove", "soda"]))
+            .await
+            .unwrap();
+        assert_eq!(db.get_aliases().await.unwrap().len(), 2);
+        for bad in [&["add", "soda"][..], &["list", "all"], &["rename"], &[]] {
+            let err = run_cli(&db, &db, 1, args(bad)).await.unwrap_err();
+            assert_eq!(err.reason, CLI_USAGE);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_routes_are_for_site_admins() {
+        let db = InMemoryRepository::default();
+        let (admin, bob) = (user("alice"), user("bob"));
+        for (user, token) in [(&admin, "admin"), (&bob, "bob")] {
+            db.insert_user(user.clone()).await.unwrap();
+            db.insert_session(token, user.id, 60).await.unwrap();
+        }
+        let app = router(Repositories {
+            db: db.clone(),
+            hb: templates(),
+            config: Arc::new(Config {
+                admins: vec![admin.handle],
+                ..Config::default()
+            }),
+        });
+        let request = |method: Method, uri: &str, body: &str, token: &str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(COOKIE, format!("session={token}"))
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+        let form = r#"{"phrase": "soda", "tags": ["pop"]}"#;
+        let requests = [
+            (Method::GET, "/en/aliases", ""),
+            (Method::POST, "/en/aliases", form),
+            (Method::POST, "/en/aliases/import", "soda => pop"),
+            (Method::DELETE, "/en/aliases/soda", ""),
+        ];
+
+        for (method, uri, body) in requests.clone() {
+            let response = app
+                .clone()
+                .oneshot(request(method, uri, body, "bob"))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        }
+        assert!(db.get_alias_store().get_aliases().await.unwrap().is_empty());
+        for (method, uri, body) in requests {
+            let response = app
+                .clone()
+                .oneshot(request(method, uri, body, "admin"))
+                .await
+                .unwrap();
+            assert!(response.status().is_success());
+        }
+    }
+
+    #[test]
+    fn test_parse_explicit_mapping() {
+        assert_eq!(
+            parse_synonyms("# drinks\nsoda, pop => soft drink\n\n").unwrap(),
+            vec![
+                ("soda".to_string(), vec!["soft drink".to_string()]),
+                ("pop".to_string(), vec!["soft drink".to_string()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_equivalent_terms() {
+        let all = vec!["tv".to_string(), "television".to_string()];
+        assert_eq!(
+            parse_synonyms("tv, television").unwrap(),
+            vec![
+                ("tv".to_string(), all.clone()),
+                ("television".to_string(), all)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_empty_side() {
+        let err = parse_synonyms("soda =>").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadRequest);
+    }
+}
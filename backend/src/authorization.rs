@@ -0,0 +1,271 @@
+use crate::groups::GroupStore;
+use crate::schemas::{AppError, GroupId, PostEntity, UserEntity};
+
+impl PostEntity {
+    // The group allowed to read the post, None means public.
+    pub fn visibility_group(&self) -> Option<GroupId> {
+        self.visibility_scope.or(self.space)
+    }
+
+    // The group allowed to reply to the post, None means anyone.
+    pub fn reply_group(&self) -> Option<GroupId> {
+        self.reply_scope.or(self.space)
+    }
+}
+
+fn is_in_group(group: Option<GroupId>, viewer: Option<&UserEntity>) -> bool {
+    match (group, viewer) {
+        (None, _) => true,
+        (Some(_), None) => false,
+        (Some(group_id), Some(user)) => user.groups.contains(&group_id),
+    }
+}
+
+pub fn can_view(post: &PostEntity, viewer: Option<&UserEntity>) -> bool {
+    is_in_group(post.visibility_group(), viewer)
+}
+
+// Anonymous readers never can, they have to sign in first.
+pub fn can_reply(post: &PostEntity, viewer: Option<&UserEntity>) -> bool {
+    viewer.is_some() && is_in_group(post.reply_group(), viewer)
+}
+
+// Face posts can be changed by the admin who published them, not by every admin.
+pub fn can_edit(post: &PostEntity, user: &UserEntity) -> bool {
+    match post.publisher {
+        Some(publisher) => publisher == user.id,
+        None => post.author == user.handle,
+    }
+}
+
+// Authors can only scope a post to groups they belong to, and only post in
+// those they admin when members aren't allowed to.
+pub async fn can_publish(
+    groups: &impl GroupStore,
+    post: &PostEntity,
+    user: &UserEntity,
+) -> Result<bool, AppError> {
+    if ![post.space, post.visibility_scope, post.reply_scope]
+        .into_iter()
+        .all(|group| is_in_group(group, Some(user)))
+    {
+        return Ok(false);
+    }
+    for group_id in [post.space, post.visibility_scope].into_iter().flatten() {
+        let group = groups.get_group(group_id).await?;
+        if !group.allow_member_posting && !group.is_admin(user.id) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+
+    use super::*;
+    use crate::groups::{create_group, join_group};
+    use crate::memory::InMemoryRepository;
+    use crate::schemas::GroupManagement;
+    use crate::search::SearchOptions;
+    use crate::services::{find_posts, register_post};
+    use crate::tokenizer::Language;
+    use crate::users::UserStore;
+
+    async fn user(db: &InMemoryRepository, handle: &str) -> UserEntity {
+        let user = UserEntity {
+            id: uuid::Uuid::new_v4(),
+            handle: handle.to_string(),
+            password_hash: String::new(),
+            groups: vec![],
+            block_list: vec![],
+            blocked_by: vec![],
+            version: 0,
+        };
+        db.insert_user(user.clone()).await.unwrap();
+        user
+    }
+
+    fn post(user: &UserEntity, title: &str) -> PostEntity {
+        PostEntity {
+            title: title.to_string(),
+            slug: slug::slugify(title),
+            author: user.handle.clone(),
+            search_tags: vec![],
+            body: "<p>Mix, then bake.</p>".to_string(),
+            space: None,
+            reply_scope: None,
+            visibility_scope: None,
+            parent: None,
+            publisher: Some(user.id),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            lang: Language::En,
+        }
+    }
+
+    async fn group(
+        db: &InMemoryRepository,
+        admin: &UserEntity,
+        allow_member_posting: bool,
+    ) -> GroupId {
+        create_group(
+            db,
+            db,
+            admin.clone(),
+            "Bakers".to_string(),
+            GroupManagement::Open,
+            allow_member_posting,
+        )
+        .await
+        .unwrap()
+        .id
+    }
+
+    #[tokio::test]
+    async fn test_view_and_reply() {
+        let db = InMemoryRepository::default();
+        let (alice, bob) = (user(&db, "alice").await, user(&db, "bob").await);
+        let group_id = group(&db, &alice, true).await;
+        let alice = db.get_user(alice.id).await.unwrap();
+
+        let public = post(&alice, "Cake");
+        assert!(can_view(&public, None));
+        assert!(can_view(&public, Some(&bob)));
+        assert!(!can_reply(&public, None));
+        assert!(can_reply(&public, Some(&bob)));
+
+        let mut hidden = post(&alice, "Cake");
+        hidden.visibility_scope = Some(group_id);
+        assert!(!can_view(&hidden, None));
+        assert!(!can_view(&hidden, Some(&bob)));
+        assert!(can_view(&hidden, Some(&alice)));
+
+        // Replies can be kept to the group while anyone reads.
+        let mut members_reply = post(&alice, "Cake");
+        members_reply.reply_scope = Some(group_id);
+        assert!(can_view(&members_reply, Some(&bob)));
+        assert!(!can_reply(&members_reply, Some(&bob)));
+        assert!(can_reply(&members_reply, Some(&alice)));
+
+        // A space scopes both.
+        let mut in_space = post(&alice, "Cake");
+        in_space.space = Some(group_id);
+        assert_eq!(in_space.visibility_group(), Some(group_id));
+        assert_eq!(in_space.reply_group(), Some(group_id));
+        assert!(!can_view(&in_space, Some(&bob)));
+    }
+
+    #[tokio::test]
+    async fn test_edit() {
+        let db = InMemoryRepository::default();
+        let (alice, bob) = (user(&db, "alice").await, user(&db, "bob").await);
+        let own = post(&alice, "Cake");
+        assert!(can_edit(&own, &alice));
+        assert!(!can_edit(&own, &bob));
+
+        // Posted as a face, the publisher is who can edit.
+        let mut as_face = post(&alice, "Cake");
+        as_face.author = "bakers".to_string();
+        assert!(can_edit(&as_face, &alice));
+        let mut legacy = post(&alice, "Cake");
+        legacy.publisher = None;
+        assert!(can_edit(&legacy, &alice));
+        assert!(!can_edit(&legacy, &bob));
+    }
+
+    #[tokio::test]
+    async fn test_publish() {
+        let db = InMemoryRepository::default();
+        let (alice, bob, carol) = (
+            user(&db, "alice").await,
+            user(&db, "bob").await,
+            user(&db, "carol").await,
+        );
+        let open = group(&db, &alice, true).await;
+        let announcements = group(&db, &alice, false).await;
+        for group_id in [open, announcements] {
+            let bob = db.get_user(bob.id).await.unwrap();
+            join_group(&db, &db, bob, group_id).await.unwrap();
+        }
+        let (alice, bob) = (
+            db.get_user(alice.id).await.unwrap(),
+            db.get_user(bob.id).await.unwrap(),
+        );
+
+        assert!(can_publish(&db, &post(&carol, "Cake"), &carol)
+            .await
+            .unwrap());
+        let mut in_open = post(&bob, "Cake");
+        in_open.visibility_scope = Some(open);
+        assert!(can_publish(&db, &in_open, &bob).await.unwrap());
+        assert!(!can_publish(&db, &in_open, &carol).await.unwrap());
+
+        // Only admins post where members can't.
+        let mut in_announcements = post(&bob, "Cake");
+        in_announcements.space = Some(announcements);
+        assert!(!can_publish(&db, &in_announcements, &bob).await.unwrap());
+        assert!(can_publish(&db, &in_announcements, &alice).await.unwrap());
+        // Restricting replies to the group isn't posting in it.
+        let mut replies_in_announcements = post(&bob, "Cake");
+        replies_in_announcements.reply_scope = Some(announcements);
+        assert!(can_publish(&db, &replies_in_announcements, &bob)
+            .await
+            .unwrap());
+    }
+
+    async fn search_page(
+        db: &InMemoryRepository,
+        viewer: Option<&UserEntity>,
+        page_num: usize,
+    ) -> (Vec<String>, usize) {
+        let page = find_posts(
+            db.clone(),
+            db,
+            db,
+            viewer,
+            "cake",
+            SearchOptions::default(),
+            page_num,
+            2,
+        )
+        .await
+        .unwrap();
+        (
+            page.objects.into_iter().map(|post| post.title).collect(),
+            page.total_objects,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_hidden_posts_left_out_of_pages() {
+        let db = InMemoryRepository::default();
+        let alice = user(&db, "alice").await;
+        let group_id = group(&db, &alice, true).await;
+        let alice = db.get_user(alice.id).await.unwrap();
+        // Public posts are the newest, ranked first.
+        for n in 0..8 {
+            let mut item = post(&alice, &format!("Cake {n}"));
+            item.created_at = Utc::now() - Duration::minutes(n);
+            if n >= 5 {
+                item.visibility_scope = Some(group_id);
+            }
+            register_post(db.clone(), &db, item).await.unwrap();
+        }
+
+        // Hidden posts are left out of the total from the first page on.
+        let (titles, total) = search_page(&db, None, 1).await;
+        assert_eq!(titles, vec!["Cake 0", "Cake 1"]);
+        assert_eq!(total, 5);
+        assert_eq!(search_page(&db, None, 2).await.0, vec!["Cake 2", "Cake 3"]);
+        let (titles, total) = search_page(&db, None, 3).await;
+        assert_eq!(titles, vec!["Cake 4"]);
+        assert_eq!(total, 5);
+        assert_eq!(search_page(&db, None, 4).await, (vec![], 5));
+
+        let (titles, total) = search_page(&db, Some(&alice), 4).await;
+        assert_eq!(titles, vec!["Cake 6", "Cake 7"]);
+        assert_eq!(total, 8);
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::schemas::{AppError, AuthorEntity, AuthorId};
+
+pub trait AuthorStore
+where
+    Self: Sync + Send,
+{
+    fn get_author(
+        &self,
+        author_id: &str,
+    ) -> impl std::future::Future<Output = Result<Option<AuthorEntity>, AppError>> + std::marker::Send;
+    // Batched lookup, results are in the same order as author_ids.
+    fn get_authors(
+        &self,
+        author_ids: Vec<AuthorId>,
+    ) -> impl std::future::Future<Output = Result<Vec<Option<AuthorEntity>>, AppError>> + std::marker::Send;
+    // Returns false, and stores nothing, if the author id is already taken.
+    fn insert_author(
+        &self,
+        author: AuthorEntity,
+    ) -> impl std::future::Future<Output = Result<bool, AppError>> + std::marker::Send;
+    fn remove_author(
+        &self,
+        author_id: &str,
+    ) -> impl std::future::Future<Output = Result<(), AppError>> + std::marker::Send;
+    fn insert_author_post(
+        &self,
+        author_id: &str,
+        item_ref: String,
+    ) -> impl std::future::Future<Output = Result<(), AppError>> + std::marker::Send;
+    fn remove_author_post(
+        &self,
+        author_id: &str,
+        item_ref: String,
+    ) -> impl std::future::Future<Output = Result<(), AppError>> + std::marker::Send;
+    fn get_author_post_refs(
+        &self,
+        author_id: &str,
+    ) -> impl std::future::Future<Output = Result<Vec<String>, AppError>> + std::marker::Send;
+}
+
+impl AuthorEntity {
+    // Stand-in for posts whose author has no profile, so pages still render.
+    pub fn unknown(author_id: AuthorId) -> Self {
+        Self {
+            name: author_id.clone(),
+            author_id,
+            profile_picture: "".to_string(),
+        }
+    }
+}
+
+pub async fn resolve_authors(
+    db: &impl AuthorStore,
+    author_ids: impl IntoIterator<Item = AuthorId>,
+) -> Result<HashMap<AuthorId, AuthorEntity>, AppError> {
+    let author_ids: Vec<_> = author_ids
+        .into_iter()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    if author_ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let authors = db.get_authors(author_ids.clone()).await?;
+    Ok(author_ids
+        .into_iter()
+        .zip(authors)
+        .map(|(author_id, author)| {
+            (
+                author_id.clone(),
+                author.unwrap_or_else(|| AuthorEntity::unknown(author_id)),
+            )
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::InMemoryRepository;
+
+    fn author(author_id: &str, name: &str) -> AuthorEntity {
+        AuthorEntity {
+            author_id: author_id.to_string(),
+            name: name.to_string(),
+            profile_picture: "".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_author_ids_are_claimed_once() {
+        let db = InMemoryRepository::default();
+        assert!(db.insert_author(author("alice", "Alice")).await.unwrap());
+        assert!(!db.insert_author(author("alice", "Mallory")).await.unwrap());
+        assert_eq!(db.get_author("alice").await.unwrap().unwrap().name, "Alice");
+
+        db.remove_author("alice").await.unwrap();
+        assert!(db.get_author("alice").await.unwrap().is_none());
+        assert!(db.insert_author(author("alice", "Alice")).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_authors() {
+        let db = InMemoryRepository::default();
+        db.insert_author(author("alice", "Alice")).await.unwrap();
+        let authors = resolve_authors(
+            &db,
+            ["alice", "bob", "alice"].map(|author_id| author_id.to_string()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(authors.len(), 2);
+        assert_eq!(authors["alice"].name, "Alice");
+        // Unknown authors still get a name to render.
+        assert_eq!(authors["bob"].name, "bob");
+        assert!(resolve_authors(&db, vec![]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_author_posts() {
+        let db = InMemoryRepository::default();
+        db.insert_author_post("alice", "cake".to_string())
+            .await
+            .unwrap();
+        db.insert_author_post("alice", "cake".to_string())
+            .await
+            .unwrap();
+        db.insert_author_post("alice", "bread".to_string())
+            .await
+            .unwrap();
+        let mut post_refs = db.get_author_post_refs("alice").await.unwrap();
+        post_refs.sort();
+        assert_eq!(post_refs, vec!["bread", "cake"]);
+
+        db.remove_author_post("alice", "cake".to_string())
+            .await
+            .unwrap();
+        assert_eq!(
+            db.get_author_post_refs("alice").await.unwrap(),
+            vec!["bread"]
+        );
+        assert!(db.get_author_post_refs("bob").await.unwrap().is_empty());
+    }
+}
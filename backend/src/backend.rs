@@ -0,0 +1,322 @@
+use crate::aliases::AliasStore;
+use crate::authors::AuthorStore;
+use crate::groups::GroupStore;
+use crate::indexing::InsertHandle;
+use crate::pow::PowValidator;
+use crate::schemas::{AppError, PostEntity};
+use crate::search::ItemRepo;
+use crate::suggest::SuggestStore;
+use crate::threads::ThreadStore;
+use crate::users::UserStore;
+
+// Everything the app needs from its storage, so that it runs over any of them.
+pub trait Backend
+where
+    Self: ItemRepo<String, String, PostEntity, AppError>
+        + InsertHandle<String, String, PostEntity, AppError>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+{
+    fn get_pow_validator(&self) -> impl PowValidator + Send + Sync;
+    fn get_user_store(&self) -> impl UserStore;
+    fn get_author_store(&self) -> impl AuthorStore;
+    fn get_group_store(&self) -> impl GroupStore;
+    fn get_thread_store(&self) -> impl ThreadStore;
+    fn get_suggest_store(&self) -> impl SuggestStore;
+    fn get_alias_store(&self) -> impl AliasStore;
+    // Counters in the Prometheus text format.
+    fn render_metrics(&self) -> String;
+}
+
+// The same checks run over every store, each one calling them from its own tests.
+#[cfg(test)]
+pub mod tests {
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::blocking::block_user;
+    use crate::schemas::{ErrorKind, GroupEntity, GroupManagement, UserEntity};
+    use crate::scoring::CorpusStats;
+    use crate::search::{SearchDb, SearchOptions};
+    use crate::services::{delete_post, edit_post, find_posts, register_post};
+    use crate::suggest::TitleSuggestion;
+    use crate::tokenizer::Language;
+
+    pub fn user(handle: &str) -> UserEntity {
+        UserEntity {
+            id: Uuid::new_v4(),
+            handle: handle.to_string(),
+            password_hash: String::new(),
+            groups: vec![],
+            block_list: vec![],
+            blocked_by: vec![],
+            version: 0,
+        }
+    }
+
+    pub fn post(user: &UserEntity, title: &str) -> PostEntity {
+        PostEntity {
+            title: title.to_string(),
+            slug: slug::slugify(title),
+            author: user.handle.clone(),
+            search_tags: vec![],
+            body: "<p>Mix, then bake.</p>".to_string(),
+            space: None,
+            reply_scope: None,
+            visibility_scope: None,
+            parent: None,
+            publisher: Some(user.id),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            lang: Language::En,
+        }
+    }
+
+    async fn search_titles(db: &impl Backend, query: &str) -> Vec<String> {
+        find_posts(
+            db.clone(),
+            &db.get_author_store(),
+            &db.get_user_store(),
+            None,
+            query,
+            SearchOptions::default(),
+            1,
+            20,
+        )
+        .await
+        .unwrap()
+        .objects
+        .into_iter()
+        .map(|post| post.title)
+        .collect()
+    }
+
+    pub async fn post_round_trip(db: impl Backend) {
+        let mut item = post(&user("alice"), "Gâteau au beurre noisette");
+        item.search_tags = vec!["gateau".to_string(), "beurr".to_string()];
+        item.space = Some(Uuid::new_v4());
+        item.lang = Language::Fr;
+        assert!(db.insert_new_item(item.clone()).await.unwrap());
+        assert!(!db.insert_new_item(item.clone()).await.unwrap());
+
+        let stored = db
+            .get_db()
+            .get_item_from_ref(item.slug.clone())
+            .await
+            .unwrap();
+        assert_eq!(stored.search_tags, item.search_tags);
+        assert_eq!(stored.space, item.space);
+        assert_eq!(stored.publisher, item.publisher);
+        assert_eq!(stored.created_at, item.created_at);
+        assert_eq!(stored.lang, Language::Fr);
+        // Posts in a group are not suggested.
+        let suggest = db.get_suggest_store();
+        assert!(suggest
+            .get_title_completions("gateau", 10)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(suggest
+            .get_tag_completions("beur", 10)
+            .await
+            .unwrap()
+            .is_empty());
+
+        db.remove_item(item.slug.clone()).await.unwrap();
+        let err = db.get_db().get_item_from_ref(item.slug).await.unwrap_err();
+        assert_eq!(err.kind, ErrorKind::NotFound);
+    }
+
+    pub async fn search_follows_edits(db: impl Backend) {
+        let alice = user("alice");
+        let (authors, suggest) = (db.get_author_store(), db.get_suggest_store());
+        let slug = register_post(db.clone(), &authors, post(&alice, "Brown butter cake"))
+            .await
+            .unwrap();
+        assert_eq!(
+            search_titles(&db, "butter").await,
+            vec!["Brown butter cake"]
+        );
+        assert_eq!(
+            search_titles(&db, "Butter").await,
+            vec!["Brown butter cake"]
+        );
+        assert!(db
+            .render_metrics()
+            .contains("ribbit_search_cache_hits_total 1\n"));
+        assert_eq!(
+            suggest.get_title_completions("brown", 10).await.unwrap(),
+            vec![TitleSuggestion {
+                title: "Brown butter cake".to_string(),
+                slug: slug.clone(),
+            }]
+        );
+        assert_eq!(
+            suggest.get_tag_completions("butt", 10).await.unwrap(),
+            vec![("butter".to_string(), 1)]
+        );
+
+        edit_post(
+            &db,
+            &alice,
+            slug.clone(),
+            "Olive oil cake".to_string(),
+            "Bake.",
+            "",
+        )
+        .await
+        .unwrap();
+        assert!(search_titles(&db, "butter").await.is_empty());
+        assert_eq!(search_titles(&db, "olive").await, vec!["Olive oil cake"]);
+        assert_eq!(search_titles(&db, "oliev").await, vec!["Olive oil cake"]);
+        assert!(suggest
+            .get_tag_completions("butt", 10)
+            .await
+            .unwrap()
+            .is_empty());
+
+        delete_post(&db, &authors, &db.get_thread_store(), &alice, slug)
+            .await
+            .unwrap();
+        assert!(search_titles(&db, "olive").await.is_empty());
+        assert!(db
+            .get_db()
+            .get_similar_tags("oliev")
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            db.get_db().get_corpus_stats().await.unwrap(),
+            CorpusStats::default()
+        );
+    }
+
+    // Words are ranked across all those matching, not only the first few.
+    pub async fn tag_completions_ranked_by_use(db: impl Backend) {
+        let (alice, authors) = (user("alice"), db.get_author_store());
+        for title in ["Cream cake", "Cream candy", "Cream crumble"] {
+            let item = PostEntity {
+                body: String::new(),
+                ..post(&alice, title)
+            };
+            register_post(db.clone(), &authors, item).await.unwrap();
+        }
+
+        let suggest = db.get_suggest_store();
+        assert_eq!(
+            suggest.get_tag_completions("c", 2).await.unwrap(),
+            vec![("cream".to_string(), 3), ("cake".to_string(), 1)]
+        );
+        assert_eq!(
+            suggest.get_tag_completions("ca", 10).await.unwrap(),
+            vec![("cake".to_string(), 1), ("candy".to_string(), 1)]
+        );
+        // The empty prefix matches every word.
+        assert_eq!(
+            suggest.get_tag_completions("", 1).await.unwrap(),
+            vec![("cream".to_string(), 3)]
+        );
+
+        let slug = slug::slugify("Cream cake");
+        delete_post(&db, &authors, &db.get_thread_store(), &alice, slug)
+            .await
+            .unwrap();
+        assert_eq!(
+            suggest.get_tag_completions("c", 10).await.unwrap(),
+            vec![
+                ("cream".to_string(), 2),
+                ("candy".to_string(), 1),
+                ("crumble".to_string(), 1)
+            ]
+        );
+    }
+
+    // A reply whose post went missing is skipped, not a reason to keep the rest.
+    pub async fn dangling_replies_are_skipped(db: impl Backend) {
+        let alice = user("alice");
+        let (authors, threads) = (db.get_author_store(), db.get_thread_store());
+        let slug = register_post(db.clone(), &authors, post(&alice, "Brown butter cake"))
+            .await
+            .unwrap();
+        let mut reply = post(&alice, "Brown butter frosting");
+        reply.parent = Some(slug.clone());
+        let reply = register_post(db.clone(), &authors, reply).await.unwrap();
+        threads.insert_reply(&slug, reply.clone()).await.unwrap();
+        // Replies removed behind the thread's back, as a crash midway could.
+        for (parent, title) in [(&slug, "Lost crumbs"), (&reply, "Lost icing")] {
+            let mut gone = post(&alice, title);
+            gone.parent = Some(parent.clone());
+            assert!(db.insert_new_item(gone.clone()).await.unwrap());
+            threads
+                .insert_reply(parent, gone.slug.clone())
+                .await
+                .unwrap();
+            db.remove_item(gone.slug).await.unwrap();
+        }
+
+        delete_post(&db, &authors, &threads, &alice, slug.clone())
+            .await
+            .unwrap();
+        assert!(search_titles(&db, "brown").await.is_empty());
+        for slug in [slug, reply] {
+            let err = db
+                .get_db()
+                .get_item_from_ref(slug.clone())
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind, ErrorKind::NotFound);
+            assert!(threads.get_reply_refs(&slug).await.unwrap().is_empty());
+        }
+    }
+
+    pub async fn users_and_groups(db: impl Backend) {
+        let (users, groups) = (db.get_user_store(), db.get_group_store());
+        let (alice, bob) = (user("alice"), user("bob"));
+        assert!(users.insert_user(alice.clone()).await.unwrap());
+        assert!(users.insert_user(bob.clone()).await.unwrap());
+        assert!(!users.insert_user(user("alice")).await.unwrap());
+
+        let group = GroupEntity {
+            id: Uuid::new_v4(),
+            name: "Bakers".to_string(),
+            management: GroupManagement::AdminInvite,
+            allow_member_posting: true,
+            face: None,
+            admins: vec![alice.id],
+            members: vec![alice.id, bob.id],
+            invited: vec![],
+            version: 0,
+        };
+        assert!(groups.save_group(group.clone()).await.unwrap());
+        assert!(!groups.save_group(group.clone()).await.unwrap());
+        let mut alice = alice;
+        alice.groups.push(group.id);
+        assert!(users.save_user(alice.clone()).await.unwrap());
+        // A save from the version alice was read at loses to the one above.
+        assert!(!users.save_user(alice.clone()).await.unwrap());
+        let alice = users.get_user(alice.id).await.unwrap();
+
+        block_user(&users, &groups, alice.clone(), "bob")
+            .await
+            .unwrap();
+        let stored = groups.get_group(group.id).await.unwrap();
+        assert_eq!(stored.management, GroupManagement::AdminInvite);
+        assert!(stored.allow_member_posting);
+        assert_eq!(stored.members, vec![alice.id]);
+        assert_eq!(
+            users.get_user(alice.id).await.unwrap().block_list,
+            vec![bob.id]
+        );
+        assert_eq!(
+            users.get_user(bob.id).await.unwrap().blocked_by,
+            vec![alice.id]
+        );
+        assert_eq!(
+            users.get_user(alice.id).await.unwrap().groups,
+            vec![group.id]
+        );
+    }
+}
@@ -0,0 +1,497 @@
+use std::collections::HashSet;
+
+use futures::future::try_join_all;
+
+use crate::groups::{update_group, GroupStore};
+use crate::schemas::{AppError, AuthorId, ErrorKind, PostEntity, UserEntity, UserId};
+use crate::users::{find_user_by_handle, update_user, UserStore};
+
+impl UserEntity {
+    // Blocking works both ways: neither side sees or meets the other.
+    pub fn avoids(&self, other: UserId) -> bool {
+        self.block_list.contains(&other) || self.blocked_by.contains(&other)
+    }
+}
+
+// Handles of everyone the viewer avoids, to filter their posts out of feeds.
+pub async fn avoided_handles(
+    users: &impl UserStore,
+    viewer: Option<&UserEntity>,
+) -> Result<HashSet<AuthorId>, AppError> {
+    let Some(viewer) = viewer else {
+        return Ok(HashSet::new());
+    };
+    let user_ids: HashSet<_> = viewer
+        .block_list
+        .iter()
+        .chain(viewer.blocked_by.iter())
+        .cloned()
+        .collect();
+    Ok(
+        try_join_all(user_ids.into_iter().map(|user_id| users.get_user(user_id)))
+            .await?
+            .into_iter()
+            .map(|user| user.handle)
+            .collect(),
+    )
+}
+
+// Whether the author of a post and the user avoid each other.
+pub async fn avoids_author(
+    users: &impl UserStore,
+    user: &UserEntity,
+    author: &str,
+) -> Result<bool, AppError> {
+    Ok(match users.get_user_id_from_handle(author).await? {
+        Some(author_id) => user.avoids(author_id),
+        None => false,
+    })
+}
+
+// Whether the viewer avoids the user who published the post, who may have
+// posted it as a group face rather than under their own handle.
+pub fn avoids_publisher(viewer: Option<&UserEntity>, post: &PostEntity) -> bool {
+    match (viewer, post.publisher) {
+        (Some(viewer), Some(publisher)) => viewer.avoids(publisher),
+        _ => false,
+    }
+}
+
+// Each side of a block is saved from its latest version, so that changes made
+// to either user meanwhile, such as joining a group, are kept.
+pub async fn block_user(
+    users: &impl UserStore,
+    groups: &impl GroupStore,
+    blocker: UserEntity,
+    handle: &str,
+) -> Result<(), AppError> {
+    let blocked = find_user_by_handle(users, handle).await?;
+    if blocked.id == blocker.id {
+        return Err(AppError::new(ErrorKind::BadRequest, "can't block yourself"));
+    }
+    // The blocked user is excluded from every group the blocker manages.
+    let mut left = vec![];
+    for group_id in users.get_user(blocker.id).await?.groups {
+        let group = update_group(groups, group_id, |group| {
+            if !group.is_admin(blocker.id) || !group.is_member(blocked.id) {
+                return Ok(false);
+            }
+            group.admins.retain(|u| *u != blocked.id);
+            group.members.retain(|u| *u != blocked.id);
+            group.invited.retain(|u| *u != blocked.id);
+            Ok(true)
+        })
+        .await?;
+        if !group.is_member(blocked.id) {
+            left.push(group_id);
+        }
+    }
+    update_user(users, blocked.id, |blocked| {
+        let groups = blocked.groups.len();
+        blocked.groups.retain(|g| !left.contains(g));
+        if blocked.blocked_by.contains(&blocker.id) {
+            return Ok(blocked.groups.len() != groups);
+        }
+        blocked.blocked_by.push(blocker.id);
+        Ok(true)
+    })
+    .await?;
+    update_user(users, blocker.id, |blocker| {
+        if blocker.block_list.contains(&blocked.id) {
+            return Ok(false);
+        }
+        blocker.block_list.push(blocked.id);
+        Ok(true)
+    })
+    .await?;
+    Ok(())
+}
+
+pub async fn unblock_user(
+    users: &impl UserStore,
+    blocker: UserEntity,
+    handle: &str,
+) -> Result<(), AppError> {
+    let blocked = find_user_by_handle(users, handle).await?;
+    update_user(users, blocked.id, |blocked| {
+        if !blocked.blocked_by.contains(&blocker.id) {
+            return Ok(false);
+        }
+        blocked.blocked_by.retain(|u| *u != blocker.id);
+        Ok(true)
+    })
+    .await?;
+    update_user(users, blocker.id, |blocker| {
+        if !blocker.block_list.contains(&blocked.id) {
+            return Ok(false);
+        }
+        blocker.block_list.retain(|u| *u != blocked.id);
+        Ok(true)
+    })
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::groups::{create_group, invite_member, join_group, set_group_face};
+    use crate::memory::InMemoryRepository;
+    use crate::schemas::{GroupEntity, GroupId, GroupManagement};
+    use crate::search::SearchOptions;
+    use crate::services::{find_author_profile, find_post, find_posts, register_post};
+    use crate::threads::reply_to_post;
+    use crate::tokenizer::Language;
+    use crate::users::{find_session_user, sign_up};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct TestUsers {
+        pub users: Arc<Mutex<HashMap<UserId, UserEntity>>>,
+    }
+
+    #[derive(Clone, Default)]
+    struct TestGroups {
+        pub groups: Arc<Mutex<HashMap<GroupId, GroupEntity>>>,
+    }
+
+    impl UserStore for TestUsers {
+        async fn get_user(&self, user_id: UserId) -> Result<UserEntity, AppError> {
+            Ok(self.users.lock().unwrap().get(&user_id).cloned().unwrap())
+        }
+        async fn get_user_id_from_handle(&self, handle: &str) -> Result<Option<UserId>, AppError> {
+            Ok(self
+                .users
+                .lock()
+                .unwrap()
+                .values()
+                .find(|user| user.handle == handle)
+                .map(|user| user.id))
+        }
+        async fn insert_user(&self, user: UserEntity) -> Result<bool, AppError> {
+            self.users.lock().unwrap().insert(user.id, user);
+            Ok(true)
+        }
+        async fn save_user(&self, user: UserEntity) -> Result<bool, AppError> {
+            let mut users = self.users.lock().unwrap();
+            if users.get(&user.id).map(|stored| stored.version) != Some(user.version) {
+                return Ok(false);
+            }
+            users.insert(
+                user.id,
+                UserEntity {
+                    version: user.version + 1,
+                    ..user
+                },
+            );
+            Ok(true)
+        }
+        async fn insert_session(&self, _: &str, _: UserId, _: u64) -> Result<(), AppError> {
+            Ok(())
+        }
+        async fn get_session(&self, _token: &str) -> Result<Option<UserId>, AppError> {
+            Ok(None)
+        }
+    }
+
+    impl GroupStore for TestGroups {
+        async fn get_group(&self, group_id: GroupId) -> Result<GroupEntity, AppError> {
+            Ok(self.groups.lock().unwrap().get(&group_id).cloned().unwrap())
+        }
+        async fn save_group(&self, group: GroupEntity) -> Result<bool, AppError> {
+            let mut groups = self.groups.lock().unwrap();
+            let version = groups.get(&group.id).map_or(0, |stored| stored.version);
+            if version != group.version {
+                return Ok(false);
+            }
+            groups.insert(
+                group.id,
+                GroupEntity {
+                    version: version + 1,
+                    ..group
+                },
+            );
+            Ok(true)
+        }
+    }
+
+    fn user(users: &TestUsers, handle: &str) -> UserEntity {
+        let user = UserEntity {
+            id: uuid::Uuid::new_v4(),
+            handle: handle.to_string(),
+            password_hash: "".to_string(),
+            groups: vec![],
+            block_list: vec![],
+            blocked_by: vec![],
+            version: 0,
+        };
+        users.users.lock().unwrap().insert(user.id, user.clone());
+        user
+    }
+
+    fn group(groups: &TestGroups, users: &TestUsers, admin: &UserEntity) -> GroupId {
+        let group = GroupEntity {
+            id: uuid::Uuid::new_v4(),
+            name: "frogs".to_string(),
+            management: GroupManagement::Open,
+            allow_member_posting: true,
+            face: None,
+            admins: vec![admin.id],
+            members: vec![admin.id],
+            invited: vec![],
+            version: 0,
+        };
+        let mut admin = users.users.lock().unwrap()[&admin.id].clone();
+        admin.groups.push(group.id);
+        users.users.lock().unwrap().insert(admin.id, admin);
+        groups
+            .groups
+            .lock()
+            .unwrap()
+            .insert(group.id, group.clone());
+        group.id
+    }
+
+    fn reload(users: &TestUsers, user: &UserEntity) -> UserEntity {
+        users.users.lock().unwrap()[&user.id].clone()
+    }
+
+    #[tokio::test]
+    async fn test_blocked_user_cant_join() {
+        let (users, groups) = (TestUsers::default(), TestGroups::default());
+        let alice = user(&users, "alice");
+        let bob = user(&users, "bob");
+        let group_id = group(&groups, &users, &alice);
+
+        block_user(&users, &groups, reload(&users, &alice), "bob")
+            .await
+            .unwrap();
+        let err = join_group(&groups, &users, reload(&users, &bob), group_id)
+            .await
+            .unwrap_err();
+
+        assert_eq!(err.kind, ErrorKind::Forbidden);
+    }
+
+    #[tokio::test]
+    async fn test_blocker_cant_join_blocked_group() {
+        let (users, groups) = (TestUsers::default(), TestGroups::default());
+        let alice = user(&users, "alice");
+        let bob = user(&users, "bob");
+        let group_id = group(&groups, &users, &bob);
+
+        block_user(&users, &groups, reload(&users, &alice), "bob")
+            .await
+            .unwrap();
+        let err = join_group(&groups, &users, reload(&users, &alice), group_id)
+            .await
+            .unwrap_err();
+
+        assert_eq!(err.kind, ErrorKind::Forbidden);
+    }
+
+    #[tokio::test]
+    async fn test_block_removes_from_managed_groups() {
+        let (users, groups) = (TestUsers::default(), TestGroups::default());
+        let alice = user(&users, "alice");
+        let bob = user(&users, "bob");
+        let group_id = group(&groups, &users, &alice);
+        join_group(&groups, &users, reload(&users, &bob), group_id)
+            .await
+            .unwrap();
+
+        block_user(&users, &groups, reload(&users, &alice), "bob")
+            .await
+            .unwrap();
+
+        assert!(!groups.get_group(group_id).await.unwrap().is_member(bob.id));
+        assert!(reload(&users, &bob).groups.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_block_keeps_changes_made_meanwhile() {
+        let (users, groups) = (TestUsers::default(), TestGroups::default());
+        let alice = user(&users, "alice");
+        let bob = user(&users, "bob");
+        let carol = user(&users, "carol");
+        let group_id = group(&groups, &users, &carol);
+        let stale = reload(&users, &alice);
+        join_group(&groups, &users, reload(&users, &alice), group_id)
+            .await
+            .unwrap();
+
+        block_user(&users, &groups, stale, "bob").await.unwrap();
+
+        let alice = reload(&users, &alice);
+        assert_eq!(alice.groups, vec![group_id]);
+        assert_eq!(alice.block_list, vec![bob.id]);
+        assert_eq!(reload(&users, &bob).blocked_by, vec![alice.id]);
+    }
+
+    #[tokio::test]
+    async fn test_blocked_user_cant_be_invited() {
+        let (users, groups) = (TestUsers::default(), TestGroups::default());
+        let alice = user(&users, "alice");
+        let carol = user(&users, "carol");
+        user(&users, "bob");
+        let group_id = group(&groups, &users, &carol);
+        join_group(&groups, &users, reload(&users, &alice), group_id)
+            .await
+            .unwrap();
+
+        block_user(&users, &groups, reload(&users, &alice), "bob")
+            .await
+            .unwrap();
+        let err = invite_member(&groups, &users, reload(&users, &carol), group_id, "bob")
+            .await
+            .unwrap_err();
+
+        assert_eq!(err.kind, ErrorKind::Forbidden);
+    }
+
+    #[tokio::test]
+    async fn test_unblock_restores_join() {
+        let (users, groups) = (TestUsers::default(), TestGroups::default());
+        let alice = user(&users, "alice");
+        let bob = user(&users, "bob");
+        let group_id = group(&groups, &users, &alice);
+
+        block_user(&users, &groups, reload(&users, &alice), "bob")
+            .await
+            .unwrap();
+        unblock_user(&users, reload(&users, &alice), "bob")
+            .await
+            .unwrap();
+        join_group(&groups, &users, reload(&users, &bob), group_id)
+            .await
+            .unwrap();
+
+        assert!(groups.get_group(group_id).await.unwrap().is_member(bob.id));
+    }
+
+    #[tokio::test]
+    async fn test_face_posts_of_avoided_publishers_are_hidden() {
+        let db = InMemoryRepository::default();
+        let mut tokens = vec![];
+        for handle in ["alice", "bob", "carol"] {
+            tokens.push(sign_up(&db, &db, handle, "correct horse").await.unwrap());
+        }
+        let alice = find_session_user(&db, &tokens[0]).await.unwrap().unwrap();
+        let group = create_group(
+            &db,
+            &db,
+            alice.clone(),
+            "Bakers".to_string(),
+            GroupManagement::Open,
+            true,
+        )
+        .await
+        .unwrap();
+        set_group_face(
+            &db,
+            &db,
+            alice.clone(),
+            group.id,
+            "bakers",
+            "Bakers".to_string(),
+        )
+        .await
+        .unwrap();
+        let now = Utc::now();
+        let slug = register_post(
+            db.clone(),
+            &db,
+            PostEntity {
+                title: "Brown butter cake".to_string(),
+                slug: "brown-butter-cake".to_string(),
+                author: "bakers".to_string(),
+                search_tags: vec![],
+                body: "<p>Mix, then bake.</p>".to_string(),
+                space: None,
+                reply_scope: None,
+                visibility_scope: None,
+                parent: None,
+                publisher: Some(alice.id),
+                created_at: now,
+                updated_at: now,
+                lang: Language::En,
+            },
+        )
+        .await
+        .unwrap();
+        let bob = find_session_user(&db, &tokens[1]).await.unwrap().unwrap();
+        block_user(&db, &db, bob, "alice").await.unwrap();
+        let bob = find_session_user(&db, &tokens[1]).await.unwrap().unwrap();
+        let carol = find_session_user(&db, &tokens[2]).await.unwrap().unwrap();
+
+        let search = |viewer: UserEntity| {
+            let db = db.clone();
+            async move {
+                find_posts(
+                    db.clone(),
+                    &db,
+                    &db,
+                    Some(&viewer),
+                    "butter",
+                    SearchOptions::default(),
+                    1,
+                    20,
+                )
+                .await
+                .unwrap()
+                .total_objects
+            }
+        };
+        assert_eq!(search(bob.clone()).await, 0);
+        assert_eq!(search(carol.clone()).await, 1);
+        let err = find_post(db.clone(), &db, &db, &db, Some(&bob), slug.clone())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::NotFound);
+        let profile = find_author_profile(
+            db.clone(),
+            &db,
+            &db,
+            Some(&bob),
+            "bakers".to_string(),
+            1,
+            20,
+        )
+        .await
+        .unwrap();
+        assert!(profile.posts.objects.is_empty());
+        let err = reply_to_post(&db, &db, &db, &bob, slug.clone(), "Needs salt.")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::NotFound);
+        reply_to_post(&db, &db, &db, &carol, slug, "Needs salt.")
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_avoided_handles_both_directions() {
+        let users = TestUsers::default();
+        let groups = TestGroups::default();
+        let alice = user(&users, "alice");
+        let bob = user(&users, "bob");
+        user(&users, "carol");
+
+        block_user(&users, &groups, reload(&users, &alice), "bob")
+            .await
+            .unwrap();
+
+        let from_alice = avoided_handles(&users, Some(&reload(&users, &alice)))
+            .await
+            .unwrap();
+        let from_bob = avoided_handles(&users, Some(&reload(&users, &bob)))
+            .await
+            .unwrap();
+        assert_eq!(from_alice, HashSet::from(["bob".to_string()]));
+        assert_eq!(from_bob, HashSet::from(["alice".to_string()]));
+        assert!(avoided_handles(&users, None).await.unwrap().is_empty());
+    }
+}
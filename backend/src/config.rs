@@ -0,0 +1,424 @@
+// Settings are read from a TOML file, then RIBBIT_* environment variables
+// override them. Everything has a default, an empty file runs ribbit over
+// SQLite in ribbit.db, on port 8062:
+//
+//   listen = "0.0.0.0:8062"
+//   worker_threads = 12
+//   store = "sql"                # or "redis", or "memory"
+//   scoring = "weights"          # or "bm25"
+//   admins = ["alice"]
+//
+//   [redis]                      # Search cache and proofs of work
+//   url = "rediss://redis:6380"  # rediss:// for TLS
+//   username = "ribbit"
+//   password = "..."
+//   [redka]                      # Everything else, with the "redis" store
+//   url = "redis://redka"
+//   [sql]                        # With the "sql" store, the default
+//   url = "sqlite://ribbit.db?mode=rwc"
+//
+//   [pow]
+//   difficulty = 18
+//   challenges = 16
+//   valid_seconds = 900
+//
+//   [search]
+//   page_size = 20
+//   word_max = 20
+//   phrase_max = 1
+//   cache_ttl = 600
+
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use redis::{ConnectionInfo, IntoConnectionInfo};
+use serde::de::value::{Error as ValueError, StrDeserializer};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use crate::schemas::{AppError, ErrorKind};
+use crate::scoring::Scoring;
+use crate::searchdb::SEARCH_CACHE_TTL;
+use crate::sqldb::DEFAULT_DATABASE_URL;
+
+// Read when RIBBIT_CONFIG names no file, if it exists.
+pub const DEFAULT_CONFIG_PATH: &str = "ribbit.toml";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Store {
+    Redis,  // redis for the search cache, redka for everything else
+    Memory, // Nothing is kept across restarts, it is for trying ribbit out
+    #[default]
+    Sql, // SQLite, or Postgres with a postgres:// url and the feature
+}
+
+// A section given in the file needs its url, there is no host to default to.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RedisConfig {
+    // redis://host:port/db, or rediss:// for TLS, with "#insecure" at the
+    // end to skip verifying the certificate.
+    pub url: String,
+    // Override the ones in the url, so that secrets can come from the environment.
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SqlConfig {
+    pub url: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PowConfig {
+    pub difficulty: u8,     // From 10 to 98, each step doubles the work
+    pub challenges: usize,  // Solved for each post
+    pub valid_seconds: u32, // Also how long solved ones are remembered, to refuse them again
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SearchConfig {
+    pub page_size: usize,
+    pub word_max: usize,   // Words of a query looked up, the rest is ignored
+    pub phrase_max: usize, // Longest run of words looked up as an alias phrase
+    pub cache_ttl: i64,    // Seconds cached results live, even without changes
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: SocketAddr,
+    pub worker_threads: usize,
+    pub store: Store,
+    pub scoring: Scoring,
+    pub admins: Vec<String>, // Handles allowed to manage search aliases and read metrics
+    pub redis: RedisConfig,
+    pub redka: RedisConfig,
+    pub sql: SqlConfig,
+    pub pow: PowConfig,
+    pub search: SearchConfig,
+}
+
+impl RedisConfig {
+    fn with_host(host: &str) -> Self {
+        Self {
+            url: format!("redis://{host}"),
+            username: None,
+            password: None,
+        }
+    }
+
+    pub fn connection_info(&self) -> Result<ConnectionInfo, AppError> {
+        let mut info = self
+            .url
+            .as_str()
+            .into_connection_info()
+            .map_err(|err| invalid(format!("bad url: {err}")))?;
+        if self.username.is_some() {
+            info.redis.username = self.username.clone();
+        }
+        if self.password.is_some() {
+            info.redis.password = self.password.clone();
+        }
+        Ok(info)
+    }
+}
+
+impl Default for SqlConfig {
+    fn default() -> Self {
+        Self {
+            url: DEFAULT_DATABASE_URL.to_string(),
+        }
+    }
+}
+
+impl Default for PowConfig {
+    fn default() -> Self {
+        Self {
+            difficulty: 18,
+            challenges: 16,
+            valid_seconds: 900,
+        }
+    }
+}
+
+impl Default for SearchConfig {
+    fn default() -> Self {
+        Self {
+            page_size: 20,
+            word_max: 20,
+            phrase_max: 1,
+            cache_ttl: SEARCH_CACHE_TTL,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: SocketAddr::from(([0, 0, 0, 0], 8062)),
+            worker_threads: 12,
+            store: Store::default(),
+            scoring: Scoring::default(),
+            admins: vec![],
+            redis: RedisConfig::with_host("redis"),
+            redka: RedisConfig::with_host("redka"),
+            sql: SqlConfig::default(),
+            pow: PowConfig::default(),
+            search: SearchConfig::default(),
+        }
+    }
+}
+
+fn invalid(reason: impl Into<String>) -> AppError {
+    AppError::new(ErrorKind::BadRequest, reason)
+}
+
+// Sets the field from the variable, if it is set.
+fn parse_var<T>(var: &str, value: Option<String>, field: &mut T) -> Result<(), AppError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Some(value) = value {
+        *field = value
+            .trim()
+            .parse()
+            .map_err(|err| invalid(format!("{var}: {err}")))?;
+    }
+    Ok(())
+}
+
+// For the enums, spelled as in the file.
+fn deserialize_var<T: DeserializeOwned>(
+    var: &str,
+    value: Option<String>,
+    field: &mut T,
+) -> Result<(), AppError> {
+    if let Some(value) = value {
+        *field = T::deserialize(StrDeserializer::<ValueError>::new(value.trim()))
+            .map_err(|err| invalid(format!("{var}: {err}")))?;
+    }
+    Ok(())
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Self, AppError> {
+        toml::from_str(text).map_err(|err| invalid(err.to_string()))
+    }
+
+    // The file named by RIBBIT_CONFIG, or the default one when there is one,
+    // then the environment over it.
+    pub fn load() -> Result<Self, AppError> {
+        let path = std::env::var("RIBBIT_CONFIG").ok();
+        let text = match &path {
+            Some(path) => Some(
+                std::fs::read_to_string(path).map_err(|err| invalid(format!("{path}: {err}")))?,
+            ),
+            None => std::fs::read_to_string(DEFAULT_CONFIG_PATH).ok(),
+        };
+        let path = path.as_deref().unwrap_or(DEFAULT_CONFIG_PATH);
+        let mut config = match text {
+            Some(text) => {
+                Self::parse(&text).map_err(|err| invalid(format!("{path}: {}", err.reason)))?
+            }
+            None => Self::default(),
+        };
+        config.override_with(|var| std::env::var(var).ok())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn override_with(&mut self, env: impl Fn(&str) -> Option<String>) -> Result<(), AppError> {
+        let var = |name: &str| (name.to_string(), env(name));
+        let string = |name: &str, field: &mut String| {
+            if let Some(value) = env(name) {
+                *field = value;
+            }
+        };
+        let secret = |name: &str, field: &mut Option<String>| {
+            if let Some(value) = env(name) {
+                *field = Some(value);
+            }
+        };
+        let (name, value) = var("RIBBIT_LISTEN");
+        parse_var(&name, value, &mut self.listen)?;
+        let (name, value) = var("RIBBIT_WORKER_THREADS");
+        parse_var(&name, value, &mut self.worker_threads)?;
+        let (name, value) = var("RIBBIT_STORE");
+        deserialize_var(&name, value, &mut self.store)?;
+        let (name, value) = var("RIBBIT_SCORING");
+        deserialize_var(&name, value, &mut self.scoring)?;
+        if let Some(admins) = env("RIBBIT_ADMINS") {
+            self.admins = admins
+                .split(',')
+                .map(|handle| handle.trim().to_string())
+                .filter(|handle| !handle.is_empty())
+                .collect();
+        }
+        string("RIBBIT_REDIS_URL", &mut self.redis.url);
+        secret("RIBBIT_REDIS_USERNAME", &mut self.redis.username);
+        secret("RIBBIT_REDIS_PASSWORD", &mut self.redis.password);
+        string("RIBBIT_REDKA_URL", &mut self.redka.url);
+        secret("RIBBIT_REDKA_USERNAME", &mut self.redka.username);
+        secret("RIBBIT_REDKA_PASSWORD", &mut self.redka.password);
+        string("RIBBIT_DATABASE_URL", &mut self.sql.url);
+        let (name, value) = var("RIBBIT_POW_DIFFICULTY");
+        parse_var(&name, value, &mut self.pow.difficulty)?;
+        let (name, value) = var("RIBBIT_POW_CHALLENGES");
+        parse_var(&name, value, &mut self.pow.challenges)?;
+        let (name, value) = var("RIBBIT_POW_VALID_SECONDS");
+        parse_var(&name, value, &mut self.pow.valid_seconds)?;
+        let (name, value) = var("RIBBIT_PAGE_SIZE");
+        parse_var(&name, value, &mut self.search.page_size)?;
+        let (name, value) = var("RIBBIT_WORD_MAX");
+        parse_var(&name, value, &mut self.search.word_max)?;
+        let (name, value) = var("RIBBIT_PHRASE_MAX");
+        parse_var(&name, value, &mut self.search.phrase_max)?;
+        let (name, value) = var("RIBBIT_SEARCH_CACHE_TTL");
+        parse_var(&name, value, &mut self.search.cache_ttl)?;
+        Ok(())
+    }
+
+    // Catches what would only fail later, or misbehave, once serving.
+    pub fn validate(&self) -> Result<(), AppError> {
+        let positive = [
+            ("worker_threads", self.worker_threads as i64),
+            ("pow.challenges", self.pow.challenges as i64),
+            ("pow.valid_seconds", self.pow.valid_seconds as i64),
+            ("search.page_size", self.search.page_size as i64),
+            ("search.word_max", self.search.word_max as i64),
+            ("search.phrase_max", self.search.phrase_max as i64),
+            ("search.cache_ttl", self.search.cache_ttl),
+        ];
+        for (field, value) in positive {
+            if value <= 0 {
+                return Err(invalid(format!("{field} must be more than 0")));
+            }
+        }
+        if !(10..99).contains(&self.pow.difficulty) {
+            return Err(invalid("pow.difficulty must be from 10 to 98"));
+        }
+        match self.store {
+            Store::Redis => {
+                for (section, redis) in [("redis", &self.redis), ("redka", &self.redka)] {
+                    redis
+                        .connection_info()
+                        .map_err(|err| invalid(format!("{section}.url: {}", err.reason)))?;
+                }
+            }
+            Store::Sql if self.sql.url.is_empty() => {
+                return Err(invalid("sql.url must be set"));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn test_empty_file_is_the_default() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config, Config::default());
+        assert_eq!(config.listen.port(), 8062);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_file() {
+        let config = Config::parse(
+            "listen = \"127.0.0.1:9000\"
+             store = \"redis\"
+             scoring = \"bm25\"
+             [redis]
+             url = \"rediss://cache:6380/1\"
+             password = \"secret\"
+             [search]
+             page_size = 50",
+        )
+        .unwrap();
+        assert_eq!(config.listen.port(), 9000);
+        assert_eq!(config.store, Store::Redis);
+        assert_eq!(config.scoring, Scoring::Bm25);
+        assert_eq!(config.search.page_size, 50);
+        // Unset fields of a section keep their default.
+        assert_eq!(config.search.word_max, 20);
+        let info = config.redis.connection_info().unwrap();
+        assert_eq!(info.redis.password.as_deref(), Some("secret"));
+        assert_eq!(info.redis.db, 1);
+    }
+
+    #[test]
+    fn test_env_overrides_file() {
+        let mut config = Config::parse("admins = [\"alice\"]\n[pow]\ndifficulty = 20").unwrap();
+        config
+            .override_with(env(&[
+                ("RIBBIT_ADMINS", "bob, carol,"),
+                ("RIBBIT_POW_DIFFICULTY", "12"),
+                ("RIBBIT_STORE", "memory"),
+                ("RIBBIT_REDKA_PASSWORD", "secret"),
+            ]))
+            .unwrap();
+        assert_eq!(config.admins, vec!["bob", "carol"]);
+        assert_eq!(config.pow.difficulty, 12);
+        assert_eq!(config.store, Store::Memory);
+        assert_eq!(config.redka.password.as_deref(), Some("secret"));
+    }
+
+    #[test]
+    fn test_clear_errors() {
+        let err = Config::parse("[search]\npage_sise = 10").unwrap_err();
+        assert!(err.reason.contains("page_sise"), "{}", err.reason);
+
+        let err = Config::default()
+            .override_with(env(&[("RIBBIT_PAGE_SIZE", "ten")]))
+            .unwrap_err();
+        assert!(
+            err.reason.starts_with("RIBBIT_PAGE_SIZE: "),
+            "{}",
+            err.reason
+        );
+
+        let err = Config::default()
+            .override_with(env(&[("RIBBIT_STORE", "mongo")]))
+            .unwrap_err();
+        assert!(err.reason.contains("`memory`"), "{}", err.reason);
+
+        let mut config = Config::default();
+        config.search.page_size = 0;
+        assert_eq!(
+            config.validate().unwrap_err().reason,
+            "search.page_size must be more than 0"
+        );
+
+        let mut config = Config {
+            store: Store::Redis,
+            ..Config::default()
+        };
+        config.redka.url = "http://redka".to_string();
+        assert!(config
+            .validate()
+            .unwrap_err()
+            .reason
+            .starts_with("redka.url: "));
+    }
+}
@@ -0,0 +1,612 @@
+use futures::future::try_join_all;
+use slug::slugify;
+
+use crate::authors::AuthorStore;
+use crate::schemas::{
+    AppError, AuthorEntity, AuthorId, ErrorKind, GroupEntity, GroupId, GroupManagement, UserEntity,
+    UserId,
+};
+use crate::users::{find_user_by_handle, update_user, UserStore};
+
+pub trait GroupStore
+where
+    Self: Sync + Send,
+{
+    fn get_group(
+        &self,
+        group_id: GroupId,
+    ) -> impl std::future::Future<Output = Result<GroupEntity, AppError>> + std::marker::Send;
+    // Stores the group with its version bumped, if it is still at the version
+    // it was read at. Returns false, and stores nothing, otherwise.
+    fn save_group(
+        &self,
+        group: GroupEntity,
+    ) -> impl std::future::Future<Output = Result<bool, AppError>> + std::marker::Send;
+}
+
+// Attempts at saving a change before giving up to concurrent ones.
+pub const SAVE_ATTEMPTS: usize = 16;
+
+impl GroupEntity {
+    pub fn is_member(&self, user_id: UserId) -> bool {
+        self.members.contains(&user_id)
+    }
+
+    pub fn is_admin(&self, user_id: UserId) -> bool {
+        self.admins.contains(&user_id)
+    }
+
+    pub fn can_invite(&self, user_id: UserId) -> bool {
+        match self.management {
+            GroupManagement::Open | GroupManagement::MemberInvite => self.is_member(user_id),
+            GroupManagement::AdminInvite => self.is_admin(user_id),
+        }
+    }
+
+    pub fn can_join(&self, user_id: UserId) -> bool {
+        self.management == GroupManagement::Open || self.invited.contains(&user_id)
+    }
+}
+
+fn forbidden(reason: &str) -> AppError {
+    AppError::new(ErrorKind::Forbidden, reason)
+}
+
+// Applies the change to the group and saves it, from a fresh read whenever
+// another save got in first. The change returns whether there is anything
+// to save, or an error to give up with.
+pub async fn update_group(
+    groups: &impl GroupStore,
+    group_id: GroupId,
+    mut change: impl FnMut(&mut GroupEntity) -> Result<bool, AppError>,
+) -> Result<GroupEntity, AppError> {
+    for _ in 0..SAVE_ATTEMPTS {
+        let mut group = groups.get_group(group_id).await?;
+        if !change(&mut group)? {
+            return Ok(group);
+        }
+        if groups.save_group(group.clone()).await? {
+            group.version += 1;
+            return Ok(group);
+        }
+    }
+    Err(AppError::new(
+        ErrorKind::Conflict,
+        format!("group {group_id} is changing too often, try again"),
+    ))
+}
+
+async fn add_user_group(
+    users: &impl UserStore,
+    user_id: UserId,
+    group_id: GroupId,
+) -> Result<(), AppError> {
+    update_user(users, user_id, |user| {
+        if user.groups.contains(&group_id) {
+            return Ok(false);
+        }
+        user.groups.push(group_id);
+        Ok(true)
+    })
+    .await?;
+    Ok(())
+}
+
+async fn remove_user_group(
+    users: &impl UserStore,
+    user_id: UserId,
+    group_id: GroupId,
+) -> Result<(), AppError> {
+    update_user(users, user_id, |user| {
+        if !user.groups.contains(&group_id) {
+            return Ok(false);
+        }
+        user.groups.retain(|g| *g != group_id);
+        Ok(true)
+    })
+    .await?;
+    Ok(())
+}
+
+pub async fn create_group(
+    groups: &impl GroupStore,
+    users: &impl UserStore,
+    creator: UserEntity,
+    name: String,
+    management: GroupManagement,
+    allow_member_posting: bool,
+) -> Result<GroupEntity, AppError> {
+    let group = GroupEntity {
+        id: uuid::Uuid::new_v4(),
+        name,
+        management,
+        allow_member_posting,
+        face: None,
+        admins: vec![creator.id],
+        members: vec![creator.id],
+        invited: vec![],
+        version: 0,
+    };
+    if !groups.save_group(group.clone()).await? {
+        return Err(AppError::new(
+            ErrorKind::Conflict,
+            "the group already exists",
+        ));
+    }
+    add_user_group(users, creator.id, group.id).await?;
+    Ok(GroupEntity {
+        version: 1,
+        ..group
+    })
+}
+
+pub async fn join_group(
+    groups: &impl GroupStore,
+    users: &impl UserStore,
+    user: UserEntity,
+    group_id: GroupId,
+) -> Result<GroupEntity, AppError> {
+    let group = update_group(groups, group_id, |group| {
+        if group.is_member(user.id) {
+            return Ok(false);
+        }
+        if !group.can_join(user.id) {
+            return Err(forbidden("joining this group needs an invitation"));
+        }
+        if group.members.iter().any(|member| user.avoids(*member)) {
+            return Err(forbidden("blocked by or blocking a member of this group"));
+        }
+        group.invited.retain(|u| *u != user.id);
+        group.members.push(user.id);
+        Ok(true)
+    })
+    .await?;
+    add_user_group(users, user.id, group_id).await?;
+    Ok(group)
+}
+
+pub async fn leave_group(
+    groups: &impl GroupStore,
+    users: &impl UserStore,
+    user: UserEntity,
+    group_id: GroupId,
+) -> Result<GroupEntity, AppError> {
+    let group = update_group(groups, group_id, |group| {
+        if !group.is_member(user.id) {
+            return Ok(false);
+        }
+        if group.admins == vec![user.id] {
+            return Err(AppError::new(
+                ErrorKind::Conflict,
+                "the last admin can't leave the group",
+            ));
+        }
+        group.admins.retain(|u| *u != user.id);
+        group.members.retain(|u| *u != user.id);
+        Ok(true)
+    })
+    .await?;
+    remove_user_group(users, user.id, group_id).await?;
+    Ok(group)
+}
+
+pub async fn invite_member(
+    groups: &impl GroupStore,
+    users: &impl UserStore,
+    inviter: UserEntity,
+    group_id: GroupId,
+    invitee_handle: &str,
+) -> Result<GroupEntity, AppError> {
+    if !groups.get_group(group_id).await?.can_invite(inviter.id) {
+        return Err(forbidden("not allowed to invite in this group"));
+    }
+    let invitee = find_user_by_handle(users, invitee_handle).await?;
+    update_group(groups, group_id, |group| {
+        if !group.can_invite(inviter.id) {
+            return Err(forbidden("not allowed to invite in this group"));
+        }
+        if group.is_member(invitee.id) || group.invited.contains(&invitee.id) {
+            return Ok(false);
+        }
+        if group.members.iter().any(|member| invitee.avoids(*member)) {
+            return Err(forbidden("blocked by or blocking a member of this group"));
+        }
+        group.invited.push(invitee.id);
+        Ok(true)
+    })
+    .await
+}
+
+pub async fn promote_admin(
+    groups: &impl GroupStore,
+    users: &impl UserStore,
+    admin: UserEntity,
+    group_id: GroupId,
+    member_handle: &str,
+) -> Result<GroupEntity, AppError> {
+    if !groups.get_group(group_id).await?.is_admin(admin.id) {
+        return Err(forbidden("only admins can promote members"));
+    }
+    let member = find_user_by_handle(users, member_handle).await?;
+    update_group(groups, group_id, |group| {
+        if !group.is_admin(admin.id) {
+            return Err(forbidden("only admins can promote members"));
+        }
+        if !group.is_member(member.id) {
+            return Err(AppError::new(
+                ErrorKind::BadRequest,
+                "only members can become admins",
+            ));
+        }
+        if group.is_admin(member.id) {
+            return Ok(false);
+        }
+        group.admins.push(member.id);
+        Ok(true)
+    })
+    .await
+}
+
+pub async fn demote_admin(
+    groups: &impl GroupStore,
+    users: &impl UserStore,
+    admin: UserEntity,
+    group_id: GroupId,
+    member_handle: &str,
+) -> Result<GroupEntity, AppError> {
+    if !groups.get_group(group_id).await?.is_admin(admin.id) {
+        return Err(forbidden("only admins can demote admins"));
+    }
+    let member = find_user_by_handle(users, member_handle).await?;
+    update_group(groups, group_id, |group| {
+        if !group.is_admin(admin.id) {
+            return Err(forbidden("only admins can demote admins"));
+        }
+        if !group.is_admin(member.id) {
+            return Ok(false);
+        }
+        if group.admins.len() == 1 {
+            return Err(AppError::new(
+                ErrorKind::Conflict,
+                "a group needs at least one admin",
+            ));
+        }
+        // Demoted admins stay members.
+        group.admins.retain(|u| *u != member.id);
+        Ok(true)
+    })
+    .await
+}
+
+pub async fn set_group_face(
+    groups: &impl GroupStore,
+    authors: &impl AuthorStore,
+    admin: UserEntity,
+    group_id: GroupId,
+    handle: &str,
+    name: String,
+) -> Result<GroupEntity, AppError> {
+    let has_face = || AppError::new(ErrorKind::Conflict, "the group already has a face");
+    let group = groups.get_group(group_id).await?;
+    if !group.is_admin(admin.id) {
+        return Err(forbidden("only admins can give a group a face"));
+    }
+    if group.face.is_some() {
+        return Err(has_face());
+    }
+    let handle = slugify(handle);
+    if handle.is_empty() {
+        return Err(AppError::new(ErrorKind::BadRequest, "empty handle"));
+    }
+    if !authors
+        .insert_author(AuthorEntity {
+            author_id: handle.clone(),
+            name,
+            profile_picture: "".to_string(),
+        })
+        .await?
+    {
+        return Err(AppError::new(
+            ErrorKind::Conflict,
+            format!("handle {handle} is taken"),
+        ));
+    }
+    let updated = update_group(groups, group_id, |group| {
+        if !group.is_admin(admin.id) {
+            return Err(forbidden("only admins can give a group a face"));
+        }
+        if group.face.is_some() {
+            return Err(has_face());
+        }
+        group.face = Some(handle.clone());
+        Ok(true)
+    })
+    .await;
+    // The handle claimed above is given back if another change won.
+    if updated.is_err() {
+        authors.remove_author(&handle).await?;
+    }
+    updated
+}
+
+// Fetches a group for a viewer, hiding who is in it from non-members.
+pub async fn find_group(
+    groups: &impl GroupStore,
+    viewer: Option<&UserEntity>,
+    group_id: GroupId,
+) -> Result<GroupEntity, AppError> {
+    let mut group = groups.get_group(group_id).await?;
+    if !viewer.is_some_and(|viewer| group.is_member(viewer.id)) {
+        group.members.clear();
+        group.invited.clear();
+    }
+    Ok(group)
+}
+
+// The faces a user can post as, that is those of the groups they admin.
+pub async fn find_user_faces(
+    groups: &impl GroupStore,
+    user: &UserEntity,
+) -> Result<Vec<AuthorId>, AppError> {
+    Ok(try_join_all(
+        user.groups
+            .iter()
+            .map(|group_id| groups.get_group(*group_id)),
+    )
+    .await?
+    .into_iter()
+    .filter(|group| group.is_admin(user.id))
+    .filter_map(|group| group.face)
+    .collect())
+}
+
+// Picks the author of a new post, rejecting faces the user doesn't control.
+pub async fn resolve_post_as(
+    groups: &impl GroupStore,
+    user: &UserEntity,
+    post_as: Option<AuthorId>,
+) -> Result<AuthorId, AppError> {
+    match post_as {
+        None => Ok(user.handle.clone()),
+        Some(handle) if handle == user.handle => Ok(handle),
+        Some(handle) => {
+            if find_user_faces(groups, user).await?.contains(&handle) {
+                Ok(handle)
+            } else {
+                Err(forbidden("only group admins can post as its face"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+    use crate::memory::InMemoryRepository;
+
+    async fn user(db: &InMemoryRepository, handle: &str) -> UserEntity {
+        let user = UserEntity {
+            id: uuid::Uuid::new_v4(),
+            handle: handle.to_string(),
+            password_hash: String::new(),
+            groups: vec![],
+            block_list: vec![],
+            blocked_by: vec![],
+            version: 0,
+        };
+        db.insert_user(user.clone()).await.unwrap();
+        user
+    }
+
+    async fn reload(db: &InMemoryRepository, user: &UserEntity) -> UserEntity {
+        db.get_user(user.id).await.unwrap()
+    }
+
+    async fn group(
+        db: &InMemoryRepository,
+        admin: &UserEntity,
+        management: GroupManagement,
+    ) -> GroupId {
+        create_group(
+            db,
+            db,
+            admin.clone(),
+            "Bakers".to_string(),
+            management,
+            true,
+        )
+        .await
+        .unwrap()
+        .id
+    }
+
+    #[tokio::test]
+    async fn test_open_group() {
+        let db = InMemoryRepository::default();
+        let (alice, bob, carol) = (
+            user(&db, "alice").await,
+            user(&db, "bob").await,
+            user(&db, "carol").await,
+        );
+        let group_id = group(&db, &alice, GroupManagement::Open).await;
+
+        // Anyone joins, and any member invites.
+        join_group(&db, &db, bob.clone(), group_id).await.unwrap();
+        let group = invite_member(&db, &db, reload(&db, &bob).await, group_id, "carol")
+            .await
+            .unwrap();
+        assert_eq!(group.invited, vec![carol.id]);
+        let group = join_group(&db, &db, carol.clone(), group_id).await.unwrap();
+        assert_eq!(group.members, vec![alice.id, bob.id, carol.id]);
+        assert!(group.invited.is_empty());
+        assert_eq!(reload(&db, &carol).await.groups, vec![group_id]);
+
+        let group = leave_group(&db, &db, reload(&db, &bob).await, group_id)
+            .await
+            .unwrap();
+        assert!(!group.is_member(bob.id));
+        assert!(reload(&db, &bob).await.groups.is_empty());
+        let err = leave_group(&db, &db, reload(&db, &alice).await, group_id)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Conflict);
+    }
+
+    #[tokio::test]
+    async fn test_member_invite_group() {
+        let db = InMemoryRepository::default();
+        let (alice, bob, carol) = (
+            user(&db, "alice").await,
+            user(&db, "bob").await,
+            user(&db, "carol").await,
+        );
+        let group_id = group(&db, &alice, GroupManagement::MemberInvite).await;
+
+        let err = join_group(&db, &db, bob.clone(), group_id)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Forbidden);
+        let err = invite_member(&db, &db, carol.clone(), group_id, "bob")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Forbidden);
+
+        invite_member(&db, &db, alice.clone(), group_id, "bob")
+            .await
+            .unwrap();
+        join_group(&db, &db, bob.clone(), group_id).await.unwrap();
+        // Members who aren't admins invite too.
+        invite_member(&db, &db, bob.clone(), group_id, "carol")
+            .await
+            .unwrap();
+        let group = join_group(&db, &db, carol.clone(), group_id).await.unwrap();
+        assert_eq!(group.members, vec![alice.id, bob.id, carol.id]);
+    }
+
+    #[tokio::test]
+    async fn test_admin_invite_group() {
+        let db = InMemoryRepository::default();
+        let (alice, bob, carol) = (
+            user(&db, "alice").await,
+            user(&db, "bob").await,
+            user(&db, "carol").await,
+        );
+        let group_id = group(&db, &alice, GroupManagement::AdminInvite).await;
+        invite_member(&db, &db, alice.clone(), group_id, "bob")
+            .await
+            .unwrap();
+        join_group(&db, &db, bob.clone(), group_id).await.unwrap();
+
+        let err = invite_member(&db, &db, bob.clone(), group_id, "carol")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Forbidden);
+        let err = promote_admin(&db, &db, bob.clone(), group_id, "bob")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Forbidden);
+        let err = promote_admin(&db, &db, alice.clone(), group_id, "carol")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadRequest);
+
+        // Once promoted, the member invites.
+        promote_admin(&db, &db, alice.clone(), group_id, "bob")
+            .await
+            .unwrap();
+        let group = invite_member(&db, &db, bob.clone(), group_id, "carol")
+            .await
+            .unwrap();
+        assert_eq!(group.invited, vec![carol.id]);
+        let group = demote_admin(&db, &db, bob.clone(), group_id, "alice")
+            .await
+            .unwrap();
+        assert_eq!(group.admins, vec![bob.id]);
+        assert!(group.is_member(alice.id));
+        let err = demote_admin(&db, &db, bob.clone(), group_id, "bob")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Conflict);
+    }
+
+    #[tokio::test]
+    async fn test_stale_save_is_rejected() {
+        let db = InMemoryRepository::default();
+        let alice = user(&db, "alice").await;
+        let group_id = group(&db, &alice, GroupManagement::Open).await;
+        let stale = db.get_group(group_id).await.unwrap();
+
+        let mut renamed = stale.clone();
+        renamed.name = "Brewers".to_string();
+        assert!(db.save_group(renamed).await.unwrap());
+        assert!(!db.save_group(stale).await.unwrap());
+        assert_eq!(db.get_group(group_id).await.unwrap().name, "Brewers");
+    }
+
+    // Lets another user join right after the first read, as a concurrent
+    // request would.
+    struct RacingGroups {
+        db: InMemoryRepository,
+        racer: UserEntity,
+        raced: AtomicBool,
+    }
+
+    impl GroupStore for RacingGroups {
+        async fn get_group(&self, group_id: GroupId) -> Result<GroupEntity, AppError> {
+            let group = self.db.get_group(group_id).await?;
+            if !self.raced.swap(true, Ordering::Relaxed) {
+                join_group(&self.db, &self.db, self.racer.clone(), group_id).await?;
+            }
+            Ok(group)
+        }
+
+        async fn save_group(&self, group: GroupEntity) -> Result<bool, AppError> {
+            self.db.save_group(group).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_joins_are_kept() {
+        let db = InMemoryRepository::default();
+        let (alice, bob, carol) = (
+            user(&db, "alice").await,
+            user(&db, "bob").await,
+            user(&db, "carol").await,
+        );
+        let group_id = group(&db, &alice, GroupManagement::Open).await;
+        let groups = RacingGroups {
+            db: db.clone(),
+            racer: carol.clone(),
+            raced: AtomicBool::new(false),
+        };
+
+        join_group(&groups, &db, bob.clone(), group_id)
+            .await
+            .unwrap();
+        let group = db.get_group(group_id).await.unwrap();
+        assert_eq!(group.members, vec![alice.id, carol.id, bob.id]);
+        assert_eq!(group.version, 3);
+    }
+
+    #[tokio::test]
+    async fn test_members_hidden_from_outsiders() {
+        let db = InMemoryRepository::default();
+        let (alice, bob) = (user(&db, "alice").await, user(&db, "bob").await);
+        let group_id = group(&db, &alice, GroupManagement::Open).await;
+        invite_member(&db, &db, alice.clone(), group_id, "bob")
+            .await
+            .unwrap();
+
+        let group = find_group(&db, Some(&alice), group_id).await.unwrap();
+        assert_eq!(group.members, vec![alice.id]);
+        assert_eq!(group.invited, vec![bob.id]);
+        for viewer in [Some(&bob), None] {
+            let group = find_group(&db, viewer, group_id).await.unwrap();
+            assert!(group.members.is_empty());
+            assert!(group.invited.is_empty());
+            assert_eq!(group.admins, vec![alice.id]);
+        }
+    }
+}
@@ -1,38 +1,181 @@
+// What a store needs to know of an item to keep and rank it.
+pub trait IndexedItem<ItemRef> {
+    fn item_ref(&self) -> ItemRef;
+    // Higher is newer.
+    fn timestamp(&self) -> i64;
+}
+
 pub trait InsertHandle<Tag, ItemRef, Item, DbError>
 where
     ItemRef: Clone,
     Tag: Clone,
+    Self: Sync,
 {
+    // Each tag comes with the weight of the field it was found in.
     fn insert_tags(
         &self,
-        tags: Vec<Tag>,
+        tags: Vec<(Tag, usize)>,
         item_ref: ItemRef,
     ) -> impl std::future::Future<Output = Result<(), DbError>> + std::marker::Send;
     fn insert_item(
         &self,
         item: Item,
     ) -> impl std::future::Future<Output = Result<(), DbError>> + std::marker::Send;
+    fn remove_tags(
+        &self,
+        tags: Vec<Tag>,
+        item_ref: ItemRef,
+    ) -> impl std::future::Future<Output = Result<(), DbError>> + std::marker::Send;
+    fn remove_item(
+        &self,
+        item_ref: ItemRef,
+    ) -> impl std::future::Future<Output = Result<(), DbError>> + std::marker::Send;
+    // Stores the item only if its ref is still free, returns whether it did.
+    fn insert_new_item(
+        &self,
+        item: Item,
+    ) -> impl std::future::Future<Output = Result<bool, DbError>> + std::marker::Send;
+    // Replaces the term frequencies kept for the item, which also count
+    // towards the document frequency of each term and the corpus length.
+    fn insert_term_stats(
+        &self,
+        item_ref: ItemRef,
+        terms: Vec<(Tag, usize)>,
+    ) -> impl std::future::Future<Output = Result<(), DbError>> + std::marker::Send;
+    fn remove_term_stats(
+        &self,
+        item_ref: ItemRef,
+    ) -> impl std::future::Future<Output = Result<(), DbError>> + std::marker::Send;
     fn insert_alias(
         &self,
         phrase: String,
         tags: Vec<Tag>,
     ) -> impl std::future::Future<Output = Result<(), DbError>> + std::marker::Send;
+    fn remove_alias(
+        &self,
+        phrase: String,
+    ) -> impl std::future::Future<Output = Result<(), DbError>> + std::marker::Send;
+
+    // Stores the item and indexes it, taking it off the tags it lost. Stores
+    // with transactions should do it in one, so that no search sees it half
+    // indexed.
+    fn store_indexed_item(
+        &self,
+        item_ref: ItemRef,
+        item: Item,
+        stale_tags: Vec<Tag>,
+        tags: Vec<(Tag, usize)>,
+        terms: Vec<(Tag, usize)>,
+    ) -> impl std::future::Future<Output = Result<(), DbError>> + std::marker::Send
+    where
+        Tag: Send,
+        ItemRef: Send,
+        Item: Send,
+        DbError: Send,
+    {
+        async move {
+            let stale_ref = item_ref.clone();
+            let remove_stale = async move {
+                match stale_tags.is_empty() {
+                    true => Ok(()),
+                    false => self.remove_tags(stale_tags, stale_ref).await,
+                }
+            };
+            let _ = futures::try_join!(
+                self.insert_item(item),
+                remove_stale,
+                self.insert_tags(tags, item_ref.clone()),
+                self.insert_term_stats(item_ref, terms)
+            )?;
+            Ok(())
+        }
+    }
 }
 
 pub async fn insert_and_index_item<Tag, ItemRef, Item, DbError>(
     handler: &impl InsertHandle<Tag, ItemRef, Item, DbError>,
     item_ref: ItemRef,
     item: Item,
-    tags: Vec<Tag>,
+    tags: Vec<(Tag, usize)>,
+    terms: Vec<(Tag, usize)>,
 ) -> Result<(), DbError>
+where
+    Item: Clone + Send,
+    ItemRef: Clone + Send,
+    Tag: Clone + Send,
+    DbError: Send,
+{
+    handler
+        .store_indexed_item(item_ref, item, vec![], tags, terms)
+        .await
+}
+
+// Inserts the first candidate whose ref is free and indexes it, so that no
+// existing item is ever overwritten. Returns the ref that was allocated.
+pub async fn insert_and_index_new_item<Tag, ItemRef, Item, DbError>(
+    handler: &impl InsertHandle<Tag, ItemRef, Item, DbError>,
+    candidates: impl IntoIterator<Item = (ItemRef, Item)>,
+    tags: Vec<(Tag, usize)>,
+    terms: Vec<(Tag, usize)>,
+) -> Result<Option<ItemRef>, DbError>
 where
     Item: Clone,
     ItemRef: Clone,
     Tag: Clone,
+{
+    for (item_ref, item) in candidates {
+        if handler.insert_new_item(item).await? {
+            handler.insert_tags(tags, item_ref.clone()).await?;
+            handler.insert_term_stats(item_ref.clone(), terms).await?;
+            return Ok(Some(item_ref));
+        }
+    }
+    Ok(None)
+}
+
+// Replaces a stored item, moving it from the tags it lost to the ones it gained.
+pub async fn update_and_reindex_item<Tag, ItemRef, Item, DbError>(
+    handler: &impl InsertHandle<Tag, ItemRef, Item, DbError>,
+    item_ref: ItemRef,
+    item: Item,
+    old_tags: Vec<(Tag, usize)>,
+    new_tags: Vec<(Tag, usize)>,
+    terms: Vec<(Tag, usize)>,
+) -> Result<(), DbError>
+where
+    Item: Clone + Send,
+    ItemRef: Clone + Send,
+    Tag: Clone + PartialEq + Send,
+    DbError: Send,
+{
+    // Reweighted tags are only inserted again, which overwrites their weight.
+    let stale_tags: Vec<_> = old_tags
+        .iter()
+        .filter(|(tag, _)| !new_tags.iter().any(|(new_tag, _)| new_tag == tag))
+        .map(|(tag, _)| tag.clone())
+        .collect();
+    let added_tags: Vec<_> = new_tags
+        .into_iter()
+        .filter(|tag| !old_tags.contains(tag))
+        .collect();
+    handler
+        .store_indexed_item(item_ref, item, stale_tags, added_tags, terms)
+        .await
+}
+
+pub async fn remove_and_unindex_item<Tag, ItemRef, Item, DbError>(
+    handler: &impl InsertHandle<Tag, ItemRef, Item, DbError>,
+    item_ref: ItemRef,
+    tags: Vec<Tag>,
+) -> Result<(), DbError>
+where
+    ItemRef: Clone,
+    Tag: Clone,
 {
     let _ = tokio::try_join!(
-        { handler.insert_item(item.clone()) },
-        handler.insert_tags(tags, item_ref)
+        handler.remove_tags(tags, item_ref.clone()),
+        handler.remove_term_stats(item_ref.clone()),
+        handler.remove_item(item_ref)
     )?;
     Ok(())
 }
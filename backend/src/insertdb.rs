@@ -2,27 +2,263 @@ use redis::AsyncCommands;
 
 use crate::indexing::InsertHandle;
 use crate::schemas::{AppError, PostEntity};
+use crate::search::SearchCache;
 use crate::searchdb::Repository;
+use crate::tokenizer::{bigrams, fold};
 use futures::future::try_join_all;
 
+// Member of the title completion index, "{folded title}\t{slug}\t{title}".
+// Only public thread roots are suggested.
+fn title_member(item: &PostEntity) -> Option<String> {
+    if item.parent.is_some() || item.visibility_group().is_some() {
+        return None;
+    }
+    Some(format!(
+        "{}\t{}\t{}",
+        fold(&item.title),
+        item.slug,
+        item.title
+    ))
+}
+
+// Keys of the completion index the word is listed under, one per prefix of
+// it, from the empty one to the whole word.
+fn word_prefix_keys(word: &str) -> Vec<String> {
+    word.char_indices()
+        .map(|(end, _)| &word[..end])
+        .chain([word])
+        .map(|prefix| format!("word_prefix.{prefix}"))
+        .collect()
+}
+
+// Whether the tag is a single word, looked up for typos. Words never contain
+// ':' or ' ', as the tokenizer strips punctuation, unlike field and pair tags.
+pub fn is_term(tag: &str) -> bool {
+    !tag.contains([':', ' '])
+}
+
+// Queues adding the item's terms to the statistics.
+fn count_terms(pipe: &mut redis::Pipeline, item_ref: &str, terms: &[(String, usize)]) {
+    if terms.is_empty() {
+        return;
+    }
+    let length: usize = terms.iter().map(|(_, count)| count).sum();
+    pipe.hset_multiple(format!("tf.{item_ref}"), terms).ignore();
+    for (tag, _) in terms {
+        pipe.hincr("doc_freq", tag, 1).ignore();
+    }
+    pipe.zadd("doc_lengths", item_ref, length)
+        .ignore()
+        .incr("corpus_length", length)
+        .ignore();
+}
+
+// Queues taking the item's terms, as stored, out of the statistics.
+fn uncount_terms(pipe: &mut redis::Pipeline, item_ref: &str, terms: &[(String, usize)]) {
+    if terms.is_empty() {
+        return;
+    }
+    let length: usize = terms.iter().map(|(_, count)| count).sum();
+    for (tag, _) in terms {
+        pipe.hincr("doc_freq", tag, -1).ignore();
+    }
+    pipe.del(format!("tf.{item_ref}"))
+        .ignore()
+        .zrem("doc_lengths", item_ref)
+        .ignore()
+        .decr("corpus_length", length)
+        .ignore();
+}
+
+impl Repository {
+    async fn get_term_stats(&self, item_ref: &str) -> Result<Vec<(String, usize)>, AppError> {
+        Ok(self
+            .redka
+            .clone()
+            .client
+            .hgetall::<_, Vec<(String, usize)>>(format!("tf.{item_ref}"))
+            .await?)
+    }
+
+    async fn get_stored_item(&self, item_ref: &str) -> Result<Option<PostEntity>, AppError> {
+        let json_str = self
+            .redka
+            .clone()
+            .client
+            .get::<_, Option<String>>(format!("post.{item_ref}"))
+            .await?;
+        Ok(json_str.map(|json_str| serde_json::from_str(json_str.as_str()).unwrap()))
+    }
+
+    async fn insert_title(&self, item: &PostEntity) -> Result<(), AppError> {
+        if let Some(member) = title_member(item) {
+            self.redka
+                .clone()
+                .client
+                .zadd::<_, _, _, ()>("title_prefixes", member, 0)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn remove_title(&self, item: &PostEntity) -> Result<(), AppError> {
+        if let Some(member) = title_member(item) {
+            self.redka
+                .clone()
+                .client
+                .zrem::<_, _, ()>("title_prefixes", member)
+                .await?;
+        }
+        Ok(())
+    }
+
+    // Completion index of the words of public thread roots. Each prefix has
+    // a `word_prefix.{prefix}` zset of the words starting with it, scored by
+    // minus the number of posts using them, so that ranges list the most
+    // used first, alphabetically among equals.
+    async fn insert_words(&self, item: &PostEntity) -> Result<(), AppError> {
+        let words = item.completion_words();
+        if words.is_empty() {
+            return Ok(());
+        }
+        let mut pipe = redis::pipe();
+        for word in words.iter() {
+            for key in word_prefix_keys(word) {
+                pipe.zincr(key, word, -1).ignore();
+            }
+        }
+        pipe.query_async::<()>(&mut self.redka.clone().client)
+            .await?;
+        Ok(())
+    }
+
+    // Words no post uses anymore are dropped. A post using one meanwhile
+    // takes its score below 0, out of the range removed.
+    async fn remove_words(&self, item: &PostEntity) -> Result<(), AppError> {
+        let words = item.completion_words();
+        if words.is_empty() {
+            return Ok(());
+        }
+        let mut pipe = redis::pipe();
+        for word in words.iter() {
+            for key in word_prefix_keys(word) {
+                pipe.zincr(&key, word, 1).ignore();
+                pipe.zrembyscore(key, 0, "+inf").ignore();
+            }
+        }
+        pipe.query_async::<()>(&mut self.redka.clone().client)
+            .await?;
+        Ok(())
+    }
+
+    // Keeps the recency index used to sort search results.
+    async fn insert_date(&self, item: &PostEntity) -> Result<(), AppError> {
+        self.redka
+            .clone()
+            .client
+            .zadd::<_, _, _, ()>("posts_by_date", &item.slug, item.created_at.timestamp())
+            .await?;
+        Ok(())
+    }
+
+    // Adds the tag to the term dictionary, looked up by letter pairs for typos.
+    async fn insert_term(&self, tag: &str) -> Result<(), AppError> {
+        if !is_term(tag) {
+            return Ok(());
+        }
+        let mut pipe = redis::pipe();
+        for gram in bigrams(tag) {
+            pipe.sadd(format!("tag_grams.{gram}"), tag).ignore();
+        }
+        pipe.query_async::<()>(&mut self.redka.clone().client)
+            .await?;
+        Ok(())
+    }
+
+    // Drops the tag from the term dictionary once no item is indexed under it.
+    async fn remove_term(&self, tag: &str) -> Result<(), AppError> {
+        if !is_term(tag) {
+            return Ok(());
+        }
+        let mut redka = self.redka.clone().client;
+        if redka.zcard::<_, usize>(format!("tag.{tag}")).await? > 0 {
+            return Ok(());
+        }
+        // Redka has neither WATCH nor scripts to check and remove at once. The
+        // tag is removed first and put back if an item was indexed under it
+        // meanwhile, so that a concurrent insert_term is never undone.
+        let mut pipe = redis::pipe();
+        for gram in bigrams(tag) {
+            pipe.srem(format!("tag_grams.{gram}"), tag).ignore();
+        }
+        pipe.query_async::<()>(&mut redka).await?;
+        if redka.zcard::<_, usize>(format!("tag.{tag}")).await? > 0 {
+            self.insert_term(tag).await?;
+        }
+        Ok(())
+    }
+}
+
 impl InsertHandle<String, String, PostEntity, AppError> for Repository {
-    async fn insert_tags(&self, tags: Vec<String>, item_ref: String) -> Result<(), AppError> {
+    async fn insert_tags(
+        &self,
+        tags: Vec<(String, usize)>,
+        item_ref: String,
+    ) -> Result<(), AppError> {
+        let item_ref_str = item_ref.as_str();
+        // The weight is the score, so indexing again only updates it.
+        try_join_all(tags.iter().map(|(tag, weight)| async move {
+            self.redka
+                .clone()
+                .client
+                .zadd::<_, _, _, ()>(format!("tag.{tag}"), item_ref_str, *weight)
+                .await?;
+            self.insert_term(tag).await
+        }))
+        .await?;
+        self.redis
+            .invalidate_tags(tags.into_iter().map(|(tag, _)| tag).collect())
+            .await
+    }
+
+    async fn remove_tags(&self, tags: Vec<String>, item_ref: String) -> Result<(), AppError> {
         let item_ref_str = item_ref.as_str();
-        try_join_all(tags.into_iter().map(|tag| async move {
+        try_join_all(tags.clone().into_iter().map(|tag| async move {
             self.redka
                 .clone()
                 .client
-                .sadd::<String, String, Vec<String>>(
-                    format!("tag.{}", tag.as_str()),
-                    item_ref_str.to_owned(),
-                )
-                .await
+                .zrem::<_, _, ()>(format!("tag.{}", tag.as_str()), item_ref_str)
+                .await?;
+            self.remove_term(&tag).await
         }))
         .await?;
+        self.redis.invalidate_tags(tags).await
+    }
+
+    async fn remove_item(&self, item_ref: String) -> Result<(), AppError> {
+        if let Some(old) = self.get_stored_item(&item_ref).await? {
+            self.remove_title(&old).await?;
+            self.remove_words(&old).await?;
+        }
+        self.redka
+            .clone()
+            .client
+            .zrem::<_, _, ()>("posts_by_date", &item_ref)
+            .await?;
+        self.redka
+            .clone()
+            .client
+            .del::<_, ()>(format!("post.{item_ref}"))
+            .await?;
         Ok(())
     }
 
     async fn insert_item(&self, item: PostEntity) -> Result<(), AppError> {
+        // An edit may have changed the title and words.
+        if let Some(old) = self.get_stored_item(&item.slug).await? {
+            self.remove_title(&old).await?;
+            self.remove_words(&old).await?;
+        }
         self.redka
             .clone()
             .client
@@ -31,6 +267,54 @@ impl InsertHandle<String, String, PostEntity, AppError> for Repository {
                 serde_json::to_string(&item).unwrap(),
             )
             .await?;
+        self.insert_title(&item).await?;
+        self.insert_words(&item).await?;
+        self.insert_date(&item).await
+    }
+
+    async fn insert_new_item(&self, item: PostEntity) -> Result<bool, AppError> {
+        let inserted = self
+            .redka
+            .clone()
+            .client
+            .set_nx::<_, _, bool>(
+                format!("post.{}", item.slug),
+                serde_json::to_string(&item).unwrap(),
+            )
+            .await?;
+        if inserted {
+            self.insert_title(&item).await?;
+            self.insert_words(&item).await?;
+            self.insert_date(&item).await?;
+        }
+        Ok(inserted)
+    }
+
+    // Replaces the statistics of the item's terms at once, so that searches
+    // never see them half counted. Redka has no WATCH, so the old terms are
+    // read beforehand, the item's writes being the only ones to change them.
+    async fn insert_term_stats(
+        &self,
+        item_ref: String,
+        terms: Vec<(String, usize)>,
+    ) -> Result<(), AppError> {
+        let old = self.get_term_stats(&item_ref).await?;
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        uncount_terms(&mut pipe, &item_ref, &old);
+        count_terms(&mut pipe, &item_ref, &terms);
+        pipe.query_async::<()>(&mut self.redka.clone().client)
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_term_stats(&self, item_ref: String) -> Result<(), AppError> {
+        let old = self.get_term_stats(&item_ref).await?;
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        uncount_terms(&mut pipe, &item_ref, &old);
+        pipe.query_async::<()>(&mut self.redka.clone().client)
+            .await?;
         Ok(())
     }
 
@@ -38,7 +322,27 @@ impl InsertHandle<String, String, PostEntity, AppError> for Repository {
         self.redka
             .clone()
             .client
-            .sadd::<_, _, Vec<String>>(format!("aliases.{phrase}"), tags)
+            .sadd::<_, _, ()>(format!("aliases.{phrase}"), tags)
+            .await?;
+        // Lists the phrases, so aliases can be browsed without scanning keys.
+        self.redka
+            .clone()
+            .client
+            .sadd::<_, _, ()>("alias_phrases", phrase)
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_alias(&self, phrase: String) -> Result<(), AppError> {
+        self.redka
+            .clone()
+            .client
+            .del::<_, ()>(format!("aliases.{phrase}"))
+            .await?;
+        self.redka
+            .clone()
+            .client
+            .srem::<_, _, ()>("alias_phrases", phrase)
             .await?;
         Ok(())
     }
@@ -0,0 +1,126 @@
+use std::sync::Arc;
+use std::time;
+
+use axum::middleware;
+use axum::routing::{delete, get, post, put};
+use axum::{extract::Request, http::StatusCode, middleware::Next, response::IntoResponse, Router};
+use backend::Backend;
+use config::Config;
+
+pub mod aliases;
+pub mod authorization;
+pub mod authors;
+pub mod backend;
+pub mod blocking;
+pub mod config;
+pub mod groups;
+pub mod indexing;
+pub mod insertdb;
+pub mod memory;
+pub mod pow;
+pub mod query;
+pub mod rest;
+pub mod schemas;
+pub mod scoring;
+pub mod search;
+pub mod searchdb;
+pub mod services;
+pub mod sqldb;
+pub mod suggest;
+pub mod templates;
+pub mod threads;
+pub mod tokenizer;
+pub mod users;
+
+#[derive(Clone)]
+pub struct Repositories<Db> {
+    pub db: Db,
+    pub hb: handlebars::Handlebars<'static>,
+    pub config: Arc<Config>,
+}
+
+pub fn templates() -> handlebars::Handlebars<'static> {
+    let mut hb = handlebars::Handlebars::new();
+    hb.register_template_string("post", templates::POST_TPL)
+        .unwrap();
+    hb.register_partial("reply", templates::REPLY_TPL).unwrap();
+
+    hb.register_template_string("publish", templates::PUBLISH_TPL)
+        .unwrap();
+
+    hb.register_template_string("home", templates::HOME_TPL)
+        .unwrap();
+    hb.register_template_string("list", templates::LIST_TPL)
+        .unwrap();
+    hb.register_template_string("author", templates::AUTHOR_TPL)
+        .unwrap();
+    hb.register_template_string("sign_up", templates::SIGN_UP_TPL)
+        .unwrap();
+    hb.register_template_string("sign_in", templates::SIGN_IN_TPL)
+        .unwrap();
+    hb
+}
+
+pub fn router<Db: Backend>(repos: Repositories<Db>) -> Router {
+    Router::new()
+        .route("/:lang/home", get(rest::home::<Db>))
+        .route("/:lang/search", get(rest::search_post::<Db>))
+        .route("/:lang/search/suggest", get(rest::suggest::<Db>))
+        .route("/:lang/aliases", get(rest::list_aliases::<Db>))
+        .route("/:lang/aliases", post(rest::add_alias::<Db>))
+        .route("/:lang/aliases/import", post(rest::import_aliases::<Db>))
+        .route("/:lang/aliases/:phrase", delete(rest::remove_alias::<Db>))
+        .route("/:lang/post", post(rest::post_form::<Db>))
+        .route("/:lang/post/:slug", get(rest::get_post::<Db>))
+        .route("/:lang/post/:slug", put(rest::edit_post::<Db>))
+        .route("/:lang/post/:slug", delete(rest::delete_post::<Db>))
+        .route("/:lang/post/:slug/reply", post(rest::reply_post::<Db>))
+        .route("/:lang/post", get(rest::get_challenge_form::<Db>))
+        .route("/:lang/author/:handle", get(rest::get_author::<Db>))
+        .route("/:lang/group", post(rest::create_group::<Db>))
+        .route("/:lang/group/:group_id", get(rest::get_group::<Db>))
+        .route(
+            "/:lang/group/:group_id/face",
+            post(rest::set_group_face::<Db>),
+        )
+        .route("/:lang/group/:group_id/join", post(rest::join_group::<Db>))
+        .route(
+            "/:lang/group/:group_id/leave",
+            post(rest::leave_group::<Db>),
+        )
+        .route(
+            "/:lang/group/:group_id/invite",
+            post(rest::invite_member::<Db>),
+        )
+        .route(
+            "/:lang/group/:group_id/promote",
+            post(rest::promote_admin::<Db>),
+        )
+        .route(
+            "/:lang/group/:group_id/demote",
+            post(rest::demote_admin::<Db>),
+        )
+        .route("/:lang/user/:handle/block", post(rest::block_user::<Db>))
+        .route(
+            "/:lang/user/:handle/unblock",
+            post(rest::unblock_user::<Db>),
+        )
+        .route("/:lang/sign-up", get(rest::get_sign_up_form::<Db>))
+        .route("/:lang/sign-up", post(rest::sign_up::<Db>))
+        .route("/:lang/sign-in", get(rest::get_sign_in_form::<Db>))
+        .route("/:lang/sign-in", post(rest::sign_in::<Db>))
+        .route("/metrics", get(rest::metrics::<Db>))
+        .layer(middleware::from_fn(log_access))
+        .with_state(repos)
+}
+
+async fn log_access(req: Request, next: Next) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let t0 = time::Instant::now();
+    let uri = req.uri().to_owned().to_string();
+
+    let res = next.run(req).await;
+
+    let t = (time::Instant::now() - t0).as_millis();
+    tracing::info!(uri = uri, time_ms = t);
+    Ok(res)
+}
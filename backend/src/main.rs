@@ -1,68 +1,83 @@
-use axum::middleware;
-use axum::routing::{get, post};
-use axum::{extract::Request, http::StatusCode, middleware::Next, response::IntoResponse, Router};
-use searchdb::Repository;
+use ribbit::backend::Backend;
+use ribbit::config::{Config, Store};
+use ribbit::memory::InMemoryRepository;
+use ribbit::schemas::{self, AppError};
+use ribbit::searchdb::Repository;
+use ribbit::sqldb::SqlRepository;
+use ribbit::{aliases, router, templates, Repositories};
 use spow::pow::Pow;
-use std::net::SocketAddr;
-use std::time;
-// pub mod config
-pub mod indexing;
-pub mod insertdb;
-pub mod pow;
-pub mod rest;
-pub mod schemas;
-pub mod search;
-pub mod searchdb;
-pub mod services;
-pub mod templates;
+use std::sync::Arc;
 
-#[derive(Clone)]
-pub struct Repositories {
-    pub db: Repository,
-    pub hb: handlebars::Handlebars<'static>,
+fn main() {
+    tracing_subscriber::fmt().json().init();
+    let config = Config::load().unwrap_or_else(|err| exit_with(err));
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(config.worker_threads)
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(start(config));
 }
 
-#[tokio::main(flavor = "multi_thread", worker_threads = 12)]
-async fn main() {
-    tracing_subscriber::fmt().json().init();
-    let mut hb = handlebars::Handlebars::new();
-    hb.register_template_string("post", templates::POST_TPL)
-        .unwrap();
+fn exit_with(err: AppError) -> ! {
+    eprintln!("{}", err.reason);
+    std::process::exit(1);
+}
 
-    hb.register_template_string("publish", templates::PUBLISH_TPL)
-        .unwrap();
+async fn start(config: Config) {
+    match config.store {
+        Store::Memory => {
+            let mut db = InMemoryRepository::default();
+            db.scoring = config.scoring;
+            run(db, config).await
+        }
+        Store::Sql => {
+            let mut db = SqlRepository::connect(&config.sql.url)
+                .await
+                .unwrap_or_else(|err| exit_with(err));
+            db.scoring = config.scoring;
+            db.cache_ttl = config.search.cache_ttl;
+            run(db, config).await
+        }
+        Store::Redis => {
+            let mut db = Repository::connect(&config.redis, &config.redka)
+                .await
+                .unwrap_or_else(|err| exit_with(err));
+            db.scoring = config.scoring;
+            db.redis.ttl = config.search.cache_ttl;
+            run(db, config).await
+        }
+    }
+}
 
-    hb.register_template_string("home", templates::HOME_TPL)
-        .unwrap();
-    hb.register_template_string("list", templates::LIST_TPL)
-        .unwrap();
+async fn run<Db: Backend>(db: Db, config: Config) {
+    let addr = config.listen;
     let repos = Repositories {
-        db: Repository::new("redis", "redka").await,
-        hb,
+        db,
+        hb: templates(),
+        config: Arc::new(config),
     };
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("aliases") {
+        let store = repos.db.get_alias_store();
+        let phrase_max = repos.config.search.phrase_max;
+        let args = args[1..].to_vec();
+        if let Err(err) = aliases::run_cli(&repos.db, &store, phrase_max, args).await {
+            exit_with(err);
+        }
+        return;
+    }
     Pow::init_random().unwrap();
-    let app = Router::new()
-        .route("/:lang/home", get(rest::home))
-        .route("/:lang/search", get(rest::search_post))
-        .route("/:lang/post", post(rest::post_form))
-        .route("/:lang/post/:slug", get(rest::get_post))
-        .route("/:lang/post", get(rest::get_challenge_form))
-        .layer(middleware::from_fn(log_access))
-        .with_state(repos);
+    let app = router(repos);
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 8062));
     tracing::info!("listening on {addr}");
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .unwrap_or_else(|err| {
+            exit_with(AppError::new(
+                schemas::ErrorKind::Internal,
+                format!("can't listen on {addr}: {err}"),
+            ))
+        });
     axum::serve(listener, app).await.unwrap();
 }
-
-async fn log_access(req: Request, next: Next) -> Result<impl IntoResponse, (StatusCode, String)> {
-    let t0 = time::Instant::now();
-    let uri = req.uri().to_owned().to_string();
-
-    let res = next.run(req).await;
-
-    let t = (time::Instant::now() - t0).as_millis();
-    tracing::info!(uri = uri, time_ms = t);
-    Ok(res)
-}
@@ -0,0 +1,833 @@
+use core::fmt::Debug;
+use core::hash::Hash;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use spow::pow::Pow;
+
+use crate::aliases::{Alias, AliasStore};
+use crate::authors::AuthorStore;
+use crate::backend::Backend;
+use crate::groups::GroupStore;
+use crate::indexing::{IndexedItem, InsertHandle};
+use crate::insertdb::is_term;
+use crate::pow::PowValidator;
+use crate::query::tags_from_field;
+use crate::schemas::{
+    AppError, AuthorEntity, AuthorId, ErrorKind, GroupEntity, GroupId, PostEntity, UserEntity,
+    UserId,
+};
+use crate::scoring::{CorpusStats, DocStats, Scorer, Scoring};
+use crate::search::{ItemRepo, SearchCache, SearchDb};
+use crate::searchdb::CacheMetrics;
+use crate::suggest::{SuggestStore, TitleSuggestion};
+use crate::threads::ThreadStore;
+use crate::tokenizer::{fold, is_typo_of};
+use crate::users::UserStore;
+
+struct Maps<Tag, ItemRef, Item> {
+    posts: DashMap<ItemRef, Item>,
+    dates: DashMap<ItemRef, i64>,
+    tags: DashMap<Tag, HashMap<ItemRef, usize>>, // Weight of each item under the tag
+    aliases: DashMap<String, Vec<Tag>>,
+    term_frequencies: DashMap<ItemRef, HashMap<Tag, usize>>, // Per item
+    doc_frequencies: DashMap<Tag, usize>,
+    searches: DashMap<String, Vec<ItemRef>>,
+    search_tags: DashMap<Tag, HashSet<String>>, // Searches that resolved to the tag
+    generation: Mutex<u64>,                     // Held while caching or invalidating searches
+    challenges: DashMap<String, Instant>,       // Spent until the instant
+    users: DashMap<UserId, UserEntity>,
+    logins: DashMap<String, UserId>,
+    sessions: DashMap<String, (UserId, Instant)>, // Expiring at the instant
+    authors: DashMap<AuthorId, AuthorEntity>,
+    author_posts: DashMap<AuthorId, HashSet<String>>,
+    groups: DashMap<GroupId, GroupEntity>,
+    replies: DashMap<String, Vec<String>>,
+}
+
+impl<Tag, ItemRef, Item> Default for Maps<Tag, ItemRef, Item>
+where
+    Tag: Eq + Hash,
+    ItemRef: Eq + Hash,
+{
+    fn default() -> Self {
+        Self {
+            posts: DashMap::new(),
+            dates: DashMap::new(),
+            tags: DashMap::new(),
+            aliases: DashMap::new(),
+            term_frequencies: DashMap::new(),
+            doc_frequencies: DashMap::new(),
+            searches: DashMap::new(),
+            search_tags: DashMap::new(),
+            generation: Mutex::new(0),
+            challenges: DashMap::new(),
+            users: DashMap::new(),
+            logins: DashMap::new(),
+            sessions: DashMap::new(),
+            authors: DashMap::new(),
+            author_posts: DashMap::new(),
+            groups: DashMap::new(),
+            replies: DashMap::new(),
+        }
+    }
+}
+
+// Every store over concurrent maps, for tests and embedding. Clones share
+// the same data, nothing outlives the process. The search index takes any
+// item, the app stores only run over posts.
+pub struct InMemoryRepository<Tag = String, ItemRef = String, Item = PostEntity> {
+    maps: Arc<Maps<Tag, ItemRef, Item>>,
+    metrics: Arc<CacheMetrics>,
+    pub scoring: Scoring,
+}
+
+impl<Tag, ItemRef, Item> Clone for InMemoryRepository<Tag, ItemRef, Item> {
+    fn clone(&self) -> Self {
+        Self {
+            maps: self.maps.clone(),
+            metrics: self.metrics.clone(),
+            scoring: self.scoring,
+        }
+    }
+}
+
+impl<Tag, ItemRef, Item> Default for InMemoryRepository<Tag, ItemRef, Item>
+where
+    Tag: Eq + Hash,
+    ItemRef: Eq + Hash,
+{
+    fn default() -> Self {
+        Self {
+            maps: Arc::default(),
+            metrics: Arc::default(),
+            scoring: Scoring::default(),
+        }
+    }
+}
+
+impl<Tag, ItemRef, Item> SearchCache<Tag, ItemRef, AppError>
+    for InMemoryRepository<Tag, ItemRef, Item>
+where
+    Tag: Clone + Eq + Hash + Send + Sync,
+    ItemRef: Clone + Eq + Hash + Send + Sync,
+    Item: Send + Sync,
+{
+    async fn get_generation(&self) -> Result<u64, AppError> {
+        Ok(*self.maps.generation.lock().unwrap())
+    }
+
+    async fn get_cached_page(
+        &self,
+        search_tags: &str,
+        start: usize,
+        count: usize,
+    ) -> Result<Option<(Vec<ItemRef>, usize)>, AppError> {
+        let page = self.maps.searches.get(search_tags).map(|results| {
+            let end = results.len().min(start.saturating_add(count));
+            (results[start.min(end)..end].to_vec(), results.len())
+        });
+        // A search counts once, when its first page is read.
+        if start == 0 {
+            let counter = if page.is_none() {
+                &self.metrics.misses
+            } else {
+                &self.metrics.hits
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(page)
+    }
+
+    async fn cache_search(
+        &self,
+        search_tags: &str,
+        tags: Vec<Tag>,
+        results: Vec<ItemRef>,
+        generation: u64,
+    ) -> Result<(), AppError> {
+        let current = self.maps.generation.lock().unwrap();
+        if *current != generation {
+            return Ok(());
+        }
+        self.maps.searches.insert(search_tags.to_string(), results);
+        for tag in tags {
+            self.maps
+                .search_tags
+                .entry(tag)
+                .or_default()
+                .insert(search_tags.to_string());
+        }
+        Ok(())
+    }
+
+    async fn invalidate_tags(&self, tags: Vec<Tag>) -> Result<(), AppError> {
+        let mut generation = self.maps.generation.lock().unwrap();
+        *generation += 1;
+        for tag in tags {
+            if let Some((_, searches)) = self.maps.search_tags.remove(&tag) {
+                for search in searches {
+                    self.maps.searches.remove(&search);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<Tag, ItemRef, Item> SearchDb<Tag, ItemRef, Item, AppError>
+    for InMemoryRepository<Tag, ItemRef, Item>
+where
+    Tag: From<String> + AsRef<str> + Clone + Eq + Hash + Send + Sync,
+    ItemRef: Clone + Eq + Hash + Debug + Send + Sync,
+    Item: Clone + Send + Sync,
+{
+    async fn get_item_refs_from_tag(&self, tag: Tag) -> Result<Vec<(ItemRef, usize)>, AppError> {
+        Ok(self
+            .maps
+            .tags
+            .get(&tag)
+            .map(|weights| weights.iter().map(|(k, v)| (k.clone(), *v)).collect())
+            .unwrap_or_default())
+    }
+
+    async fn get_item_from_ref(&self, item_ref: ItemRef) -> Result<Item, AppError> {
+        self.maps
+            .posts
+            .get(&item_ref)
+            .map(|item| item.clone())
+            .ok_or(AppError::new(
+                ErrorKind::NotFound,
+                format!("no item {item_ref:?}"),
+            ))
+    }
+
+    async fn get_tags_from_phrase(&self, phrase: &str) -> Result<Vec<Tag>, AppError> {
+        Ok(self
+            .maps
+            .aliases
+            .get(phrase)
+            .map(|tags| tags.clone())
+            .unwrap_or_else(|| vec![Tag::from(phrase.to_string())]))
+    }
+
+    async fn get_tags_from_field(&self, field: &str, value: &str) -> Result<Vec<Tag>, AppError> {
+        Ok(tags_from_field(field, value)
+            .into_iter()
+            .map(Tag::from)
+            .collect())
+    }
+
+    async fn get_similar_tags(&self, word: &str) -> Result<Vec<Tag>, AppError> {
+        Ok(self
+            .maps
+            .tags
+            .iter()
+            .map(|entry| entry.key().clone())
+            .filter(|tag| is_term(tag.as_ref()) && is_typo_of(tag.as_ref(), word))
+            .collect())
+    }
+
+    async fn get_items_recency(&self, item_refs: Vec<ItemRef>) -> Result<Vec<i64>, AppError> {
+        Ok(item_refs
+            .iter()
+            .map(|item_ref| self.maps.dates.get(item_ref).map(|date| *date).unwrap_or(0))
+            .collect())
+    }
+
+    async fn get_corpus_stats(&self) -> Result<CorpusStats, AppError> {
+        Ok(CorpusStats {
+            docs: self.maps.term_frequencies.len(),
+            length: self
+                .maps
+                .term_frequencies
+                .iter()
+                .map(|terms| terms.values().sum::<usize>())
+                .sum(),
+        })
+    }
+
+    async fn get_doc_frequencies(&self, tags: Vec<Tag>) -> Result<Vec<usize>, AppError> {
+        Ok(tags
+            .iter()
+            .map(|tag| {
+                self.maps
+                    .doc_frequencies
+                    .get(tag)
+                    .map(|count| *count)
+                    .unwrap_or(0)
+            })
+            .collect())
+    }
+
+    async fn get_term_frequencies(
+        &self,
+        item_refs: Vec<ItemRef>,
+        tags: Vec<Tag>,
+    ) -> Result<Vec<DocStats>, AppError> {
+        Ok(item_refs
+            .iter()
+            .map(|item_ref| match self.maps.term_frequencies.get(item_ref) {
+                Some(terms) => DocStats {
+                    length: terms.values().sum(),
+                    frequencies: tags
+                        .iter()
+                        .map(|tag| terms.get(tag).cloned().unwrap_or(0))
+                        .collect(),
+                },
+                None => DocStats::default(),
+            })
+            .collect())
+    }
+}
+
+impl<Tag, ItemRef, Item> ItemRepo<Tag, ItemRef, Item, AppError>
+    for InMemoryRepository<Tag, ItemRef, Item>
+where
+    Tag: From<String> + AsRef<str> + Clone + Eq + Hash + Debug + Send + Sync,
+    ItemRef: Clone + Eq + Hash + Ord + Debug + Send + Sync,
+    Item: Clone + Send + Sync,
+{
+    fn get_cache(&self) -> impl SearchCache<Tag, ItemRef, AppError> {
+        self.clone()
+    }
+
+    fn get_db(&self) -> impl SearchDb<Tag, ItemRef, Item, AppError> {
+        self.clone()
+    }
+
+    fn get_scorer(&self) -> impl Scorer<Tag, ItemRef, Item, AppError> {
+        self.scoring
+    }
+}
+
+impl<Tag, ItemRef, Item> InMemoryRepository<Tag, ItemRef, Item>
+where
+    Tag: Eq + Hash,
+    ItemRef: Eq + Hash,
+{
+    fn forget_term_stats(&self, item_ref: &ItemRef) {
+        if let Some((_, terms)) = self.maps.term_frequencies.remove(item_ref) {
+            for tag in terms.keys() {
+                if let Some(mut count) = self.maps.doc_frequencies.get_mut(tag) {
+                    *count = count.saturating_sub(1);
+                }
+            }
+        }
+    }
+}
+
+impl<Tag, ItemRef, Item> InsertHandle<Tag, ItemRef, Item, AppError>
+    for InMemoryRepository<Tag, ItemRef, Item>
+where
+    Tag: Clone + Eq + Hash + Send + Sync,
+    ItemRef: Clone + Eq + Hash + Send + Sync,
+    Item: IndexedItem<ItemRef> + Send + Sync,
+{
+    async fn insert_tags(
+        &self,
+        tags: Vec<(Tag, usize)>,
+        item_ref: ItemRef,
+    ) -> Result<(), AppError> {
+        for (tag, weight) in tags.iter() {
+            self.maps
+                .tags
+                .entry(tag.clone())
+                .or_default()
+                .insert(item_ref.clone(), *weight);
+        }
+        self.invalidate_tags(tags.into_iter().map(|(tag, _)| tag).collect())
+            .await
+    }
+
+    async fn remove_tags(&self, tags: Vec<Tag>, item_ref: ItemRef) -> Result<(), AppError> {
+        for tag in tags.iter() {
+            if let Some(mut weights) = self.maps.tags.get_mut(tag) {
+                weights.remove(&item_ref);
+            }
+            // Like the Redis index, a tag nothing is under anymore is gone.
+            self.maps
+                .tags
+                .remove_if(tag, |_, weights| weights.is_empty());
+        }
+        self.invalidate_tags(tags).await
+    }
+
+    async fn remove_item(&self, item_ref: ItemRef) -> Result<(), AppError> {
+        self.maps.dates.remove(&item_ref);
+        self.maps.posts.remove(&item_ref);
+        Ok(())
+    }
+
+    async fn insert_item(&self, item: Item) -> Result<(), AppError> {
+        self.maps.dates.insert(item.item_ref(), item.timestamp());
+        self.maps.posts.insert(item.item_ref(), item);
+        Ok(())
+    }
+
+    async fn insert_new_item(&self, item: Item) -> Result<bool, AppError> {
+        match self.maps.posts.entry(item.item_ref()) {
+            dashmap::Entry::Occupied(_) => Ok(false),
+            dashmap::Entry::Vacant(entry) => {
+                self.maps.dates.insert(item.item_ref(), item.timestamp());
+                entry.insert(item);
+                Ok(true)
+            }
+        }
+    }
+
+    async fn insert_term_stats(
+        &self,
+        item_ref: ItemRef,
+        terms: Vec<(Tag, usize)>,
+    ) -> Result<(), AppError> {
+        self.forget_term_stats(&item_ref);
+        if terms.is_empty() {
+            return Ok(());
+        }
+        for (tag, _) in terms.iter() {
+            *self.maps.doc_frequencies.entry(tag.clone()).or_insert(0) += 1;
+        }
+        self.maps
+            .term_frequencies
+            .insert(item_ref, terms.into_iter().collect());
+        Ok(())
+    }
+
+    async fn remove_term_stats(&self, item_ref: ItemRef) -> Result<(), AppError> {
+        self.forget_term_stats(&item_ref);
+        Ok(())
+    }
+
+    async fn insert_alias(&self, phrase: String, tags: Vec<Tag>) -> Result<(), AppError> {
+        let mut aliased = self.maps.aliases.entry(phrase).or_default();
+        for tag in tags {
+            if !aliased.contains(&tag) {
+                aliased.push(tag);
+            }
+        }
+        Ok(())
+    }
+
+    async fn remove_alias(&self, phrase: String) -> Result<(), AppError> {
+        self.maps.aliases.remove(&phrase);
+        Ok(())
+    }
+}
+
+impl PowValidator for InMemoryRepository {
+    // A proof is spent for as long as it is valid, then forgotten.
+    async fn is_valid_pow(&self, challenges: Vec<String>, valid_seconds: u64) -> bool {
+        let now = Instant::now();
+        self.maps.challenges.retain(|_, expiry| *expiry > now);
+        for challenge in challenges {
+            if Pow::validate(&challenge).is_err() {
+                return false;
+            }
+            // Each proof is good for a single use.
+            match self.maps.challenges.entry(challenge) {
+                dashmap::Entry::Occupied(_) => return false,
+                dashmap::Entry::Vacant(entry) => {
+                    entry.insert(now + Duration::from_secs(valid_seconds));
+                }
+            }
+        }
+        true
+    }
+}
+
+impl UserStore for InMemoryRepository {
+    async fn get_user(&self, user_id: UserId) -> Result<UserEntity, AppError> {
+        self.maps
+            .users
+            .get(&user_id)
+            .map(|user| user.clone())
+            .ok_or(AppError::new(
+                ErrorKind::NotFound,
+                format!("no user {user_id}"),
+            ))
+    }
+
+    async fn get_user_id_from_handle(&self, handle: &str) -> Result<Option<UserId>, AppError> {
+        Ok(self.maps.logins.get(handle).map(|user_id| *user_id))
+    }
+
+    async fn insert_user(&self, user: UserEntity) -> Result<bool, AppError> {
+        match self.maps.logins.entry(user.handle.clone()) {
+            dashmap::Entry::Occupied(_) => Ok(false),
+            dashmap::Entry::Vacant(entry) => {
+                entry.insert(user.id);
+                self.maps.users.insert(user.id, user);
+                Ok(true)
+            }
+        }
+    }
+
+    async fn save_user(&self, user: UserEntity) -> Result<bool, AppError> {
+        let version = user.version;
+        match self.maps.users.entry(user.id) {
+            dashmap::Entry::Occupied(mut entry) if entry.get().version == version => {
+                entry.insert(UserEntity {
+                    version: version + 1,
+                    ..user
+                });
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn insert_session(&self, token: &str, user_id: UserId, ttl: u64) -> Result<(), AppError> {
+        self.maps.sessions.insert(
+            token.to_string(),
+            (user_id, Instant::now() + Duration::from_secs(ttl)),
+        );
+        Ok(())
+    }
+
+    async fn get_session(&self, token: &str) -> Result<Option<UserId>, AppError> {
+        self.maps
+            .sessions
+            .remove_if(token, |_, (_, expiry)| *expiry <= Instant::now());
+        Ok(self.maps.sessions.get(token).map(|session| session.0))
+    }
+}
+
+impl AuthorStore for InMemoryRepository {
+    async fn get_author(&self, author_id: &str) -> Result<Option<AuthorEntity>, AppError> {
+        Ok(self
+            .maps
+            .authors
+            .get(author_id)
+            .map(|author| author.clone()))
+    }
+
+    async fn get_authors(
+        &self,
+        author_ids: Vec<AuthorId>,
+    ) -> Result<Vec<Option<AuthorEntity>>, AppError> {
+        Ok(author_ids
+            .iter()
+            .map(|author_id| {
+                self.maps
+                    .authors
+                    .get(author_id)
+                    .map(|author| author.clone())
+            })
+            .collect())
+    }
+
+    async fn insert_author(&self, author: AuthorEntity) -> Result<bool, AppError> {
+        match self.maps.authors.entry(author.author_id.clone()) {
+            dashmap::Entry::Occupied(_) => Ok(false),
+            dashmap::Entry::Vacant(entry) => {
+                entry.insert(author);
+                Ok(true)
+            }
+        }
+    }
+
+    async fn remove_author(&self, author_id: &str) -> Result<(), AppError> {
+        self.maps.authors.remove(author_id);
+        Ok(())
+    }
+
+    async fn insert_author_post(&self, author_id: &str, item_ref: String) -> Result<(), AppError> {
+        self.maps
+            .author_posts
+            .entry(author_id.to_string())
+            .or_default()
+            .insert(item_ref);
+        Ok(())
+    }
+
+    async fn remove_author_post(&self, author_id: &str, item_ref: String) -> Result<(), AppError> {
+        if let Some(mut posts) = self.maps.author_posts.get_mut(author_id) {
+            posts.remove(&item_ref);
+        }
+        Ok(())
+    }
+
+    async fn get_author_post_refs(&self, author_id: &str) -> Result<Vec<String>, AppError> {
+        Ok(self
+            .maps
+            .author_posts
+            .get(author_id)
+            .map(|posts| posts.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+}
+
+impl GroupStore for InMemoryRepository {
+    async fn get_group(&self, group_id: GroupId) -> Result<GroupEntity, AppError> {
+        self.maps
+            .groups
+            .get(&group_id)
+            .map(|group| group.clone())
+            .ok_or(AppError::new(
+                ErrorKind::NotFound,
+                format!("no group {group_id}"),
+            ))
+    }
+
+    async fn save_group(&self, group: GroupEntity) -> Result<bool, AppError> {
+        let version = group.version;
+        let saved = GroupEntity {
+            version: version + 1,
+            ..group
+        };
+        match self.maps.groups.entry(saved.id) {
+            dashmap::Entry::Occupied(mut entry) if entry.get().version == version => {
+                entry.insert(saved);
+            }
+            dashmap::Entry::Vacant(entry) if version == 0 => {
+                entry.insert(saved);
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+impl ThreadStore for InMemoryRepository {
+    async fn insert_reply(&self, parent_ref: &str, item_ref: String) -> Result<(), AppError> {
+        self.maps
+            .replies
+            .entry(parent_ref.to_string())
+            .or_default()
+            .push(item_ref);
+        Ok(())
+    }
+
+    async fn remove_reply(&self, parent_ref: &str, item_ref: String) -> Result<(), AppError> {
+        if let Some(mut replies) = self.maps.replies.get_mut(parent_ref) {
+            replies.retain(|reply| *reply != item_ref);
+        }
+        Ok(())
+    }
+
+    async fn remove_replies(&self, parent_ref: &str) -> Result<(), AppError> {
+        self.maps.replies.remove(parent_ref);
+        Ok(())
+    }
+
+    async fn get_reply_refs(&self, parent_ref: &str) -> Result<Vec<String>, AppError> {
+        Ok(self
+            .maps
+            .replies
+            .get(parent_ref)
+            .map(|replies| replies.clone())
+            .unwrap_or_default())
+    }
+}
+
+impl AliasStore for InMemoryRepository {
+    async fn get_aliases(&self) -> Result<Vec<Alias>, AppError> {
+        let mut aliases: Vec<_> = self
+            .maps
+            .aliases
+            .iter()
+            .map(|entry| {
+                let mut tags = entry.value().clone();
+                tags.sort();
+                Alias {
+                    phrase: entry.key().clone(),
+                    tags,
+                }
+            })
+            .collect();
+        aliases.sort_by(|a, b| a.phrase.cmp(&b.phrase));
+        Ok(aliases)
+    }
+}
+
+impl SuggestStore for InMemoryRepository {
+    async fn get_tag_completions(
+        &self,
+        prefix: &str,
+        limit: usize,
+    ) -> Result<Vec<(String, usize)>, AppError> {
+        let mut uses: HashMap<String, usize> = HashMap::new();
+        for post in self.maps.posts.iter() {
+            for word in post.completion_words() {
+                if word.starts_with(prefix) {
+                    *uses.entry(word).or_insert(0) += 1;
+                }
+            }
+        }
+        let mut words: Vec<_> = uses.into_iter().collect();
+        words.sort_by(|(word, count), (other_word, other_count)| {
+            other_count.cmp(count).then(word.cmp(other_word))
+        });
+        words.truncate(limit);
+        Ok(words)
+    }
+
+    async fn get_title_completions(
+        &self,
+        prefix: &str,
+        limit: usize,
+    ) -> Result<Vec<TitleSuggestion>, AppError> {
+        // Only public thread roots are suggested.
+        let mut titles: Vec<_> = self
+            .maps
+            .posts
+            .iter()
+            .filter(|post| post.parent.is_none() && post.visibility_group().is_none())
+            .map(|post| (fold(&post.title), post.slug.clone(), post.title.clone()))
+            .filter(|(folded, _, _)| folded.starts_with(prefix))
+            .collect();
+        titles.sort();
+        Ok(titles
+            .into_iter()
+            .take(limit)
+            .map(|(_, slug, title)| TitleSuggestion { title, slug })
+            .collect())
+    }
+}
+
+impl Backend for InMemoryRepository {
+    fn get_pow_validator(&self) -> impl PowValidator + Send + Sync {
+        self.clone()
+    }
+
+    fn get_user_store(&self) -> impl UserStore {
+        self.clone()
+    }
+
+    fn get_author_store(&self) -> impl AuthorStore {
+        self.clone()
+    }
+
+    fn get_group_store(&self) -> impl GroupStore {
+        self.clone()
+    }
+
+    fn get_thread_store(&self) -> impl ThreadStore {
+        self.clone()
+    }
+
+    fn get_suggest_store(&self) -> impl SuggestStore {
+        self.clone()
+    }
+
+    fn get_alias_store(&self) -> impl AliasStore {
+        self.clone()
+    }
+
+    fn render_metrics(&self) -> String {
+        self.metrics.render()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::{to_bytes, Body};
+    use axum::http::header::COOKIE;
+    use axum::http::{Request, StatusCode};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::backend::tests::{self, post};
+    use crate::config::Config;
+    use crate::services::register_post;
+    use crate::{router, templates, Repositories};
+
+    fn alice() -> UserEntity {
+        tests::user("alice")
+    }
+
+    #[tokio::test]
+    async fn test_post_round_trip() {
+        tests::post_round_trip(InMemoryRepository::default()).await;
+    }
+
+    #[tokio::test]
+    async fn test_search_follows_edits() {
+        tests::search_follows_edits(InMemoryRepository::default()).await;
+    }
+
+    #[tokio::test]
+    async fn test_tag_completions_ranked_by_use() {
+        tests::tag_completions_ranked_by_use(InMemoryRepository::default()).await;
+    }
+
+    #[tokio::test]
+    async fn test_dangling_replies_are_skipped() {
+        tests::dangling_replies_are_skipped(InMemoryRepository::default()).await;
+    }
+
+    #[tokio::test]
+    async fn test_users_and_groups() {
+        tests::users_and_groups(InMemoryRepository::default()).await;
+    }
+
+    #[tokio::test]
+    async fn test_app_without_redis() {
+        let db = InMemoryRepository::default();
+        register_post(db.clone(), &db, post(&alice(), "Brown butter cake"))
+            .await
+            .unwrap();
+        let admin = alice();
+        db.insert_user(admin.clone()).await.unwrap();
+        db.insert_session("token", admin.id, 60).await.unwrap();
+        let app = router(Repositories {
+            db,
+            hb: templates(),
+            config: Arc::new(Config {
+                admins: vec![admin.handle],
+                ..Config::default()
+            }),
+        });
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::get("/en/search?search=butter")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("Brown butter cake"));
+
+        // Only site admins read the metrics.
+        let response = app
+            .clone()
+            .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = app
+            .oneshot(
+                Request::get("/metrics")
+                    .header(COOKIE, "session=token")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("ribbit_search_cache_misses_total 1"));
+    }
+
+    #[tokio::test]
+    async fn test_spent_challenges_are_forgotten() {
+        Pow::init_random().unwrap();
+        let db = InMemoryRepository::default();
+        let solved = Pow::work(&Pow::with_difficulty(10, 60).unwrap().to_string()).unwrap();
+        assert!(db.is_valid_pow(vec![solved.clone()], 60).await);
+        assert!(!db.is_valid_pow(vec![solved], 60).await);
+
+        let solved = Pow::work(&Pow::with_difficulty(10, 60).unwrap().to_string()).unwrap();
+        assert!(db.is_valid_pow(vec![solved], 0).await);
+        assert!(db.is_valid_pow(vec![], 60).await);
+        // Only the first proof is still spent.
+        assert_eq!(db.maps.challenges.len(), 1);
+    }
+}
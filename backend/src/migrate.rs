@@ -0,0 +1,880 @@
+// Versioned migrations of the redka key layout, for stores written by older
+// versions of ribbit. The app should be stopped while they run.
+//
+// The version is the number of migrations applied, kept under `schema_version`.
+// Each migration reads what it needs, then writes its changes along with the
+// new version in a single transaction. Migrations keep their own copy of the
+// layout they write, so that they don't change when the app does. They only
+// rebuild from what is stored, so running one again is harmless.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use chrono::DateTime;
+use redis::aio::MultiplexedConnection;
+use redis::AsyncCommands;
+use serde_json::Value;
+
+use ribbit::config::Config;
+use ribbit::schemas::AppError;
+use ribbit::tokenizer::{analyze, bigrams, fold, strip_html, tokenize, Language};
+
+const SCHEMA_VERSION: &str = "schema_version";
+
+const TITLE_WEIGHT: usize = 3;
+const BODY_WEIGHT: usize = 1;
+
+// The store is the redka section of the app's config, --redka overrides its url.
+const USAGE: &str = "usage: migrate [--redka <url>] [--dry-run] status
+       migrate [--redka <url>] [--dry-run] up [<version>]
+       migrate [--redka <url>] [--dry-run] down <version>";
+
+#[derive(Debug, PartialEq)]
+struct MigrationError(String);
+
+impl From<redis::RedisError> for MigrationError {
+    fn from(value: redis::RedisError) -> Self {
+        Self(value.to_string())
+    }
+}
+
+impl From<AppError> for MigrationError {
+    fn from(value: AppError) -> Self {
+        Self(value.reason)
+    }
+}
+
+impl From<serde_json::Error> for MigrationError {
+    fn from(value: serde_json::Error) -> Self {
+        Self(value.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Migration {
+    PostFields,      // PostEntity gained parent, publisher, created_at, updated_at and lang
+    BlockedBy,       // UserEntity gained blocked_by, the other side of block lists
+    PostsByDate,     // Recency index of posts
+    WeightedTags,    // tag.* sets became zsets scored by field, with author: and group: tags
+    TermDictionary,  // tag_grams.* and tag_prefixes, for typos and completions
+    TitlePrefixes,   // Completion index of public thread roots
+    AliasPhrases,    // Lists the aliases.* phrases, analyzed as queries are
+    TermStats,       // tf.*, doc_freq, doc_lengths and corpus_length, for BM25
+    CompletionWords, // word_prefixes and word_uses of public posts replaced tag_prefixes
+    PhraseTags,      // tag.* of adjacent words, "brown butter", for exact phrases
+    WordPrefixes,    // word_prefix.* of words ranked by use replaced word_prefixes and word_uses
+}
+
+// In the order they apply, version n is the first n of them applied.
+const MIGRATIONS: [Migration; 11] = [
+    Migration::PostFields,
+    Migration::BlockedBy,
+    Migration::PostsByDate,
+    Migration::WeightedTags,
+    Migration::TermDictionary,
+    Migration::TitlePrefixes,
+    Migration::AliasPhrases,
+    Migration::TermStats,
+    Migration::CompletionWords,
+    Migration::PhraseTags,
+    Migration::WordPrefixes,
+];
+
+// Fields added to serialized posts, with the value posts stored before had.
+fn post_field_defaults() -> [(&'static str, Value); 5] {
+    [
+        ("parent", Value::Null),
+        ("publisher", Value::Null),
+        // DateTime::default, what the app reads for a missing date.
+        ("created_at", Value::from("1970-01-01T00:00:00Z")),
+        ("updated_at", Value::from("1970-01-01T00:00:00Z")),
+        ("lang", Value::from("en")),
+    ]
+}
+
+fn text<'a>(value: &'a Value, field: &str) -> &'a str {
+    value[field].as_str().unwrap_or_default()
+}
+
+fn strings(value: &Value, field: &str) -> Vec<String> {
+    value[field]
+        .as_array()
+        .map(|items| {
+            items
+                .iter()
+                .filter_map(|item| item.as_str().map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn post_lang(post: &Value) -> Language {
+    serde_json::from_value(post["lang"].clone()).unwrap_or_default()
+}
+
+fn is_reply(post: &Value) -> bool {
+    !post["parent"].is_null()
+}
+
+fn visibility_group(post: &Value) -> Option<&str> {
+    post["visibility_scope"].as_str().or(post["space"].as_str())
+}
+
+fn created_at(post: &Value) -> i64 {
+    DateTime::parse_from_rfc3339(text(post, "created_at"))
+        .map(|date| date.timestamp())
+        .unwrap_or(0)
+}
+
+fn is_field_tag(tag: &str) -> bool {
+    tag.contains(':')
+}
+
+// Title and tag words, then body words, with the weight of their field.
+fn weighted_words(post: &Value) -> Vec<(String, usize)> {
+    let lang = post_lang(post);
+    analyze(text(post, "title"), lang)
+        .into_iter()
+        .chain(analyze(&strings(post, "search_tags").join(" "), lang))
+        .map(|tag| (tag, TITLE_WEIGHT))
+        .chain(
+            analyze(&strip_html(text(post, "body")), lang)
+                .into_iter()
+                .map(|tag| (tag, BODY_WEIGHT)),
+        )
+        .collect()
+}
+
+// Tags a post is indexed under with their weight, replies have none.
+fn indexed_tags(post: &Value) -> Vec<(String, usize)> {
+    if is_reply(post) {
+        return vec![];
+    }
+    let fields = std::iter::once(format!("author:{}", text(post, "author")))
+        .chain(visibility_group(post).map(|group| format!("group:{group}")))
+        .map(|tag| (tag, 0));
+    let mut weights: HashMap<String, usize> = HashMap::new();
+    for (tag, weight) in weighted_words(post).into_iter().chain(fields) {
+        let best = weights.entry(tag).or_insert(weight);
+        *best = (*best).max(weight);
+    }
+    let mut tags: Vec<_> = weights.into_iter().collect();
+    tags.sort();
+    tags
+}
+
+fn term_frequencies(post: &Value) -> Vec<(String, usize)> {
+    if is_reply(post) {
+        return vec![];
+    }
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for (tag, weight) in weighted_words(post) {
+        *counts.entry(tag).or_insert(0) += weight;
+    }
+    let mut terms: Vec<_> = counts.into_iter().collect();
+    terms.sort();
+    terms
+}
+
+// Each pair of adjacent words of a field, with the weight of the field.
+fn phrase_tags(post: &Value) -> Vec<(String, usize)> {
+    if is_reply(post) {
+        return vec![];
+    }
+    let lang = post_lang(post);
+    let pairs = |text: &str| -> Vec<String> {
+        analyze(text, lang)
+            .windows(2)
+            .map(|pair| pair.join(" "))
+            .collect()
+    };
+    let mut weights: HashMap<String, usize> = HashMap::new();
+    let weighted = std::iter::once(text(post, "title").to_string())
+        .chain(strings(post, "search_tags"))
+        .flat_map(|field| pairs(&field))
+        .map(|tag| (tag, TITLE_WEIGHT))
+        .chain(
+            pairs(&strip_html(text(post, "body")))
+                .into_iter()
+                .map(|tag| (tag, BODY_WEIGHT)),
+        );
+    for (tag, weight) in weighted {
+        let best = weights.entry(tag).or_insert(weight);
+        *best = (*best).max(weight);
+    }
+    let mut tags: Vec<_> = weights.into_iter().collect();
+    tags.sort();
+    tags
+}
+
+// Aliases keyed by their phrase analyzed as queries are, and with analyzed
+// tags, as they are added now. Aliases had no language, they are English.
+// Phrases that become the same are merged, those left empty are dropped.
+fn analyzed_aliases(aliases: Vec<(String, Vec<String>)>) -> BTreeMap<String, BTreeSet<String>> {
+    let lang = Language::default();
+    let mut analyzed: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for (phrase, tags) in aliases {
+        let phrase = analyze(&phrase, lang).join(" ");
+        let tags: BTreeSet<_> = tags.iter().flat_map(|tag| analyze(tag, lang)).collect();
+        if phrase.is_empty() || tags.is_empty() {
+            eprintln!("dropping the alias {phrase:?}, nothing is left of it");
+            continue;
+        }
+        analyzed.entry(phrase).or_default().extend(tags);
+    }
+    analyzed
+}
+
+fn title_member(post: &Value) -> Option<String> {
+    if is_reply(post) || visibility_group(post).is_some() {
+        return None;
+    }
+    let title = text(post, "title");
+    Some(format!("{}\t{}\t{title}", fold(title), text(post, "slug")))
+}
+
+// Distinct words completed as typed, of public thread roots only.
+fn completion_words(post: &Value) -> BTreeSet<String> {
+    if is_reply(post) || visibility_group(post).is_some() {
+        return BTreeSet::new();
+    }
+    let words = format!(
+        "{} {} {}",
+        text(post, "title"),
+        strings(post, "search_tags").join(" "),
+        strip_html(text(post, "body"))
+    );
+    tokenize(&words, post_lang(post)).into_iter().collect()
+}
+
+// The prefixes of the word, from the empty one to the whole word.
+fn word_prefixes(word: &str) -> Vec<&str> {
+    word.char_indices()
+        .map(|(end, _)| &word[..end])
+        .chain([word])
+        .collect()
+}
+
+// Sets the missing fields, the publisher being the user with the author's
+// handle when there is one.
+fn with_post_fields(mut post: Value, publisher: Option<String>) -> Value {
+    if let Some(fields) = post.as_object_mut() {
+        for (field, default) in post_field_defaults() {
+            fields.entry(field).or_insert(default);
+        }
+        if let (Value::Null, Some(publisher)) = (&fields["publisher"], publisher) {
+            fields.insert("publisher".to_string(), Value::from(publisher));
+        }
+    }
+    post
+}
+
+fn without_fields(mut post: Value, fields: &[&str]) -> Value {
+    if let Some(object) = post.as_object_mut() {
+        for field in fields {
+            object.remove(*field);
+        }
+    }
+    post
+}
+
+// Ids of the users who block each user, from everyone's block list.
+fn blocked_by(users: &[Value]) -> HashMap<String, Vec<String>> {
+    let mut blockers: HashMap<String, Vec<String>> = HashMap::new();
+    for user in users {
+        for blocked in strings(user, "block_list") {
+            blockers
+                .entry(blocked)
+                .or_default()
+                .push(text(user, "id").to_string());
+        }
+    }
+    for ids in blockers.values_mut() {
+        ids.sort();
+        ids.dedup();
+    }
+    blockers
+}
+
+fn del_all(pipe: &mut redis::Pipeline, keys: Vec<String>) {
+    if !keys.is_empty() {
+        pipe.del(keys).ignore();
+    }
+}
+
+fn set_json(pipe: &mut redis::Pipeline, key: &str, value: &Value) {
+    pipe.set(key, value.to_string()).ignore();
+}
+
+struct Store {
+    client: MultiplexedConnection,
+}
+
+impl Store {
+    async fn keys(&self, pattern: &str) -> Result<Vec<String>, MigrationError> {
+        let mut keys = self.client.clone().keys::<_, Vec<String>>(pattern).await?;
+        keys.sort();
+        Ok(keys)
+    }
+
+    // Values parsed as JSON, with their keys. Anything else is skipped.
+    async fn json_values(&self, pattern: &str) -> Result<Vec<(String, Value)>, MigrationError> {
+        let keys = self.keys(pattern).await?;
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+        let values: Vec<Option<String>> = redis::cmd("MGET")
+            .arg(&keys)
+            .query_async(&mut self.client.clone())
+            .await?;
+        Ok(keys
+            .into_iter()
+            .zip(values)
+            .filter_map(
+                |(key, value)| match serde_json::from_str::<Value>(&value?) {
+                    Ok(value) if value.is_object() => Some((key, value)),
+                    _ => {
+                        eprintln!("skipping {key}, not a JSON object");
+                        None
+                    }
+                },
+            )
+            .collect())
+    }
+
+    async fn posts(&self) -> Result<Vec<Value>, MigrationError> {
+        Ok(self
+            .json_values("post.*")
+            .await?
+            .into_iter()
+            .map(|(_, post)| post)
+            .collect())
+    }
+
+    // Number of public thread roots using each completion word.
+    async fn word_uses(&self) -> Result<HashMap<String, usize>, MigrationError> {
+        let mut uses: HashMap<String, usize> = HashMap::new();
+        for post in self.posts().await? {
+            for word in completion_words(&post) {
+                *uses.entry(word).or_insert(0) += 1;
+            }
+        }
+        Ok(uses)
+    }
+
+    async fn version(&self) -> Result<usize, MigrationError> {
+        Ok(self
+            .client
+            .clone()
+            .get::<_, Option<usize>>(SCHEMA_VERSION)
+            .await?
+            .unwrap_or(0))
+    }
+}
+
+impl Migration {
+    fn name(&self) -> &'static str {
+        match self {
+            Migration::PostFields => "post fields",
+            Migration::BlockedBy => "user blocked_by",
+            Migration::PostsByDate => "posts_by_date",
+            Migration::WeightedTags => "weighted tags",
+            Migration::TermDictionary => "tag_grams and tag_prefixes",
+            Migration::TitlePrefixes => "title_prefixes",
+            Migration::AliasPhrases => "alias_phrases",
+            Migration::TermStats => "term statistics",
+            Migration::CompletionWords => "word_prefixes and word_uses",
+            Migration::PhraseTags => "phrase tags",
+            Migration::WordPrefixes => "word_prefix.*",
+        }
+    }
+
+    async fn up(&self, store: &Store) -> Result<redis::Pipeline, MigrationError> {
+        let mut pipe = redis::pipe();
+        match self {
+            Migration::PostFields => {
+                let posts = store.json_values("post.*").await?;
+                let mut publishers = HashMap::new();
+                for (_, post) in posts.iter() {
+                    let author = text(post, "author");
+                    if !publishers.contains_key(author) {
+                        let user_id = store
+                            .client
+                            .clone()
+                            .get::<_, Option<String>>(format!("login.{author}"))
+                            .await?;
+                        publishers.insert(author.to_string(), user_id);
+                    }
+                }
+                for (key, post) in posts {
+                    let publisher = publishers[text(&post, "author")].clone();
+                    set_json(&mut pipe, &key, &with_post_fields(post, publisher));
+                }
+            }
+            Migration::BlockedBy => {
+                let users = store.json_values("user.*").await?;
+                let values: Vec<_> = users.iter().map(|(_, user)| user.clone()).collect();
+                let mut blockers = blocked_by(&values);
+                for (key, mut user) in users {
+                    let ids = blockers.remove(text(&user, "id")).unwrap_or_default();
+                    if let Some(fields) = user.as_object_mut() {
+                        fields.insert("blocked_by".to_string(), Value::from(ids));
+                    }
+                    set_json(&mut pipe, &key, &user);
+                }
+            }
+            Migration::PostsByDate => {
+                del_all(&mut pipe, vec!["posts_by_date".to_string()]);
+                for post in store.posts().await? {
+                    pipe.zadd("posts_by_date", text(&post, "slug"), created_at(&post))
+                        .ignore();
+                }
+            }
+            Migration::WeightedTags => {
+                // Sets can't become zsets in place, and words were not stemmed
+                // before, so the index is built again from the posts.
+                del_all(&mut pipe, store.keys("tag.*").await?);
+                for post in store.posts().await? {
+                    for (tag, weight) in indexed_tags(&post) {
+                        pipe.zadd(format!("tag.{tag}"), text(&post, "slug"), weight)
+                            .ignore();
+                    }
+                }
+            }
+            Migration::TermDictionary => {
+                del_all(&mut pipe, store.keys("tag_grams.*").await?);
+                del_all(&mut pipe, vec!["tag_prefixes".to_string()]);
+                let tags: BTreeSet<_> = store
+                    .posts()
+                    .await?
+                    .iter()
+                    .flat_map(indexed_tags)
+                    .map(|(tag, _)| tag)
+                    .filter(|tag| !is_field_tag(tag))
+                    .collect();
+                for tag in tags {
+                    for gram in bigrams(&tag) {
+                        pipe.sadd(format!("tag_grams.{gram}"), &tag).ignore();
+                    }
+                    pipe.zadd("tag_prefixes", &tag, 0).ignore();
+                }
+            }
+            Migration::TitlePrefixes => {
+                del_all(&mut pipe, vec!["title_prefixes".to_string()]);
+                for member in store.posts().await?.iter().filter_map(title_member) {
+                    pipe.zadd("title_prefixes", member, 0).ignore();
+                }
+            }
+            // Stemming a stem mostly gives it back, running this again
+            // can still stem a few phrases further.
+            Migration::AliasPhrases => {
+                let keys = store.keys("aliases.*").await?;
+                let mut aliases = vec![];
+                for key in keys.iter() {
+                    let tags = store.client.clone().smembers::<_, Vec<String>>(key).await?;
+                    aliases.push((key["aliases.".len()..].to_string(), tags));
+                }
+                del_all(&mut pipe, keys);
+                del_all(&mut pipe, vec!["alias_phrases".to_string()]);
+                for (phrase, tags) in analyzed_aliases(aliases) {
+                    pipe.sadd(format!("aliases.{phrase}"), tags).ignore();
+                    pipe.sadd("alias_phrases", phrase).ignore();
+                }
+            }
+            Migration::TermStats => {
+                del_all(&mut pipe, store.keys("tf.*").await?);
+                del_all(
+                    &mut pipe,
+                    vec![
+                        "doc_freq".to_string(),
+                        "doc_lengths".to_string(),
+                        "corpus_length".to_string(),
+                    ],
+                );
+                let mut corpus_length = 0;
+                for post in store.posts().await? {
+                    let terms = term_frequencies(&post);
+                    if terms.is_empty() {
+                        continue;
+                    }
+                    let slug = text(&post, "slug");
+                    let length: usize = terms.iter().map(|(_, count)| count).sum();
+                    pipe.hset_multiple(format!("tf.{slug}"), &terms).ignore();
+                    for (tag, _) in terms.iter() {
+                        pipe.hincr("doc_freq", tag, 1).ignore();
+                    }
+                    pipe.zadd("doc_lengths", slug, length).ignore();
+                    corpus_length += length;
+                }
+                pipe.set("corpus_length", corpus_length).ignore();
+            }
+            // Tags were stems, and listed words of restricted posts too.
+            Migration::CompletionWords => {
+                del_all(
+                    &mut pipe,
+                    ["tag_prefixes", "word_prefixes", "word_uses"]
+                        .map(String::from)
+                        .to_vec(),
+                );
+                for (word, count) in store.word_uses().await? {
+                    pipe.zadd("word_prefixes", &word, 0).ignore();
+                    pipe.zadd("word_uses", &word, count).ignore();
+                }
+            }
+            Migration::PhraseTags => {
+                del_all(&mut pipe, store.keys("tag.* *").await?);
+                for post in store.posts().await? {
+                    for (tag, weight) in phrase_tags(&post) {
+                        pipe.zadd(format!("tag.{tag}"), text(&post, "slug"), weight)
+                            .ignore();
+                    }
+                }
+            }
+            // Scored by minus the uses, so that the most used come first.
+            Migration::WordPrefixes => {
+                let mut keys = store.keys("word_prefix.*").await?;
+                keys.extend(["word_prefixes", "word_uses"].map(String::from));
+                del_all(&mut pipe, keys);
+                for (word, count) in store.word_uses().await? {
+                    for prefix in word_prefixes(&word) {
+                        pipe.zadd(format!("word_prefix.{prefix}"), &word, -(count as i64))
+                            .ignore();
+                    }
+                }
+            }
+        }
+        Ok(pipe)
+    }
+
+    async fn down(&self, store: &Store) -> Result<redis::Pipeline, MigrationError> {
+        let mut pipe = redis::pipe();
+        match self {
+            // Replies then read as thread roots, as they did before.
+            Migration::PostFields => {
+                let fields: Vec<_> = post_field_defaults()
+                    .into_iter()
+                    .map(|(field, _)| field)
+                    .collect();
+                for (key, post) in store.json_values("post.*").await? {
+                    set_json(&mut pipe, &key, &without_fields(post, &fields));
+                }
+            }
+            Migration::BlockedBy => {
+                for (key, user) in store.json_values("user.*").await? {
+                    set_json(&mut pipe, &key, &without_fields(user, &["blocked_by"]));
+                }
+            }
+            Migration::PostsByDate => del_all(&mut pipe, vec!["posts_by_date".to_string()]),
+            // Lossy: members are kept without their weight, under the stemmed
+            // tags. The words they were stemmed from are gone, so posts are
+            // only found by words that are their own stem until reindexed.
+            // Field tags didn't exist.
+            Migration::WeightedTags => {
+                for key in store.keys("tag.*").await? {
+                    let slugs = store
+                        .client
+                        .clone()
+                        .zrange::<_, Vec<String>>(&key, 0, -1)
+                        .await?;
+                    pipe.del(&key).ignore();
+                    if !is_field_tag(&key) && !slugs.is_empty() {
+                        pipe.sadd(&key, slugs).ignore();
+                    }
+                }
+            }
+            Migration::TermDictionary => {
+                del_all(&mut pipe, store.keys("tag_grams.*").await?);
+                del_all(&mut pipe, vec!["tag_prefixes".to_string()]);
+            }
+            Migration::TitlePrefixes => del_all(&mut pipe, vec!["title_prefixes".to_string()]),
+            // Aliases stay analyzed, what was typed is gone.
+            Migration::AliasPhrases => del_all(&mut pipe, vec!["alias_phrases".to_string()]),
+            Migration::TermStats => {
+                let mut keys = store.keys("tf.*").await?;
+                keys.extend(["doc_freq", "doc_lengths", "corpus_length"].map(String::from));
+                del_all(&mut pipe, keys);
+            }
+            // Every indexed tag is listed again, as the term dictionary had them.
+            Migration::CompletionWords => {
+                del_all(
+                    &mut pipe,
+                    ["word_prefixes", "word_uses"].map(String::from).to_vec(),
+                );
+                for key in store.keys("tag.*").await? {
+                    let tag = &key["tag.".len()..];
+                    if !is_field_tag(tag) {
+                        pipe.zadd("tag_prefixes", tag, 0).ignore();
+                    }
+                }
+            }
+            // Words never contain spaces, only pairs of them do.
+            Migration::PhraseTags => del_all(&mut pipe, store.keys("tag.* *").await?),
+            Migration::WordPrefixes => {
+                del_all(&mut pipe, store.keys("word_prefix.*").await?);
+                for (word, count) in store.word_uses().await? {
+                    pipe.zadd("word_prefixes", &word, 0).ignore();
+                    pipe.zadd("word_uses", &word, count).ignore();
+                }
+            }
+        }
+        Ok(pipe)
+    }
+}
+
+// Versions to migrate to in turn, with whether each is applied or reverted.
+fn plan(current: usize, target: usize) -> Vec<(usize, bool)> {
+    if target >= current {
+        (current + 1..=target)
+            .map(|version| (version, true))
+            .collect()
+    } else {
+        (target + 1..=current)
+            .rev()
+            .map(|version| (version, false))
+            .collect()
+    }
+}
+
+// Command name and key, enough to follow a dry run.
+fn describe(cmd: &redis::Cmd) -> String {
+    cmd.args_iter()
+        .take(2)
+        .map(|arg| match arg {
+            redis::Arg::Simple(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+            redis::Arg::Cursor => "<cursor>".to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+async fn migrate(store: &Store, target: usize, dry_run: bool) -> Result<(), MigrationError> {
+    let current = store.version().await?;
+    for (version, up) in plan(current, target) {
+        let migration = MIGRATIONS[version - 1];
+        let (mut pipe, reached) = if up {
+            (migration.up(store).await?, version)
+        } else {
+            (migration.down(store).await?, version - 1)
+        };
+        pipe.set(SCHEMA_VERSION, reached).ignore();
+        let direction = if up { "up" } else { "down" };
+        println!(
+            "{direction} {version} {}: {} writes",
+            migration.name(),
+            pipe.cmd_iter().count()
+        );
+        if dry_run {
+            pipe.cmd_iter()
+                .for_each(|cmd| println!("  {}", describe(cmd)));
+            continue;
+        }
+        pipe.atomic()
+            .query_async::<()>(&mut store.client.clone())
+            .await?;
+    }
+    Ok(())
+}
+
+async fn run(mut args: Vec<String>) -> Result<(), MigrationError> {
+    let usage = || MigrationError(USAGE.to_string());
+    let mut config = Config::load()?;
+    let mut dry_run = false;
+    loop {
+        match args.first().map(String::as_str) {
+            Some("--dry-run") => {
+                dry_run = true;
+                args.remove(0);
+            }
+            Some("--redka") if args.len() > 1 => {
+                config.redka.url = args[1].clone();
+                args.drain(..2);
+            }
+            _ => break,
+        }
+    }
+    let parse = |version: &str| -> Result<usize, MigrationError> {
+        version
+            .parse()
+            .ok()
+            .filter(|version| *version <= MIGRATIONS.len())
+            .ok_or(MigrationError(format!(
+                "unknown version {version}, the latest is {}",
+                MIGRATIONS.len()
+            )))
+    };
+    // Neither the url nor the error is printed, they can hold the password.
+    let info = config
+        .redka
+        .connection_info()
+        .map_err(|err| MigrationError(format!("redka.url: {}", err.reason)))?;
+    let store = Store {
+        client: redis::Client::open(info)?
+            .get_multiplexed_tokio_connection()
+            .await
+            .map_err(|err| MigrationError(format!("can't connect to redka: {:?}", err.kind())))?,
+    };
+    let current = store.version().await?;
+    match args.split_first() {
+        Some((command, [])) if command == "status" => {
+            for (index, migration) in MIGRATIONS.iter().enumerate() {
+                let mark = if index < current { 'x' } else { ' ' };
+                println!("[{mark}] {} {}", index + 1, migration.name());
+            }
+        }
+        Some((command, rest)) if command == "up" && rest.len() <= 1 => {
+            let target = match rest.first() {
+                Some(version) => parse(version)?,
+                None => MIGRATIONS.len(),
+            };
+            if target < current {
+                return Err(MigrationError(format!(
+                    "already at version {current}, use down to revert"
+                )));
+            }
+            migrate(&store, target, dry_run).await?;
+        }
+        Some((command, [version])) if command == "down" => {
+            let target = parse(version)?;
+            if target > current {
+                return Err(MigrationError(format!(
+                    "at version {current}, use up to migrate"
+                )));
+            }
+            migrate(&store, target, dry_run).await?;
+        }
+        _ => return Err(usage()),
+    }
+    Ok(())
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Err(err) = run(args).await {
+        eprintln!("{}", err.0);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn old_post() -> Value {
+        json!({
+            "title": "Brown butter cakes",
+            "slug": "brown-butter-cakes",
+            "author": "alice",
+            "search_tags": ["baking"],
+            "body": "<p>Brown the butter.</p>",
+            "space": null,
+            "reply_scope": null,
+            "visibility_scope": null
+        })
+    }
+
+    #[test]
+    fn test_plan() {
+        assert_eq!(plan(0, 3), vec![(1, true), (2, true), (3, true)]);
+        assert_eq!(plan(3, 1), vec![(3, false), (2, false)]);
+        assert!(plan(2, 2).is_empty());
+    }
+
+    #[test]
+    fn test_post_fields_round_trip() {
+        let post = with_post_fields(old_post(), Some("1234".to_string()));
+        assert_eq!(post["publisher"], json!("1234"));
+        assert_eq!(post["lang"], json!("en"));
+        assert_eq!(post["parent"], Value::Null);
+        assert_eq!(created_at(&post), 0);
+        // Fields already set are kept.
+        let post = with_post_fields(json!({"lang": "fr", "publisher": "5678"}), None);
+        assert_eq!(post["lang"], json!("fr"));
+        assert_eq!(post["publisher"], json!("5678"));
+
+        let fields = post_field_defaults().map(|(field, _)| field);
+        let post = with_post_fields(old_post(), None);
+        assert_eq!(without_fields(post, &fields), old_post());
+    }
+
+    #[test]
+    fn test_indexed_tags() {
+        let mut post = with_post_fields(old_post(), None);
+        assert_eq!(
+            indexed_tags(&post),
+            vec![
+                ("author:alice".to_string(), 0),
+                ("bake".to_string(), TITLE_WEIGHT),
+                ("brown".to_string(), TITLE_WEIGHT),
+                ("butter".to_string(), TITLE_WEIGHT),
+                ("cake".to_string(), TITLE_WEIGHT),
+            ]
+        );
+        assert_eq!(
+            term_frequencies(&post),
+            vec![
+                ("bake".to_string(), 3),
+                ("brown".to_string(), 4),
+                ("butter".to_string(), 4),
+                ("cake".to_string(), 3),
+            ]
+        );
+        assert_eq!(
+            title_member(&post),
+            Some("brown butter cakes\tbrown-butter-cakes\tBrown butter cakes".to_string())
+        );
+
+        assert_eq!(
+            completion_words(&post).into_iter().collect::<Vec<_>>(),
+            vec!["baking", "brown", "butter", "cakes"]
+        );
+
+        assert_eq!(
+            phrase_tags(&post),
+            vec![
+                ("brown butter".to_string(), TITLE_WEIGHT),
+                ("butter cake".to_string(), TITLE_WEIGHT),
+            ]
+        );
+
+        post["parent"] = json!("cake-recipes");
+        assert!(indexed_tags(&post).is_empty());
+        assert!(phrase_tags(&post).is_empty());
+        assert!(title_member(&post).is_none());
+        assert!(completion_words(&post).is_empty());
+    }
+
+    #[test]
+    fn test_blocked_by() {
+        let users = vec![
+            json!({"id": "a", "block_list": ["c"]}),
+            json!({"id": "b", "block_list": ["c", "a"]}),
+            json!({"id": "c", "block_list": []}),
+        ];
+        let blockers = blocked_by(&users);
+        assert_eq!(blockers["c"], vec!["a", "b"]);
+        assert_eq!(blockers["a"], vec!["b"]);
+        assert!(!blockers.contains_key("b"));
+    }
+
+    #[test]
+    fn test_word_prefixes() {
+        assert_eq!(word_prefixes("été"), vec!["", "é", "ét", "été"]);
+        assert_eq!(word_prefixes(""), vec![""]);
+    }
+
+    #[test]
+    fn test_analyzed_aliases() {
+        let aliases = analyzed_aliases(vec![
+            ("Brown Butter".to_string(), vec!["Cakes".to_string()]),
+            ("brown butter".to_string(), vec!["baking".to_string()]),
+            ("!!".to_string(), vec!["cake".to_string()]),
+        ]);
+        assert_eq!(aliases.len(), 1);
+        assert_eq!(
+            aliases["brown butter"],
+            BTreeSet::from(["bake".to_string(), "cake".to_string()])
+        );
+    }
+}
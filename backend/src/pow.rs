@@ -1,6 +1,9 @@
 pub trait PowValidator {
+    // Solved challenges are remembered for valid_seconds, so that each is only
+    // accepted once while Pow::validate still takes it.
     fn is_valid_pow(
         &self,
-        challenges: [String; 16],
+        challenges: Vec<String>,
+        valid_seconds: u64,
     ) -> impl std::future::Future<Output = bool> + std::marker::Send;
 }
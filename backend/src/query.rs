@@ -0,0 +1,344 @@
+// Search query language:
+//   butter flour        free words, ranked by how much of them items match
+//   "brown butter"      exact phrase, required, its words next to each other
+//   -margarine          excluded word, or -"phrase", -tag:..., -author:...
+//   tag:vegan           required tag, with no alias or typo lookup
+//   author:handle       posts by that author
+//   group:uuid          posts visible to that group
+//   cake OR pie         either side, each side is a clause of the above
+
+use slug::slugify;
+
+use crate::tokenizer::{analyze, Language};
+
+// Tag items are indexed under for the value of one of their fields.
+pub fn field_tag(field: &str, value: &str) -> String {
+    format!("{field}:{value}")
+}
+
+// Tags of items whose field has this value, "tag" being the tags themselves.
+pub fn tags_from_field(field: &str, value: &str) -> Vec<String> {
+    match field {
+        "tag" => vec![value.to_string()],
+        "author" => vec![field_tag(field, &slugify(value))],
+        _ => vec![field_tag(field, &value.to_lowercase())],
+    }
+}
+
+// Tags of each pair of adjacent words, for items to match exact phrases.
+pub fn pair_tags(words: &[String]) -> Vec<String> {
+    words.windows(2).map(|pair| pair.join(" ")).collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Filter {
+    Phrase(String),
+    Tag(String),
+    Author(String),
+    Group(String),
+}
+
+// Items matching every required filter, none of the excluded ones, ranked by
+// the free words. Without required filters, matching any free word is enough.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Clause {
+    pub words: Vec<String>,
+    pub required: Vec<Filter>,
+    pub excluded: Vec<Filter>,
+}
+
+impl Clause {
+    fn is_empty(&self) -> bool {
+        self.words.is_empty() && self.required.is_empty()
+    }
+}
+
+// Clauses joined by OR.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct QueryAst {
+    pub clauses: Vec<Clause>,
+}
+
+impl Filter {
+    fn normalized(&self, lang: Language) -> String {
+        match self {
+            Filter::Phrase(text) => format!("\"{}\"", analyze(text, lang).join(" ")),
+            Filter::Tag(value) => field_tag("tag", &analyze(value, lang).join(" ")),
+            Filter::Author(value) => field_tag("author", &value.to_lowercase()),
+            Filter::Group(value) => field_tag("group", &value.to_lowercase()),
+        }
+    }
+}
+
+impl Clause {
+    // Words keep their order, as it makes phrases, filters don't.
+    fn normalized(&self, lang: Language) -> String {
+        let mut required: Vec<_> = self.required.iter().map(|f| f.normalized(lang)).collect();
+        let mut excluded: Vec<_> = self
+            .excluded
+            .iter()
+            .map(|f| format!("-{}", f.normalized(lang)))
+            .collect();
+        required.sort();
+        excluded.sort();
+        analyze(&self.words.join(" "), lang)
+            .into_iter()
+            .chain(required)
+            .chain(excluded)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+impl QueryAst {
+    // Same text for queries that search the same, to share cache entries.
+    pub fn normalized(&self, lang: Language) -> String {
+        self.clauses
+            .iter()
+            .map(|clause| clause.normalized(lang))
+            .collect::<Vec<_>>()
+            .join(" OR ")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+}
+
+// Splits on whitespace, keeping quoted text whole. An unterminated quote runs
+// to the end of the query. `tag:"ice cream"` stays one word.
+fn lex(query: &str) -> Vec<(bool, Token)> {
+    let mut tokens = vec![];
+    let mut chars = query.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(&first) = chars.peek() else {
+            break;
+        };
+        let negated = first == '-';
+        if negated {
+            chars.next();
+        }
+        let mut text = String::new();
+        let mut quoted = false;
+        while let Some(c) = chars.next() {
+            if c == '"' {
+                quoted = true;
+                text.extend(chars.by_ref().take_while(|c| *c != '"'));
+                continue;
+            }
+            if c.is_whitespace() {
+                break;
+            }
+            text.push(c);
+        }
+        if text.is_empty() {
+            continue;
+        }
+        let token = if quoted && !text.contains(':') {
+            Token::Quoted(text)
+        } else {
+            Token::Word(text)
+        };
+        tokens.push((negated, token));
+    }
+    tokens
+}
+
+fn field_filter(word: &str) -> Option<Filter> {
+    let (field, value) = word.split_once(':')?;
+    let value = value.trim().to_string();
+    if value.is_empty() {
+        return None;
+    }
+    match field.to_lowercase().as_str() {
+        "tag" => Some(Filter::Tag(value)),
+        "author" => Some(Filter::Author(value)),
+        "group" => Some(Filter::Group(value)),
+        _ => None,
+    }
+}
+
+pub fn parse_query(query: &str) -> QueryAst {
+    let mut clauses = vec![];
+    let mut clause = Clause::default();
+    for (negated, token) in lex(query) {
+        let filter = match token {
+            Token::Word(word) if word == "OR" && !negated => {
+                clauses.push(std::mem::take(&mut clause));
+                continue;
+            }
+            Token::Word(word) => match field_filter(&word) {
+                Some(filter) => filter,
+                None if negated => Filter::Phrase(word),
+                None => {
+                    clause.words.push(word);
+                    continue;
+                }
+            },
+            Token::Quoted(phrase) => Filter::Phrase(phrase),
+        };
+        if negated {
+            clause.excluded.push(filter);
+        } else {
+            clause.required.push(filter);
+        }
+    }
+    clauses.push(clause);
+    QueryAst {
+        clauses: clauses.into_iter().filter(|c| !c.is_empty()).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(words: &[&str]) -> Vec<String> {
+        words.iter().map(|w| w.to_string()).collect()
+    }
+
+    fn phrase(text: &str) -> Filter {
+        Filter::Phrase(text.to_string())
+    }
+
+    #[test]
+    fn test_plain_words() {
+        assert_eq!(
+            parse_query("  butter   flour "),
+            QueryAst {
+                clauses: vec![Clause {
+                    words: words(&["butter", "flour"]),
+                    ..Default::default()
+                }]
+            }
+        );
+    }
+
+    #[test]
+    fn test_empty_query() {
+        assert_eq!(parse_query(""), QueryAst::default());
+        assert_eq!(parse_query(" OR  "), QueryAst::default());
+    }
+
+    #[test]
+    fn test_quoted_phrase() {
+        assert_eq!(
+            parse_query("cake \"brown butter\""),
+            QueryAst {
+                clauses: vec![Clause {
+                    words: words(&["cake"]),
+                    required: vec![phrase("brown butter")],
+                    ..Default::default()
+                }]
+            }
+        );
+    }
+
+    #[test]
+    fn test_unterminated_quote() {
+        assert_eq!(
+            parse_query("\"brown butter"),
+            QueryAst {
+                clauses: vec![Clause {
+                    required: vec![phrase("brown butter")],
+                    ..Default::default()
+                }]
+            }
+        );
+    }
+
+    #[test]
+    fn test_exclusions() {
+        assert_eq!(
+            parse_query("cake -margarine -\"palm oil\" -author:bob"),
+            QueryAst {
+                clauses: vec![Clause {
+                    words: words(&["cake"]),
+                    excluded: vec![
+                        phrase("margarine"),
+                        phrase("palm oil"),
+                        Filter::Author("bob".to_string())
+                    ],
+                    ..Default::default()
+                }]
+            }
+        );
+    }
+
+    #[test]
+    fn test_fields() {
+        assert_eq!(
+            parse_query("tag:vegan Author:alice group:1234 tag:\"ice cream\""),
+            QueryAst {
+                clauses: vec![Clause {
+                    required: vec![
+                        Filter::Tag("vegan".to_string()),
+                        Filter::Author("alice".to_string()),
+                        Filter::Group("1234".to_string()),
+                        Filter::Tag("ice cream".to_string()),
+                    ],
+                    ..Default::default()
+                }]
+            }
+        );
+    }
+
+    #[test]
+    fn test_unknown_field_is_a_word() {
+        assert_eq!(
+            parse_query("time:12 tag:"),
+            QueryAst {
+                clauses: vec![Clause {
+                    words: words(&["time:12", "tag:"]),
+                    ..Default::default()
+                }]
+            }
+        );
+    }
+
+    #[test]
+    fn test_or() {
+        assert_eq!(
+            parse_query("cake tag:vegan OR pie -apple"),
+            QueryAst {
+                clauses: vec![
+                    Clause {
+                        words: words(&["cake"]),
+                        required: vec![Filter::Tag("vegan".to_string())],
+                        ..Default::default()
+                    },
+                    Clause {
+                        words: words(&["pie"]),
+                        excluded: vec![phrase("apple")],
+                        ..Default::default()
+                    },
+                ]
+            }
+        );
+    }
+
+    #[test]
+    fn test_normalized() {
+        assert_eq!(
+            parse_query("  Cakes -Margarine  author:Bob \"Brown  butter\" OR pie")
+                .normalized(Language::En),
+            "cake \"brown butter\" author:bob -\"margarin\" OR pie"
+        );
+    }
+
+    #[test]
+    fn test_lowercase_or_is_a_word() {
+        assert_eq!(
+            parse_query("cake or pie"),
+            QueryAst {
+                clauses: vec![Clause {
+                    words: words(&["cake", "or", "pie"]),
+                    ..Default::default()
+                }]
+            }
+        );
+    }
+}
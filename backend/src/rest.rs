@@ -1,37 +1,223 @@
+use crate::aliases::AliasStore;
+use crate::authorization::can_publish;
+use crate::backend::Backend;
+use crate::config::PowConfig;
 use crate::pow::PowValidator;
-use crate::schemas::PostEntity;
-use crate::search::ItemRepo;
+use crate::schemas::{AppError, ErrorKind, GroupId, GroupManagement, PostEntity, UserEntity};
+use crate::search::{SearchOptions, SortOrder};
 use crate::services::register_post;
-use crate::{services, Repositories};
-use axum::extract::{Path, Query, State};
+use crate::tokenizer::Language;
+use crate::{aliases, blocking, groups, services, suggest, threads, users, Repositories};
+use axum::async_trait;
+use axum::extract::{FromRequestParts, Path, Query, State};
+use axum::http::header::{COOKIE, SET_COOKIE};
+use axum::http::request::Parts;
 use axum::http::StatusCode;
-use axum::response::{Html, IntoResponse};
+use axum::response::{Html, IntoResponse, Redirect, Response};
 use axum::{Form, Json};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use spow::pow::Pow;
 use std::collections::HashMap;
 
-pub async fn search_post(
-    State(repo): State<Repositories>,
+const SESSION_COOKIE: &str = "session";
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self.kind {
+            ErrorKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorKind::BadRequest => StatusCode::BAD_REQUEST,
+            ErrorKind::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorKind::Forbidden => StatusCode::FORBIDDEN,
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::Conflict => StatusCode::CONFLICT,
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        if status == StatusCode::INTERNAL_SERVER_ERROR {
+            tracing::error!("{:?}", self.reason);
+        }
+        status.into_response()
+    }
+}
+
+// The signed in user, if the request carries a valid session cookie.
+pub struct Viewer(pub Option<UserEntity>);
+
+#[async_trait]
+impl<Db: Backend> FromRequestParts<Repositories<Db>> for Viewer {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        repo: &Repositories<Db>,
+    ) -> Result<Self, Self::Rejection> {
+        let token = parts
+            .headers
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .find(|(name, _value)| *name == SESSION_COOKIE)
+            .map(|(_name, value)| value.to_owned());
+        match token {
+            Some(token) => Ok(Viewer(
+                users::find_session_user(&repo.db.get_user_store(), &token).await?,
+            )),
+            None => Ok(Viewer(None)),
+        }
+    }
+}
+
+// Like Viewer, but rejects anonymous requests.
+pub struct SignedIn(pub UserEntity);
+
+#[async_trait]
+impl<Db: Backend> FromRequestParts<Repositories<Db>> for SignedIn {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        repo: &Repositories<Db>,
+    ) -> Result<Self, Self::Rejection> {
+        match Viewer::from_request_parts(parts, repo).await? {
+            Viewer(Some(user)) => Ok(SignedIn(user)),
+            Viewer(None) => Err(AppError::new(ErrorKind::Unauthorized, "not signed in")),
+        }
+    }
+}
+
+// Like SignedIn, but only for the site admins listed in the configuration.
+pub struct SiteAdmin(pub UserEntity);
+
+#[async_trait]
+impl<Db: Backend> FromRequestParts<Repositories<Db>> for SiteAdmin {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        repo: &Repositories<Db>,
+    ) -> Result<Self, Self::Rejection> {
+        let SignedIn(user) = SignedIn::from_request_parts(parts, repo).await?;
+        if !repo.config.admins.contains(&user.handle) {
+            return Err(AppError::new(ErrorKind::Forbidden, "site admins only"));
+        }
+        Ok(SiteAdmin(user))
+    }
+}
+
+fn session_cookie(token: &str) -> String {
+    format!(
+        "{SESSION_COOKIE}={token}; Max-Age={}; Path=/; HttpOnly; Secure; SameSite=Lax",
+        users::SESSION_TTL
+    )
+}
+
+// Pages count from 1, and a page that isn't a number is a bad request.
+fn page_param(params: &HashMap<String, String>) -> Result<usize, AppError> {
+    params.get("page").map_or(Ok(1), |page| {
+        page.parse::<usize>()
+            .map(|page| page.max(1))
+            .map_err(|_| AppError::new(ErrorKind::BadRequest, "the page must be a number"))
+    })
+}
+
+pub async fn search_post<Db: Backend>(
+    State(repo): State<Repositories<Db>>,
+    Viewer(viewer): Viewer,
     Path(lang): Path<String>,
     Query(search_params): Query<HashMap<String, String>>,
 ) -> impl IntoResponse {
-    let search_page = search_params
-        .get("page")
-        .unwrap_or(&"1".to_owned())
-        .parse::<usize>()
-        .unwrap();
+    let search_page = match page_param(&search_params) {
+        Ok(page) => page,
+        Err(err) => return err.into_response(),
+    };
     let search_query = search_params
         .get("search")
         .unwrap_or(&"".to_owned())
         .to_string();
-    let result = services::find_posts(repo.db, search_query.as_str(), search_page).await;
-    if result.is_err() {
-        tracing::error!("{:?}", result.unwrap_err());
-        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    let sort = match search_params.get("sort").map(|s| s.as_str()) {
+        Some("recent") => SortOrder::Recency,
+        _ => SortOrder::RelevanceThenRecency,
+    };
+    let result = services::find_posts(
+        repo.db.clone(),
+        &repo.db.get_author_store(),
+        &repo.db.get_user_store(),
+        viewer.as_ref(),
+        search_query.as_str(),
+        SearchOptions {
+            sort,
+            lang: Language::from_code(&lang),
+            word_max: repo.config.search.word_max,
+            phrase_max: repo.config.search.phrase_max,
+            ..Default::default()
+        },
+        search_page,
+        repo.config.search.page_size,
+    )
+    .await;
+    let result = match result {
+        Ok(result) => result,
+        Err(err) => {
+            tracing::error!("{:?}", err);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    match repo.hb.render("list", &result) {
+        Ok(html) => Html::from(html).into_response(),
+        Err(err) => {
+            tracing::error!("{:?}", err.to_string());
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
     }
-    match repo.hb.render("list", &result.unwrap()) {
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SuggestParams {
+    #[serde(default)]
+    pub q: String,
+}
+
+pub async fn suggest<Db: Backend>(
+    State(repo): State<Repositories<Db>>,
+    Path(_lang): Path<String>,
+    Query(params): Query<SuggestParams>,
+) -> impl IntoResponse {
+    suggest::suggest(&repo.db.get_suggest_store(), &params.q)
+        .await
+        .map(Json)
+}
+
+pub async fn get_post<Db: Backend>(
+    State(repo): State<Repositories<Db>>,
+    Viewer(viewer): Viewer,
+    Path((_lang, slug)): Path<(String, String)>,
+) -> impl IntoResponse {
+    let result = services::find_post(
+        repo.db.get_db(),
+        &repo.db.get_author_store(),
+        &repo.db.get_thread_store(),
+        &repo.db.get_user_store(),
+        viewer.as_ref(),
+        slug,
+    )
+    .await;
+    let post = match result {
+        Ok(post) => post,
+        Err(err) => return err.into_response(),
+    };
+    // Signed in readers solve challenges in the background, for their reply.
+    let mut page = json!(post);
+    if viewer.is_some() {
+        page["challenges"] = json!(new_challenges(&repo.config.pow));
+    }
+    match repo.hb.render("post", &page) {
         Ok(html) => Html::from(html).into_response(),
         Err(err) => {
             tracing::error!("{:?}", err.to_string());
@@ -40,16 +226,95 @@ pub async fn search_post(
     }
 }
 
-pub async fn get_post(
-    State(repo): State<Repositories>,
-    Path((lang, slug)): Path<(String, String)>,
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EditForm {
+    pub title: String,
+    pub body: String,
+    pub tags: String,
+}
+
+pub async fn edit_post<Db: Backend>(
+    State(repo): State<Repositories<Db>>,
+    SignedIn(user): SignedIn,
+    Path((_lang, slug)): Path<(String, String)>,
+    Json(form): Json<EditForm>,
+) -> impl IntoResponse {
+    services::edit_post(&repo.db, &user, slug, form.title, &form.body, &form.tags)
+        .await
+        .map(|post| post.slug)
+}
+
+pub async fn delete_post<Db: Backend>(
+    State(repo): State<Repositories<Db>>,
+    SignedIn(user): SignedIn,
+    Path((_lang, slug)): Path<(String, String)>,
 ) -> impl IntoResponse {
-    let result = services::find_post(repo.db.get_db(), slug).await;
-    if result.is_err() {
-        tracing::error!("{:?}", result.unwrap_err().reason);
-        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    services::delete_post(
+        &repo.db,
+        &repo.db.get_author_store(),
+        &repo.db.get_thread_store(),
+        &user,
+        slug,
+    )
+    .await
+    .map(|_| StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReplyForm {
+    pub body: String,
+    pub challenges: Vec<String>,
+}
+
+pub async fn reply_post<Db: Backend>(
+    State(repo): State<Repositories<Db>>,
+    SignedIn(user): SignedIn,
+    Path((_lang, slug)): Path<(String, String)>,
+    Json(form): Json<ReplyForm>,
+) -> impl IntoResponse {
+    if !is_valid_pow(&repo, form.challenges).await {
+        return StatusCode::BAD_REQUEST.into_response();
     }
-    match repo.hb.render("post", &result.unwrap()) {
+    let result = threads::reply_to_post(
+        &repo.db,
+        &repo.db.get_thread_store(),
+        &repo.db.get_user_store(),
+        &user,
+        slug,
+        &form.body,
+    )
+    .await;
+    match result {
+        Ok(reply) => reply.slug.into_response(),
+        Err(err) => err.into_response(),
+    }
+}
+
+pub async fn get_author<Db: Backend>(
+    State(repo): State<Repositories<Db>>,
+    Viewer(viewer): Viewer,
+    Path((_lang, handle)): Path<(String, String)>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let page = match page_param(&params) {
+        Ok(page) => page,
+        Err(err) => return err.into_response(),
+    };
+    let result = services::find_author_profile(
+        repo.db.get_db(),
+        &repo.db.get_author_store(),
+        &repo.db.get_user_store(),
+        viewer.as_ref(),
+        handle,
+        page,
+        repo.config.search.page_size,
+    )
+    .await;
+    let profile = match result {
+        Ok(profile) => profile,
+        Err(err) => return err.into_response(),
+    };
+    match repo.hb.render("author", &profile) {
         Ok(html) => Html::from(html).into_response(),
         Err(err) => {
             tracing::error!("{:?}", err.to_string());
@@ -58,7 +323,10 @@ pub async fn get_post(
     }
 }
 
-pub async fn home(State(repo): State<Repositories>, Path(lang): Path<String>) -> impl IntoResponse {
+pub async fn home<Db: Backend>(
+    State(repo): State<Repositories<Db>>,
+    Path(_lang): Path<String>,
+) -> impl IntoResponse {
     match repo.hb.render("home", &json!({})) {
         Ok(html) => Html::from(html).into_response(),
         Err(err) => {
@@ -68,13 +336,45 @@ pub async fn home(State(repo): State<Repositories>, Path(lang): Path<String>) ->
     }
 }
 
-pub async fn get_challenge_form(State(repo): State<Repositories>) -> impl IntoResponse {
-    // Create a new PoW which will be valid for 15 minutes
-    let pows: Vec<_> = (0..16)
-        .map(|_i| Pow::with_difficulty(18, 900).unwrap().to_string())
-        .collect();
+// Challenges solved by the browser before publishing a post or a reply.
+fn new_challenges(pow: &PowConfig) -> Vec<String> {
+    (0..pow.challenges)
+        .map(|_i| {
+            Pow::with_difficulty(pow.difficulty, pow.valid_seconds)
+                .unwrap()
+                .to_string()
+        })
+        .collect()
+}
+
+async fn is_valid_pow<Db: Backend>(repo: &Repositories<Db>, challenges: Vec<String>) -> bool {
+    let pow = &repo.config.pow;
+    challenges.len() == pow.challenges
+        && repo
+            .db
+            .get_pow_validator()
+            .is_valid_pow(challenges, pow.valid_seconds.into())
+            .await
+}
+
+pub async fn get_challenge_form<Db: Backend>(
+    State(repo): State<Repositories<Db>>,
+    Viewer(viewer): Viewer,
+    Path(lang): Path<String>,
+) -> impl IntoResponse {
+    let Some(user) = viewer else {
+        return Redirect::to(format!("/{lang}/sign-in").as_str()).into_response();
+    };
+    let pows = new_challenges(&repo.config.pow);
+    let faces = match groups::find_user_faces(&repo.db.get_group_store(), &user).await {
+        Ok(faces) => faces,
+        Err(err) => return err.into_response(),
+    };
 
-    match repo.hb.render("publish", &json!({"challenges": pows})) {
+    match repo.hb.render(
+        "publish",
+        &json!({"challenges": pows, "handle": user.handle, "faces": faces}),
+    ) {
         Ok(html) => Html::from(html).into_response(),
         Err(err) => {
             tracing::error!("{:?}", err.to_string());
@@ -90,28 +390,367 @@ pub struct PublishForm {
     pub visibility_group: Option<uuid::Uuid>,
     pub reply_group: Option<uuid::Uuid>,
     pub tags: String,
-    pub challenges: [String; 16],
+    pub challenges: Vec<String>,
+    #[serde(default)]
+    pub post_as: Option<String>, // A group face to publish as, instead of my own handle
 }
 
-pub async fn post_form(
-    State(repo): State<Repositories>,
+pub async fn post_form<Db: Backend>(
+    State(repo): State<Repositories<Db>>,
+    Viewer(viewer): Viewer,
+    Path(lang): Path<String>,
     Json(submit): Json<PublishForm>,
 ) -> impl IntoResponse {
-    if !repo
-        .db
-        .get_pow_validator()
-        .is_valid_pow(submit.challenges.clone())
-        .await
-    {
+    let Some(user) = viewer else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    if !is_valid_pow(&repo, submit.challenges.clone()).await {
         return StatusCode::BAD_REQUEST.into_response();
     }
     tracing::info!("{:?}", submit.body.clone());
-    let post = PostEntity::from_form(submit);
-    match register_post(repo.db, post.clone()).await {
-        Ok(_) => post.slug.into_response(),
+    let author =
+        match groups::resolve_post_as(&repo.db.get_group_store(), &user, submit.post_as.clone())
+            .await
+        {
+            Ok(author) => author,
+            Err(err) => return err.into_response(),
+        };
+    let post = PostEntity::from_form(submit, author, user.id, Language::from_code(&lang));
+    match can_publish(&repo.db.get_group_store(), &post, &user).await {
+        Ok(true) => {}
+        Ok(false) => return StatusCode::FORBIDDEN.into_response(),
+        Err(err) => return err.into_response(),
+    }
+    match register_post(repo.db.clone(), &repo.db.get_author_store(), post).await {
+        Ok(slug) => slug.into_response(),
+        Err(err) => {
+            tracing::error!("{:?}", err.to_string());
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SignUpForm {
+    pub handle: String,
+    pub password: String,
+    pub password_confirm: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SignInForm {
+    pub handle: String,
+    pub password: String,
+}
+
+fn render_account_form<Db: Backend>(
+    repo: &Repositories<Db>,
+    template: &str,
+    error: Option<&AppError>,
+) -> Response {
+    let status = match error {
+        Some(err) => err.status(),
+        None => StatusCode::OK,
+    };
+    if status == StatusCode::INTERNAL_SERVER_ERROR {
+        tracing::error!("{:?}", error.unwrap().reason);
+        return status.into_response();
+    }
+    match repo.hb.render(
+        template,
+        &json!({"error": error.map(|err| err.reason.clone())}),
+    ) {
+        Ok(html) => (status, Html::from(html)).into_response(),
         Err(err) => {
             tracing::error!("{:?}", err.to_string());
             StatusCode::INTERNAL_SERVER_ERROR.into_response()
         }
     }
 }
+
+fn signed_in_redirect(lang: &str, token: &str) -> Response {
+    (
+        [(SET_COOKIE, session_cookie(token))],
+        Redirect::to(format!("/{lang}/home").as_str()),
+    )
+        .into_response()
+}
+
+pub async fn get_sign_up_form<Db: Backend>(
+    State(repo): State<Repositories<Db>>,
+) -> impl IntoResponse {
+    render_account_form(&repo, "sign_up", None)
+}
+
+pub async fn sign_up<Db: Backend>(
+    State(repo): State<Repositories<Db>>,
+    Path(lang): Path<String>,
+    Form(form): Form<SignUpForm>,
+) -> impl IntoResponse {
+    if form.password != form.password_confirm {
+        let err = AppError::new(ErrorKind::BadRequest, "passwords do not match");
+        return render_account_form(&repo, "sign_up", Some(&err));
+    }
+    match users::sign_up(
+        &repo.db.get_user_store(),
+        &repo.db.get_author_store(),
+        &form.handle,
+        &form.password,
+    )
+    .await
+    {
+        Ok(token) => signed_in_redirect(&lang, &token),
+        Err(err) => render_account_form(&repo, "sign_up", Some(&err)),
+    }
+}
+
+pub async fn get_sign_in_form<Db: Backend>(
+    State(repo): State<Repositories<Db>>,
+) -> impl IntoResponse {
+    render_account_form(&repo, "sign_in", None)
+}
+
+pub async fn sign_in<Db: Backend>(
+    State(repo): State<Repositories<Db>>,
+    Path(lang): Path<String>,
+    Form(form): Form<SignInForm>,
+) -> impl IntoResponse {
+    match users::sign_in(&repo.db.get_user_store(), &form.handle, &form.password).await {
+        Ok(token) => signed_in_redirect(&lang, &token),
+        Err(err) => render_account_form(&repo, "sign_in", Some(&err)),
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GroupForm {
+    pub name: String,
+    pub management: GroupManagement,
+    pub allow_member_posting: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GroupMemberForm {
+    pub handle: String,
+}
+
+pub async fn create_group<Db: Backend>(
+    State(repo): State<Repositories<Db>>,
+    SignedIn(user): SignedIn,
+    Json(form): Json<GroupForm>,
+) -> impl IntoResponse {
+    groups::create_group(
+        &repo.db.get_group_store(),
+        &repo.db.get_user_store(),
+        user,
+        form.name,
+        form.management,
+        form.allow_member_posting,
+    )
+    .await
+    .map(Json)
+}
+
+pub async fn get_group<Db: Backend>(
+    State(repo): State<Repositories<Db>>,
+    Viewer(viewer): Viewer,
+    Path((_lang, group_id)): Path<(String, GroupId)>,
+) -> impl IntoResponse {
+    groups::find_group(&repo.db.get_group_store(), viewer.as_ref(), group_id)
+        .await
+        .map(Json)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GroupFaceForm {
+    pub handle: String,
+    pub name: String,
+}
+
+pub async fn set_group_face<Db: Backend>(
+    State(repo): State<Repositories<Db>>,
+    SignedIn(user): SignedIn,
+    Path((_lang, group_id)): Path<(String, GroupId)>,
+    Json(form): Json<GroupFaceForm>,
+) -> impl IntoResponse {
+    groups::set_group_face(
+        &repo.db.get_group_store(),
+        &repo.db.get_author_store(),
+        user,
+        group_id,
+        &form.handle,
+        form.name,
+    )
+    .await
+    .map(Json)
+}
+
+pub async fn join_group<Db: Backend>(
+    State(repo): State<Repositories<Db>>,
+    SignedIn(user): SignedIn,
+    Path((_lang, group_id)): Path<(String, GroupId)>,
+) -> impl IntoResponse {
+    groups::join_group(
+        &repo.db.get_group_store(),
+        &repo.db.get_user_store(),
+        user,
+        group_id,
+    )
+    .await
+    .map(Json)
+}
+
+pub async fn leave_group<Db: Backend>(
+    State(repo): State<Repositories<Db>>,
+    SignedIn(user): SignedIn,
+    Path((_lang, group_id)): Path<(String, GroupId)>,
+) -> impl IntoResponse {
+    groups::leave_group(
+        &repo.db.get_group_store(),
+        &repo.db.get_user_store(),
+        user,
+        group_id,
+    )
+    .await
+    .map(Json)
+}
+
+pub async fn invite_member<Db: Backend>(
+    State(repo): State<Repositories<Db>>,
+    SignedIn(user): SignedIn,
+    Path((_lang, group_id)): Path<(String, GroupId)>,
+    Json(form): Json<GroupMemberForm>,
+) -> impl IntoResponse {
+    groups::invite_member(
+        &repo.db.get_group_store(),
+        &repo.db.get_user_store(),
+        user,
+        group_id,
+        &form.handle,
+    )
+    .await
+    .map(Json)
+}
+
+pub async fn promote_admin<Db: Backend>(
+    State(repo): State<Repositories<Db>>,
+    SignedIn(user): SignedIn,
+    Path((_lang, group_id)): Path<(String, GroupId)>,
+    Json(form): Json<GroupMemberForm>,
+) -> impl IntoResponse {
+    groups::promote_admin(
+        &repo.db.get_group_store(),
+        &repo.db.get_user_store(),
+        user,
+        group_id,
+        &form.handle,
+    )
+    .await
+    .map(Json)
+}
+
+pub async fn demote_admin<Db: Backend>(
+    State(repo): State<Repositories<Db>>,
+    SignedIn(user): SignedIn,
+    Path((_lang, group_id)): Path<(String, GroupId)>,
+    Json(form): Json<GroupMemberForm>,
+) -> impl IntoResponse {
+    groups::demote_admin(
+        &repo.db.get_group_store(),
+        &repo.db.get_user_store(),
+        user,
+        group_id,
+        &form.handle,
+    )
+    .await
+    .map(Json)
+}
+
+pub async fn block_user<Db: Backend>(
+    State(repo): State<Repositories<Db>>,
+    SignedIn(user): SignedIn,
+    Path((_lang, handle)): Path<(String, String)>,
+) -> impl IntoResponse {
+    blocking::block_user(
+        &repo.db.get_user_store(),
+        &repo.db.get_group_store(),
+        user,
+        &handle,
+    )
+    .await
+    .map(|_| StatusCode::NO_CONTENT)
+}
+
+pub async fn unblock_user<Db: Backend>(
+    State(repo): State<Repositories<Db>>,
+    SignedIn(user): SignedIn,
+    Path((_lang, handle)): Path<(String, String)>,
+) -> impl IntoResponse {
+    blocking::unblock_user(&repo.db.get_user_store(), user, &handle)
+        .await
+        .map(|_| StatusCode::NO_CONTENT)
+}
+
+pub async fn list_aliases<Db: Backend>(
+    State(repo): State<Repositories<Db>>,
+    SiteAdmin(_admin): SiteAdmin,
+    Path(_lang): Path<String>,
+) -> impl IntoResponse {
+    repo.db.get_alias_store().get_aliases().await.map(Json)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AliasForm {
+    pub phrase: String,
+    pub tags: Vec<String>,
+}
+
+pub async fn add_alias<Db: Backend>(
+    State(repo): State<Repositories<Db>>,
+    SiteAdmin(_admin): SiteAdmin,
+    Path(lang): Path<String>,
+    Json(form): Json<AliasForm>,
+) -> impl IntoResponse {
+    aliases::add_alias(
+        &repo.db,
+        &form.phrase,
+        form.tags,
+        Language::from_code(&lang),
+        repo.config.search.phrase_max,
+    )
+    .await
+    .map(Json)
+}
+
+pub async fn remove_alias<Db: Backend>(
+    State(repo): State<Repositories<Db>>,
+    SiteAdmin(_admin): SiteAdmin,
+    Path((lang, phrase)): Path<(String, String)>,
+) -> impl IntoResponse {
+    aliases::remove_alias(&repo.db, &phrase, Language::from_code(&lang))
+        .await
+        .map(|_| StatusCode::NO_CONTENT)
+}
+
+// The body is a synonyms file, one "a, b => c" rule per line.
+pub async fn import_aliases<Db: Backend>(
+    State(repo): State<Repositories<Db>>,
+    SiteAdmin(_admin): SiteAdmin,
+    Path(lang): Path<String>,
+    synonyms: String,
+) -> impl IntoResponse {
+    aliases::import_synonyms(
+        &repo.db,
+        &synonyms,
+        Language::from_code(&lang),
+        repo.config.search.phrase_max,
+    )
+    .await
+    .map(Json)
+}
+
+pub async fn metrics<Db: Backend>(
+    State(repo): State<Repositories<Db>>,
+    SiteAdmin(_admin): SiteAdmin,
+) -> impl IntoResponse {
+    repo.db.render_metrics()
+}
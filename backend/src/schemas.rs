@@ -1,24 +1,49 @@
 use core::fmt::Display;
+use std::collections::{BTreeSet, HashMap};
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use slug::slugify;
 
+use crate::indexing::IndexedItem;
+use crate::query::{field_tag, pair_tags};
 use crate::rest::PublishForm;
+use crate::tokenizer::{analyze, strip_html, tokenize, Language};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Internal,
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    Conflict,
+}
 
 #[derive(Debug)]
 pub struct AppError {
+    pub kind: ErrorKind,
     pub reason: String,
 }
 
+impl AppError {
+    pub fn new(kind: ErrorKind, reason: impl Into<String>) -> Self {
+        Self {
+            kind,
+            reason: reason.into(),
+        }
+    }
+}
+
 impl Display for AppError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str(self.reason.as_str())
     }
 }
 
-type GroupId = uuid::Uuid;
-type AuthorId = String; // Typically, a handle/slug
-type UserId = uuid::Uuid;
+pub type GroupId = uuid::Uuid;
+pub type AuthorId = String; // Typically, a handle/slug
+pub type UserId = uuid::Uuid;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Post {
@@ -27,6 +52,9 @@ pub struct Post {
     pub author: AuthorInfo,
     pub body: String,
     pub can_reply: bool, // As a post reader, can i reply to this
+    pub replies: Vec<Post>,
+    pub created_at: String,
+    pub updated_at: Option<String>, // Only set once edited
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -37,10 +65,17 @@ pub enum Authorization {
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct AuthorInfo {
+    pub handle: AuthorId,
     pub name: String,
     pub profile_picture: String,
 }
 
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AuthorProfile {
+    pub author: AuthorInfo,
+    pub posts: Page<Post>,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Page<T> {
     pub objects: Vec<T>,
@@ -49,7 +84,7 @@ pub struct Page<T> {
     pub total_objects: usize,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub enum GroupManagement {
     Open,         // Anyone can join or leave freely
     MemberInvite, // Members can invite other members
@@ -60,6 +95,7 @@ pub enum GroupManagement {
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct GroupEntity {
     pub id: GroupId,
+    pub name: String,
     // Groups can have very varied ways to manage membership.
     pub management: GroupManagement,
     // If the members are allowed to post in.
@@ -67,6 +103,10 @@ pub struct GroupEntity {
     pub face: Option<AuthorId>, // Refers to an AuthorEntity that can post. This is controlled by admins.
     pub admins: Vec<UserId>,    // Always at least one person
     pub members: Vec<UserId>,   // Always at least admins
+    pub invited: Vec<UserId>,   // Pending invitations, joining is their consent
+    // Bumped by every save, so that concurrent changes don't overwrite each other.
+    #[serde(default)]
+    pub version: u64,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -79,12 +119,21 @@ pub struct AuthorEntity {
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct UserEntity {
     pub id: UserId,
+    // The handle chosen at sign-up, used to sign in and as the default author of my posts.
+    pub handle: AuthorId,
+    pub password_hash: String, // Argon2 PHC string
     // Groups that i have joined, created, or admin.
     // Note that because groups can have faces, i can create myself aliases this way.
     // I also can create a friend list, a friend group, a community...
     pub groups: Vec<GroupId>,
     // The users i have blocked. They are excluded from ALL my groups. Unlike groups, they need no consent for being joined.
     pub block_list: Vec<UserId>,
+    // The users who have blocked me, kept so that blocks apply both ways.
+    #[serde(default)]
+    pub blocked_by: Vec<UserId>,
+    // Bumped by every save, as for groups.
+    #[serde(default)]
+    pub version: u64,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -94,30 +143,142 @@ pub struct PostEntity {
     pub author: AuthorId,
     pub search_tags: Vec<String>,
     pub body: String,
-    pub space: Option<GroupId>,       // None means public.
-    pub reply_scope: Option<GroupId>, // None means space inherited
+    pub space: Option<GroupId>,            // None means public.
+    pub reply_scope: Option<GroupId>,      // None means space inherited
     pub visibility_scope: Option<GroupId>, // idem
-                                      // pub publish_datetime: Chrono....    // TODO
+    #[serde(default)]
+    pub parent: Option<String>, // Slug of the post replied to, None for thread roots.
+    // The user who actually published, even as a group face. For moderation, never rendered.
+    #[serde(default)]
+    pub publisher: Option<UserId>,
+    #[serde(default)]
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub updated_at: DateTime<Utc>, // Same as created_at until the post is edited
+    #[serde(default)]
+    pub lang: Language, // Language the post is analyzed in for search
+}
+// Search weights of the fields of a post, title and tags hits outrank body hits.
+pub const TITLE_WEIGHT: usize = 3;
+pub const BODY_WEIGHT: usize = 1;
+
+impl IndexedItem<String> for PostEntity {
+    fn item_ref(&self) -> String {
+        self.slug.clone()
+    }
+
+    fn timestamp(&self) -> i64 {
+        self.created_at.timestamp()
+    }
 }
+
 impl PostEntity {
     pub fn search_tags(&self) -> Vec<String> {
-        self.title
-            .split(" ")
-            .map(|s| s.to_lowercase())
-            .chain(self.search_tags.clone())
+        analyze(&self.title, self.lang)
+            .into_iter()
+            .chain(analyze(&self.search_tags.join(" "), self.lang))
             .collect()
     }
 
-    pub fn from_form(form: PublishForm) -> Self {
+    pub fn body_tags(&self) -> Vec<String> {
+        analyze(&strip_html(&self.body), self.lang)
+    }
+
+    // Distinct words offered as completions, unstemmed. Like titles, only
+    // those of public thread roots are suggested.
+    pub fn completion_words(&self) -> Vec<String> {
+        if self.parent.is_some() || self.visibility_group().is_some() {
+            return vec![];
+        }
+        let text = format!(
+            "{} {} {}",
+            self.title,
+            self.search_tags.join(" "),
+            strip_html(&self.body)
+        );
+        let words: BTreeSet<_> = tokenize(&text, self.lang).into_iter().collect();
+        words.into_iter().collect()
+    }
+
+    // Replies are stored without being indexed for search. A word found in
+    // both the title and the body is indexed once, with the title weight.
+    pub fn indexed_tags(&self) -> Vec<(String, usize)> {
+        if self.parent.is_some() {
+            return vec![];
+        }
+        let mut weights: HashMap<String, usize> = HashMap::new();
+        let weighted = self
+            .search_tags()
+            .into_iter()
+            .map(|tag| (tag, TITLE_WEIGHT))
+            .chain(self.body_tags().into_iter().map(|tag| (tag, BODY_WEIGHT)));
+        // Field tags only select, they add nothing to the score.
+        // Adjacent words of a field weigh what the field does.
+        let pairs = pair_tags(&analyze(&self.title, self.lang))
+            .into_iter()
+            .chain(
+                self.search_tags
+                    .iter()
+                    .flat_map(|tag| pair_tags(&analyze(tag, self.lang))),
+            )
+            .map(|tag| (tag, TITLE_WEIGHT))
+            .chain(
+                pair_tags(&self.body_tags())
+                    .into_iter()
+                    .map(|tag| (tag, BODY_WEIGHT)),
+            );
+        let fields = std::iter::once(field_tag("author", &self.author))
+            .chain(
+                self.visibility_group()
+                    .map(|group| field_tag("group", &group.to_string())),
+            )
+            .map(|tag| (tag, 0));
+        for (tag, weight) in weighted.chain(pairs).chain(fields) {
+            let best = weights.entry(tag).or_insert(weight);
+            *best = (*best).max(weight);
+        }
+        weights.into_iter().collect()
+    }
+
+    // Occurrences of each word, a title word counting as TITLE_WEIGHT body
+    // words, for relevance scoring. Replies have none, as they aren't indexed.
+    pub fn term_frequencies(&self) -> Vec<(String, usize)> {
+        if self.parent.is_some() {
+            return vec![];
+        }
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        let weighted = self
+            .search_tags()
+            .into_iter()
+            .map(|tag| (tag, TITLE_WEIGHT))
+            .chain(self.body_tags().into_iter().map(|tag| (tag, BODY_WEIGHT)));
+        for (tag, weight) in weighted {
+            *counts.entry(tag).or_insert(0) += weight;
+        }
+        counts.into_iter().collect()
+    }
+
+    pub fn from_form(
+        form: PublishForm,
+        author: AuthorId,
+        publisher: UserId,
+        lang: Language,
+    ) -> Self {
+        let now = Utc::now();
         Self {
             title: form.title.clone(),
             slug: slugify(form.title),
-            author: "Some author".to_string(),
+            author,
             search_tags: form.tags.split(" ").map(|s| s.to_string()).collect(),
             body: markdown::to_html(form.body.as_str()).to_owned(),
             space: None,
-            reply_scope: None,
-            visibility_scope: None,
+            reply_scope: form.reply_group,
+            visibility_scope: form.visibility_group,
+            parent: None,
+            publisher: Some(publisher),
+            created_at: now,
+            updated_at: now,
+            lang,
         }
     }
 }
@@ -0,0 +1,207 @@
+use core::hash::Hash;
+use futures::future::try_join_all;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::search::{Aggregate, ItemSet, SearchDb};
+
+// Items with term statistics, and the sum of their lengths.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CorpusStats {
+    pub docs: usize,
+    pub length: usize,
+}
+
+// Length of an item and the frequency in it of each term looked up.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DocStats {
+    pub length: usize,
+    pub frequencies: Vec<usize>,
+}
+
+pub trait Scorer<Tag, ItemRef, Item, DbError>
+where
+    Self: Sync + Send,
+{
+    // Scores the items under the groups of tags the free words of a query
+    // resolved to, each group coming with the factor of its phrase.
+    fn get_scores(
+        &self,
+        db: &impl SearchDb<Tag, ItemRef, Item, DbError>,
+        groups: Vec<(Vec<Tag>, usize)>,
+    ) -> impl std::future::Future<Output = Result<ItemSet<Tag, ItemRef>, DbError>> + std::marker::Send;
+}
+
+// Phrase factor times the weight of the field the tag was found in, so
+// every tag counts the same however common. Left to the store to evaluate.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WeightScorer;
+
+impl<Tag, ItemRef, Item, DbError> Scorer<Tag, ItemRef, Item, DbError> for WeightScorer
+where
+    Tag: Send,
+    ItemRef: Eq + Hash + Send,
+    DbError: Send,
+{
+    async fn get_scores(
+        &self,
+        _db: &impl SearchDb<Tag, ItemRef, Item, DbError>,
+        groups: Vec<(Vec<Tag>, usize)>,
+    ) -> Result<ItemSet<Tag, ItemRef>, DbError> {
+        let sets = groups
+            .into_iter()
+            .map(|(tags, factor)| (ItemSet::Tags(tags), factor))
+            .collect();
+        Ok(ItemSet::Union(sets, Aggregate::Sum))
+    }
+}
+
+// Okapi BM25 scores are fractional, they are scaled up before rounding.
+const BM25_SCALE: f64 = 10.0;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Bm25Scorer {
+    pub k1: f64, // How fast repeating a term stops adding to the score
+    pub b: f64,  // How much longer items are penalized, from 0 to 1
+}
+
+impl Default for Bm25Scorer {
+    fn default() -> Self {
+        Self { k1: 1.2, b: 0.75 }
+    }
+}
+
+impl Bm25Scorer {
+    pub fn score(
+        &self,
+        frequency: usize,
+        doc_frequency: usize,
+        length: usize,
+        corpus: CorpusStats,
+    ) -> f64 {
+        // Statistics may lag behind the index, they must not turn idf negative.
+        let docs = corpus.docs.max(doc_frequency) as f64;
+        let doc_frequency = doc_frequency as f64;
+        let idf = (1.0 + (docs - doc_frequency + 0.5) / (doc_frequency + 0.5)).ln();
+        let average = if corpus.docs == 0 {
+            1.0
+        } else {
+            (corpus.length as f64 / corpus.docs as f64).max(1.0)
+        };
+        let frequency = frequency as f64;
+        let norm = self.k1 * (1.0 - self.b + self.b * length as f64 / average);
+        idf * frequency * (self.k1 + 1.0) / (frequency + norm)
+    }
+}
+
+// Within a group of tags, as for weights, an item keeps its best score.
+impl<Tag, ItemRef, Item, DbError> Scorer<Tag, ItemRef, Item, DbError> for Bm25Scorer
+where
+    Tag: Clone + Send + Sync,
+    ItemRef: Eq + Hash + Clone + Send + Sync,
+    DbError: Send,
+{
+    async fn get_scores(
+        &self,
+        db: &impl SearchDb<Tag, ItemRef, Item, DbError>,
+        groups: Vec<(Vec<Tag>, usize)>,
+    ) -> Result<ItemSet<Tag, ItemRef>, DbError> {
+        let corpus = db.get_corpus_stats().await?;
+        let listings = try_join_all(groups.into_iter().map(|(tags, factor)| async move {
+            let (refs, doc_frequencies) = futures::try_join!(
+                db.get_item_refs_from_tags(tags.clone()),
+                db.get_doc_frequencies(tags.clone())
+            )?;
+            let refs: Vec<_> = refs.into_iter().map(|(item_ref, _)| item_ref).collect();
+            let stats = db.get_term_frequencies(refs.clone(), tags).await?;
+            Ok((refs, stats, doc_frequencies, factor))
+        }))
+        .await?;
+        let mut scores: HashMap<ItemRef, f64> = HashMap::new();
+        for (refs, stats, doc_frequencies, factor) in listings {
+            for (item_ref, doc) in refs.into_iter().zip(stats) {
+                let best = doc
+                    .frequencies
+                    .iter()
+                    .zip(doc_frequencies.iter())
+                    .filter(|(frequency, _)| **frequency > 0)
+                    .map(|(frequency, doc_frequency)| {
+                        self.score(*frequency, *doc_frequency, doc.length, corpus)
+                    })
+                    .fold(0.0, f64::max);
+                *scores.entry(item_ref).or_insert(0.0) += factor as f64 * best;
+            }
+        }
+        Ok(ItemSet::Scored(
+            scores
+                .into_iter()
+                .map(|(item_ref, score)| (item_ref, (score * BM25_SCALE).round() as usize))
+                .collect(),
+        ))
+    }
+}
+
+// The scorer picked by configuration.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scoring {
+    #[default]
+    Weights,
+    Bm25,
+}
+
+impl<Tag, ItemRef, Item, DbError> Scorer<Tag, ItemRef, Item, DbError> for Scoring
+where
+    Tag: Clone + Send + Sync,
+    ItemRef: Eq + Hash + Clone + Send + Sync,
+    DbError: Send,
+{
+    async fn get_scores(
+        &self,
+        db: &impl SearchDb<Tag, ItemRef, Item, DbError>,
+        groups: Vec<(Vec<Tag>, usize)>,
+    ) -> Result<ItemSet<Tag, ItemRef>, DbError> {
+        match self {
+            Scoring::Weights => WeightScorer.get_scores(db, groups).await,
+            Scoring::Bm25 => Bm25Scorer::default().get_scores(db, groups).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CORPUS: CorpusStats = CorpusStats {
+        docs: 100,
+        length: 1000,
+    };
+
+    #[test]
+    fn test_rare_terms_score_more() {
+        let scorer = Bm25Scorer::default();
+        assert!(scorer.score(1, 2, 10, CORPUS) > scorer.score(1, 90, 10, CORPUS));
+    }
+
+    #[test]
+    fn test_repeats_saturate() {
+        let scorer = Bm25Scorer::default();
+        let once = scorer.score(1, 10, 10, CORPUS);
+        let twice = scorer.score(2, 10, 10, CORPUS);
+        let thrice = scorer.score(3, 10, 10, CORPUS);
+        assert!(twice > once);
+        assert!(thrice - twice < twice - once);
+    }
+
+    #[test]
+    fn test_longer_items_score_less() {
+        let scorer = Bm25Scorer::default();
+        assert!(scorer.score(1, 10, 5, CORPUS) > scorer.score(1, 10, 50, CORPUS));
+    }
+
+    #[test]
+    fn test_stale_statistics() {
+        let scorer = Bm25Scorer::default();
+        assert!(scorer.score(1, 3, 10, CorpusStats::default()) > 0.0);
+    }
+}
@@ -1,32 +1,171 @@
 use core::hash::Hash;
-use futures::future::try_join_all;
-use std::cmp::min;
-use std::collections::{HashMap, HashSet};
-use std::hash::RandomState;
+use futures::future::{try_join_all, BoxFuture, FutureExt};
+use std::cmp::{min, Reverse};
+use std::collections::HashMap;
 
-pub trait SearchCache<ItemRef, DbError>
+use crate::query::{pair_tags, parse_query, Clause, Filter};
+use crate::scoring::{CorpusStats, DocStats, Scorer};
+use crate::tokenizer::{analyze, Language};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortOrder {
+    Recency, // Newest first, whatever the score
+    #[default]
+    RelevanceThenRecency, // Best score first, newest first among equals
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SearchOptions {
+    pub word_max: usize,
+    pub phrase_max: usize,
+    pub sort: SortOrder,
+    pub lang: Language,       // Picks the stopwords and stemmer of the query
+    pub min_score: usize,     // Results scoring less are left out
+    pub top_n: Option<usize>, // Only the best ranked results are kept
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        Self {
+            word_max: 20,
+            phrase_max: 1,
+            sort: SortOrder::default(),
+            lang: Language::default(),
+            min_score: 0,
+            top_n: None,
+        }
+    }
+}
+
+impl SearchOptions {
+    // Results are cached already ranked, so each ranking gets its own entry.
+    pub fn cache_key(&self, search_query: &str) -> String {
+        let mut key = parse_query(search_query).normalized(self.lang);
+        if self.lang != Language::default() {
+            key = format!("{}:{key}", self.lang.code());
+        }
+        if self.min_score > 0 || self.top_n.is_some() {
+            let top_n = self.top_n.map(|n| n.to_string()).unwrap_or_default();
+            key = format!("min{}:top{top_n}:{key}", self.min_score);
+        }
+        match self.sort {
+            SortOrder::Recency => format!("recent:{key}"),
+            SortOrder::RelevanceThenRecency => key,
+        }
+    }
+}
+
+// Typo hits score this many times less than exact hits.
+pub const TYPO_PENALTY: usize = 2;
+
+// How the scores of an item found in several sets combine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregate {
+    Sum,
+    Min,
+    Max,
+}
+
+impl Aggregate {
+    fn combine(&self, a: usize, b: usize) -> usize {
+        match self {
+            Aggregate::Sum => a + b,
+            Aggregate::Min => a.min(b),
+            Aggregate::Max => a.max(b),
+        }
+    }
+}
+
+// Scored items, what a query evaluates to. The tag listings are combined
+// with set operations, which stores that can run server side should.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ItemSet<Tag, ItemRef> {
+    // Items under any of the tags, with their best weight among them.
+    Tags(Vec<Tag>),
+    // Items already scored in the app, as by BM25.
+    Scored(Vec<(ItemRef, usize)>),
+    // Items in any of the sets, their score in each multiplied by its factor.
+    Union(Vec<(ItemSet<Tag, ItemRef>, usize)>, Aggregate),
+    // Items in every set, idem.
+    Inter(Vec<(ItemSet<Tag, ItemRef>, usize)>, Aggregate),
+    // Items of the first set not in the second, with their first score.
+    Diff(Box<ItemSet<Tag, ItemRef>>, Box<ItemSet<Tag, ItemRef>>),
+}
+
+impl<Tag, ItemRef> ItemSet<Tag, ItemRef> {
+    pub fn empty() -> Self {
+        ItemSet::Tags(vec![])
+    }
+
+    // The same items, each scoring `factor` times more.
+    pub fn times(self, factor: usize) -> Self {
+        ItemSet::Union(vec![(self, factor)], Aggregate::Sum)
+    }
+}
+
+// The tags a query part resolved to, and the items it matched with their score.
+pub type Scores<Tag, ItemRef> = (Vec<Tag>, ItemSet<Tag, ItemRef>);
+
+// Items found in every map, with their values combined.
+fn intersect_scores<K: Eq + Hash>(
+    mut maps: Vec<HashMap<K, usize>>,
+    combine: impl Fn(usize, usize) -> usize,
+) -> HashMap<K, usize> {
+    let Some(mut result) = maps.pop() else {
+        return HashMap::new();
+    };
+    for map in maps {
+        result = result
+            .into_iter()
+            .filter_map(|(k, v)| map.get(&k).map(|other| (k, combine(v, *other))))
+            .collect();
+    }
+    result
+}
+
+pub trait SearchCache<Tag, ItemRef, DbError>
 where
     Self: Sync + Send,
 {
+    // Bumped by every invalidation, read before searching.
+    fn get_generation(
+        &self,
+    ) -> impl std::future::Future<Output = Result<u64, DbError>> + std::marker::Send;
+    // `tags` are the ones the query was resolved to, so the entry can be
+    // invalidated. Nothing is written if the generation moved on since the
+    // search started, as its results may predate the invalidation.
     fn cache_search(
         &self,
         search_query: &str,
+        tags: Vec<Tag>,
         results: Vec<ItemRef>,
+        generation: u64,
     ) -> impl std::future::Future<Output = Result<(), DbError>> + std::marker::Send;
-    fn get_cached_search(
+    // Up to `count` results from the `start`th on, and how many there are in
+    // all. None when the search isn't cached, it may be cached with no results.
+    fn get_cached_page(
         &self,
         search_query: &str,
-    ) -> impl std::future::Future<Output = Result<Vec<ItemRef>, DbError>> + std::marker::Send;
+        start: usize,
+        count: usize,
+    ) -> impl std::future::Future<Output = Result<Option<(Vec<ItemRef>, usize)>, DbError>>
+           + std::marker::Send;
+    // Drops every cached search that resolved to any of these tags.
+    fn invalidate_tags(
+        &self,
+        tags: Vec<Tag>,
+    ) -> impl std::future::Future<Output = Result<(), DbError>> + std::marker::Send;
 }
 
 pub trait SearchDb<Tag, ItemRef, Item, DbError>
 where
     Self: Sync + Send,
 {
+    // Each item comes with the weight it was indexed under this tag with.
     fn get_item_refs_from_tag(
         &self,
         tag: Tag,
-    ) -> impl std::future::Future<Output = Result<Vec<ItemRef>, DbError>> + std::marker::Send;
+    ) -> impl std::future::Future<Output = Result<Vec<(ItemRef, usize)>, DbError>> + std::marker::Send;
     fn get_item_from_ref(
         &self,
         item_ref: ItemRef,
@@ -35,48 +174,253 @@ where
         &self,
         phrase: &str,
     ) -> impl std::future::Future<Output = Result<Vec<Tag>, DbError>> + std::marker::Send;
+    // Tags of items whose field has this value, "tag" being the tags themselves.
+    fn get_tags_from_field(
+        &self,
+        field: &str,
+        value: &str,
+    ) -> impl std::future::Future<Output = Result<Vec<Tag>, DbError>> + std::marker::Send;
+    // Indexed tags within a few typos of the word, the word itself excluded.
+    fn get_similar_tags(
+        &self,
+        word: &str,
+    ) -> impl std::future::Future<Output = Result<Vec<Tag>, DbError>> + std::marker::Send;
+    // A timestamp per item, higher is newer, in the same order as item_refs.
+    fn get_items_recency(
+        &self,
+        item_refs: Vec<ItemRef>,
+    ) -> impl std::future::Future<Output = Result<Vec<i64>, DbError>> + std::marker::Send;
+
+    fn get_corpus_stats(
+        &self,
+    ) -> impl std::future::Future<Output = Result<CorpusStats, DbError>> + std::marker::Send;
+    // Items with each tag among their terms, in the same order as tags.
+    fn get_doc_frequencies(
+        &self,
+        tags: Vec<Tag>,
+    ) -> impl std::future::Future<Output = Result<Vec<usize>, DbError>> + std::marker::Send;
+    // In the same order as item_refs, frequencies in the same order as tags.
+    fn get_term_frequencies(
+        &self,
+        item_refs: Vec<ItemRef>,
+        tags: Vec<Tag>,
+    ) -> impl std::future::Future<Output = Result<Vec<DocStats>, DbError>> + std::marker::Send;
+
+    // The set operations below are done here in Rust from the tag listings.
+    // Stores that can run them server side should, so that only the result
+    // is sent over.
+
+    // Whether any item is indexed under any of the tags.
+    fn has_item_refs(
+        &self,
+        tags: Vec<Tag>,
+    ) -> impl std::future::Future<Output = Result<bool, DbError>> + std::marker::Send
+    where
+        Tag: Send,
+        DbError: Send,
+        ItemRef: Send,
+    {
+        async move {
+            Ok(
+                try_join_all(tags.into_iter().map(|t| self.get_item_refs_from_tag(t)))
+                    .await?
+                    .iter()
+                    .any(|refs| !refs.is_empty()),
+            )
+        }
+    }
+
+    // Items under any of the tags, with their best weight among them.
+    fn get_item_refs_from_tags(
+        &self,
+        tags: Vec<Tag>,
+    ) -> impl std::future::Future<Output = Result<Vec<(ItemRef, usize)>, DbError>> + std::marker::Send
+    where
+        Tag: Send,
+        DbError: Send,
+        ItemRef: Eq + Hash + Send,
+    {
+        async move {
+            let mut weights: HashMap<ItemRef, usize> = HashMap::new();
+            try_join_all(tags.into_iter().map(|t| self.get_item_refs_from_tag(t)))
+                .await?
+                .into_iter()
+                .flatten()
+                .for_each(|(item_ref, weight)| {
+                    let best = weights.entry(item_ref).or_insert(weight);
+                    *best = (*best).max(weight);
+                });
+            Ok(weights.into_iter().collect())
+        }
+    }
+
+    // The items of the set with their score, in no particular order.
+    fn get_scored_items(
+        &self,
+        set: ItemSet<Tag, ItemRef>,
+    ) -> impl std::future::Future<Output = Result<Vec<(ItemRef, usize)>, DbError>> + std::marker::Send
+    where
+        Tag: Send,
+        DbError: Send,
+        ItemRef: Eq + Hash + Send,
+    {
+        async move { Ok(evaluate(self, set).await?.into_iter().collect()) }
+    }
+
+    // The items of the set ranked as the options ask, without their score.
+    fn get_ranked_items(
+        &self,
+        set: ItemSet<Tag, ItemRef>,
+        options: SearchOptions,
+    ) -> impl std::future::Future<Output = Result<Vec<ItemRef>, DbError>> + std::marker::Send
+    where
+        Tag: Send,
+        DbError: Send,
+        ItemRef: Ord + Eq + Hash + Clone + Send,
+    {
+        async move {
+            let ratings: Vec<_> = self
+                .get_scored_items(set)
+                .await?
+                .into_iter()
+                .filter(|(_item_ref, score)| *score >= options.min_score)
+                .collect();
+            let recency = self
+                .get_items_recency(
+                    ratings
+                        .iter()
+                        .map(|(item_ref, _)| item_ref.clone())
+                        .collect(),
+                )
+                .await?;
+            let mut ranked: Vec<_> = ratings
+                .into_iter()
+                .zip(recency)
+                .map(|((item_ref, rating), date)| {
+                    let rating = match options.sort {
+                        SortOrder::Recency => 0,
+                        SortOrder::RelevanceThenRecency => rating,
+                    };
+                    (Reverse(rating), Reverse(date), item_ref)
+                })
+                .collect();
+            ranked.sort();
+            Ok(ranked
+                .into_iter()
+                .take(options.top_n.unwrap_or(usize::MAX))
+                .map(|(_, _, item_ref)| item_ref)
+                .collect())
+        }
+    }
+}
+
+fn weighted<ItemRef>(scores: HashMap<ItemRef, usize>, factor: usize) -> HashMap<ItemRef, usize>
+where
+    ItemRef: Eq + Hash,
+{
+    scores
+        .into_iter()
+        .map(|(item_ref, score)| (item_ref, factor * score))
+        .collect()
+}
+
+// Evaluates the set here in Rust from the tag listings.
+fn evaluate<'a, Db, Tag, ItemRef, Item, DbError>(
+    db: &'a Db,
+    set: ItemSet<Tag, ItemRef>,
+) -> BoxFuture<'a, Result<HashMap<ItemRef, usize>, DbError>>
+where
+    Db: SearchDb<Tag, ItemRef, Item, DbError> + ?Sized,
+    Tag: Send + 'a,
+    ItemRef: Eq + Hash + Send + 'a,
+    DbError: Send + 'a,
+{
+    async move {
+        match set {
+            ItemSet::Tags(tags) => Ok(db
+                .get_item_refs_from_tags(tags)
+                .await?
+                .into_iter()
+                .collect()),
+            ItemSet::Scored(scores) => Ok(scores.into_iter().collect()),
+            ItemSet::Union(sets, aggregate) => {
+                let parts = try_join_all(sets.into_iter().map(|(set, factor)| async move {
+                    Ok::<_, DbError>(weighted(evaluate(db, set).await?, factor))
+                }))
+                .await?;
+                let mut result: HashMap<ItemRef, usize> = HashMap::new();
+                for (item_ref, score) in parts.into_iter().flatten() {
+                    result
+                        .entry(item_ref)
+                        .and_modify(|best| *best = aggregate.combine(*best, score))
+                        .or_insert(score);
+                }
+                Ok(result)
+            }
+            ItemSet::Inter(sets, aggregate) => {
+                let parts = try_join_all(sets.into_iter().map(|(set, factor)| async move {
+                    Ok::<_, DbError>(weighted(evaluate(db, set).await?, factor))
+                }))
+                .await?;
+                Ok(intersect_scores(parts, |a, b| aggregate.combine(a, b)))
+            }
+            ItemSet::Diff(set, excluded) => {
+                let (mut scores, excluded) =
+                    futures::try_join!(evaluate(db, *set), evaluate(db, *excluded))?;
+                scores.retain(|item_ref, _| !excluded.contains_key(item_ref));
+                Ok(scores)
+            }
+        }
+    }
+    .boxed()
 }
 
 pub trait ItemRepo<Tag, ItemRef, Item, DbError>
 where
     ItemRef: Ord + Eq + Hash + Clone + Sync + Send + std::fmt::Debug,
     Item: Send,
-    Tag: Clone + std::fmt::Debug,
+    Tag: Clone + Send + Sync + std::fmt::Debug,
     DbError: Send,
     Self: Sync,
 {
-    fn get_cache(&self) -> impl SearchCache<ItemRef, DbError>;
+    fn get_cache(&self) -> impl SearchCache<Tag, ItemRef, DbError>;
     fn get_db(&self) -> impl SearchDb<Tag, ItemRef, Item, DbError>;
+    // Ranks the items matching the free words of a query.
+    fn get_scorer(&self) -> impl Scorer<Tag, ItemRef, Item, DbError>;
 
-    fn get_item_refs_for_phrase(
+    // The tags the words of a query part are looked up under, and the score
+    // factor of their items. What matched nothing is looked up again,
+    // allowing for typos, single words only as a phrase with a typo is
+    // better matched word by word.
+    fn get_tags_for_words(
         &self,
-        phrase: &str,
-    ) -> impl std::future::Future<Output = Result<Vec<ItemRef>, DbError>> + Send {
-        async {
-            let tags = self.get_db().get_tags_from_phrase(phrase).await?;
+        phrase: String,
+        score: usize,
+    ) -> impl std::future::Future<Output = Result<(Vec<Tag>, usize), DbError>> + Send {
+        async move {
             let db = self.get_db();
-            let hashset: HashSet<_, RandomState> = HashSet::from_iter(
-                try_join_all(tags.into_iter().map(|t| db.get_item_refs_from_tag(t)))
-                    .await?
-                    .into_iter()
-                    .flatten(),
-            );
-
-            Ok(hashset.into_iter().collect())
+            let mut tags = db.get_tags_from_phrase(&phrase).await?;
+            if db.has_item_refs(tags.clone()).await? {
+                return Ok((tags, score));
+            }
+            if !phrase.contains(' ') {
+                tags.extend(db.get_similar_tags(&phrase).await?);
+            }
+            Ok((tags, score / TYPO_PENALTY))
         }
     }
-    fn get_item_refs_search_query(
+
+    // Scores the items matching the free words of a query, by how many of
+    // them and of their phrases they match.
+    fn get_scores_for_words(
         &self,
-        search_query: &str,
-        word_max: usize,
-        phrase_max: usize,
-    ) -> impl std::future::Future<Output = Result<Vec<ItemRef>, DbError>> + Send {
+        text: &str,
+        options: SearchOptions,
+    ) -> impl std::future::Future<Output = Result<Scores<Tag, ItemRef>, DbError>> + Send {
         async move {
-            let mut counter: HashMap<ItemRef, usize> = HashMap::new();
-            let to_process_words = search_query
-                .split(" ")
-                .filter(|w| w.len() > 0)
-                .take(word_max)
+            let to_process_words = analyze(text, options.lang)
+                .into_iter()
+                .take(options.word_max)
                 .collect::<Vec<_>>();
             let to_process_phrases = to_process_words
                 .clone()
@@ -84,14 +428,14 @@ where
                 .enumerate()
                 .map(|(i, _word)| {
                     let mut v = vec![];
-                    for k in 1..min(phrase_max + 1, to_process_words.len() + 1 - i) {
+                    for k in 1..min(options.phrase_max + 1, to_process_words.len() + 1 - i) {
                         let phrase: Vec<_> = to_process_words
                             .clone()
                             .into_iter()
                             .skip(i)
                             .take(k)
                             .collect();
-                        if phrase.len() > 0 {
+                        if !phrase.is_empty() {
                             v.push((phrase.join(" "), (10 + k) * k))
                         }
                     }
@@ -99,32 +443,185 @@ where
                 })
                 .collect::<Vec<_>>();
 
-            let listings = try_join_all(to_process_phrases.into_iter().flatten().map(
-                |(phrase, score)| async move {
-                    Ok((self.get_item_refs_for_phrase(&phrase).await?, score))
-                },
-            ))
+            let groups = try_join_all(
+                to_process_phrases
+                    .into_iter()
+                    .flatten()
+                    .map(|(phrase, score)| self.get_tags_for_words(phrase, score)),
+            )
             .await?;
-            listings.into_iter().for_each(|(ref_list, index)| {
-                ref_list.into_iter().for_each(|item_ref| {
-                    if counter.contains_key(&item_ref) {
-                        counter.insert(item_ref.clone(), counter.get(&item_ref).unwrap() + index);
-                    } else {
-                        counter.insert(item_ref.clone(), index);
-                    }
-                })
-            });
+            let tags = groups
+                .iter()
+                .flat_map(|(tags, _)| tags.iter().cloned())
+                .collect();
+            let scores = self.get_scorer().get_scores(&self.get_db(), groups).await?;
+            Ok((tags, scores))
+        }
+    }
 
-            let max_pertinence = counter.values().max().unwrap_or(&1).clone();
-            let ratings = counter
+    // Items matching the filter, with the score it adds to them. Only exact
+    // phrases score, field filters just select.
+    fn get_scores_for_filter(
+        &self,
+        filter: &Filter,
+        options: SearchOptions,
+    ) -> impl std::future::Future<Output = Result<Scores<Tag, ItemRef>, DbError>> + Send {
+        async move {
+            let db = self.get_db();
+            let (field, value) = match filter {
+                Filter::Phrase(text) => return self.get_scores_for_phrase(text, options).await,
+                Filter::Tag(value) => ("tag", analyze(value, options.lang).join(" ")),
+                Filter::Author(value) => ("author", value.clone()),
+                Filter::Group(value) => ("group", value.clone()),
+            };
+            // A tag filter of several words needs them all.
+            let words: Vec<_> = match field {
+                "tag" => value.split(' ').filter(|w| !w.is_empty()).collect(),
+                _ => vec![value.as_str()],
+            };
+            let groups = try_join_all(
+                words
+                    .into_iter()
+                    .map(|word| db.get_tags_from_field(field, word)),
+            )
+            .await?;
+            let tags = groups.iter().flatten().cloned().collect();
+            let selected = groups
                 .into_iter()
-                .filter(|(_slug, weight)| weight.clone() == max_pertinence)
-                .collect::<Vec<_>>();
+                .map(|tags| (ItemSet::Tags(tags), 0))
+                .collect();
+            Ok((tags, ItemSet::Inter(selected, Aggregate::Sum)))
+        }
+    }
+
+    // Items with the words of the phrase next to each other, in order, or
+    // found under the phrase itself when it has an alias. Adjacent words are
+    // indexed in pairs, a longer phrase needs all of its pairs.
+    fn get_scores_for_phrase(
+        &self,
+        text: &str,
+        options: SearchOptions,
+    ) -> impl std::future::Future<Output = Result<Scores<Tag, ItemRef>, DbError>> + Send {
+        async move {
+            let db = self.get_db();
+            let words = analyze(text, options.lang);
+            let k = words.len();
+            let whole = match k {
+                0 => return Ok((vec![], ItemSet::empty())),
+                _ => db.get_tags_from_phrase(&words.join(" ")).await?,
+            };
+            let pairs = try_join_all(
+                pair_tags(&words)
+                    .iter()
+                    .map(|pair| db.get_tags_from_field("tag", pair)),
+            )
+            .await?;
+            let tags = pairs
+                .iter()
+                .flatten()
+                .chain(whole.iter())
+                .cloned()
+                .collect();
+            // The phrase weighs what its lightest pair does.
+            let mut found = vec![(ItemSet::Tags(whole), 1)];
+            if !pairs.is_empty() {
+                let adjacent = pairs
+                    .into_iter()
+                    .map(|pair| (ItemSet::Tags(pair), 1))
+                    .collect();
+                found.push((ItemSet::Inter(adjacent, Aggregate::Min), 1));
+            }
+            let score = (10 + k) * k;
+            Ok((tags, ItemSet::Union(found, Aggregate::Max).times(score)))
+        }
+    }
+
+    fn get_scores_for_clause(
+        &self,
+        clause: &Clause,
+        options: SearchOptions,
+    ) -> impl std::future::Future<Output = Result<Scores<Tag, ItemRef>, DbError>> + Send {
+        async move {
+            let (mut tags, word_scores) = self
+                .get_scores_for_words(&clause.words.join(" "), options)
+                .await?;
+            let required = try_join_all(
+                clause
+                    .required
+                    .iter()
+                    .map(|filter| self.get_scores_for_filter(filter, options)),
+            )
+            .await?;
+            let excluded = try_join_all(
+                clause
+                    .excluded
+                    .iter()
+                    .map(|filter| self.get_scores_for_filter(filter, options)),
+            )
+            .await?;
+            let mut scores = if required.is_empty() {
+                word_scores
+            } else {
+                let mut matches = vec![];
+                for (filter_tags, filter_scores) in required {
+                    tags.extend(filter_tags);
+                    matches.push((filter_scores, 1));
+                }
+                // Free words only rank what the filters selected.
+                let selected = ItemSet::Inter(matches, Aggregate::Sum);
+                let ranked = ItemSet::Union(
+                    vec![(selected.clone(), 1), (word_scores, 1)],
+                    Aggregate::Sum,
+                );
+                ItemSet::Inter(vec![(ranked, 1), (selected, 0)], Aggregate::Sum)
+            };
+            if !excluded.is_empty() {
+                let mut matches = vec![];
+                for (filter_tags, filter_scores) in excluded {
+                    tags.extend(filter_tags);
+                    matches.push((filter_scores, 1));
+                }
+                let excluded = ItemSet::Union(matches, Aggregate::Max);
+                scores = ItemSet::Diff(Box::new(scores), Box::new(excluded));
+            }
+            Ok((tags, scores))
+        }
+    }
 
-            let mut results: Vec<_> = ratings.into_iter().map(|(slug, _rating)| slug).collect();
-            results.sort();
+    fn get_item_refs_search_query(
+        &self,
+        search_query: &str,
+        options: SearchOptions,
+    ) -> impl std::future::Future<Output = Result<Vec<ItemRef>, DbError>> + Send {
+        async move {
+            let generation = self.get_cache().get_generation().await?;
+            let query = parse_query(search_query);
+            let clause_scores = try_join_all(
+                query
+                    .clauses
+                    .iter()
+                    .map(|clause| self.get_scores_for_clause(clause, options)),
+            )
+            .await?;
+            // Clauses are alternatives, an item keeps its best score.
+            let mut tags = vec![];
+            let mut clauses = vec![];
+            for (clause_tags, scores) in clause_scores {
+                tags.extend(clause_tags);
+                clauses.push((scores, 1));
+            }
+            // Partial matches are kept, ranked below the items matching more of the query.
+            let results = self
+                .get_db()
+                .get_ranked_items(ItemSet::Union(clauses, Aggregate::Max), options)
+                .await?;
             self.get_cache()
-                .cache_search(search_query, results.clone())
+                .cache_search(
+                    &options.cache_key(search_query),
+                    tags,
+                    results.clone(),
+                    generation,
+                )
                 .await?;
             Ok(results)
         }
@@ -138,149 +635,147 @@ where
         result_max: usize,
         page_num: usize,
     ) -> impl std::future::Future<Output = Result<(Vec<Item>, usize), DbError>> + Send {
-        async move {
-            let search = self.get_cache().get_cached_search(search_query).await?;
-            let results = if search.is_empty() {
-                self.get_item_refs_search_query(search_query, word_max, phrase_max)
-                    .await?
-            } else {
-                search
-            };
-            let total_result = results.len();
+        let options = SearchOptions {
+            word_max,
+            phrase_max,
+            ..Default::default()
+        };
+        self.get_visible_items_for_search(
+            search_query,
+            options,
+            result_max,
+            page_num,
+            |_item: &Item| true,
+        )
+    }
 
-            let db = self.get_db();
-            let futures = results
-                .into_iter()
-                .skip((page_num - 1) * result_max)
-                .take(result_max)
-                .map(|item_ref| db.get_item_from_ref(item_ref))
-                .collect::<Vec<_>>();
-            Ok((try_join_all(futures).await?, total_result))
+    // Items rejected by `visible` are left out of both the page and the total,
+    // so every ranked item is fetched and checked, a page worth at a time.
+    fn get_visible_items_for_search(
+        &self,
+        search_query: &str,
+        options: SearchOptions,
+        result_max: usize,
+        page_num: usize,
+        visible: impl Fn(&Item) -> bool + Send + Sync,
+    ) -> impl std::future::Future<Output = Result<(Vec<Item>, usize), DbError>> + Send {
+        async move {
+            let (cache, db) = (self.get_cache(), self.get_db());
+            let key = options.cache_key(search_query);
+            let chunk = result_max.max(1);
+            // Results are read a chunk at a time from the cache, or from the
+            // search itself when it wasn't cached or no longer is.
+            let mut searched: Option<Vec<ItemRef>> = None;
+            let skipped = page_num.saturating_sub(1) * result_max;
+            let mut page = Vec::with_capacity(result_max);
+            let (mut seen, mut fetched) = (0, 0);
+            loop {
+                let batch = match &searched {
+                    Some(results) => {
+                        let end = results.len().min(fetched + chunk);
+                        results[fetched.min(end)..end].to_vec()
+                    }
+                    None => match cache.get_cached_page(&key, fetched, chunk).await? {
+                        Some((batch, _)) => batch,
+                        None => {
+                            let results = self
+                                .get_item_refs_search_query(search_query, options)
+                                .await?;
+                            searched = Some(results);
+                            continue;
+                        }
+                    },
+                };
+                if batch.is_empty() {
+                    break;
+                }
+                let items = try_join_all(
+                    batch
+                        .iter()
+                        .map(|item_ref| db.get_item_from_ref(item_ref.clone())),
+                )
+                .await?;
+                fetched += batch.len();
+                for item in items.into_iter().filter(|item| visible(item)) {
+                    if seen >= skipped && page.len() < result_max {
+                        page.push(item);
+                    }
+                    seen += 1;
+                }
+            }
+            Ok((page, seen))
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use serde::{Deserialize, Serialize};
-
     use super::*;
-    use std::collections::HashMap;
+    use crate::indexing::{insert_and_index_item, IndexedItem, InsertHandle};
+    use crate::memory::InMemoryRepository;
+    use crate::scoring::Scoring;
 
-    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+    #[derive(Clone, Debug, PartialEq)]
     struct TestItem {
+        pub id: u64,
         pub name: String,
-        pub description: String,
+        pub date: i64,
     }
 
-    #[derive(Clone)]
-    struct TestCache {
-        pub correct_input: Option<String>,
-        pub retval: Vec<u64>,
-    }
+    impl IndexedItem<u64> for TestItem {
+        fn item_ref(&self) -> u64 {
+            self.id
+        }
 
-    #[derive(Clone)]
-    struct TestDB {
-        pub aliases: HashMap<String, Vec<u8>>,
-        pub tags: HashMap<u8, Vec<u64>>,
-        pub items: HashMap<u64, TestItem>,
+        fn timestamp(&self) -> i64 {
+            self.date
+        }
     }
 
-    #[derive(Debug)]
-    struct TestError {}
+    type TestRepo = InMemoryRepository<String, u64, TestItem>;
 
-    struct TestRepo {
-        pub db: TestDB,
-        pub cache: TestCache,
+    fn test_repo(scoring: Scoring) -> TestRepo {
+        let mut searcher = TestRepo::default();
+        searcher.scoring = scoring;
+        searcher
     }
-    impl SearchCache<u64, TestError> for TestCache {
-        fn cache_search(
-            &self,
-            search_query: &str,
-            _results: Vec<u64>,
-        ) -> impl std::future::Future<Output = Result<(), TestError>> + std::marker::Send {
-            async {
-                match &self.correct_input {
-                    None => {}
-                    Some(string) => {
-                        assert!(search_query.to_string() == string.clone())
-                    }
-                }
-                Ok(())
-            }
-        }
 
-        fn get_cached_search(
-            &self,
-            search_query: &str,
-        ) -> impl std::future::Future<Output = Result<Vec<u64>, TestError>> + std::marker::Send
-        {
-            async {
-                match &self.correct_input {
-                    None => {}
-                    Some(string) => {
-                        assert!(search_query.to_string() == string.clone())
-                    }
-                }
-                Ok(self.retval.clone())
-            }
+    fn item(id: u64, name: &str, date: i64) -> TestItem {
+        TestItem {
+            id,
+            name: name.to_string(),
+            date,
         }
     }
 
-    impl SearchDb<u8, u64, TestItem, TestError> for TestDB {
-        fn get_item_from_ref(
-            &self,
-            item_ref: u64,
-        ) -> impl std::future::Future<Output = Result<TestItem, TestError>> + std::marker::Send
-        {
-            println!("{}", item_ref);
-            async move { Ok(self.items.get(&item_ref).cloned().unwrap()) }
-        }
-        fn get_item_refs_from_tag(
-            &self,
-            tag: u8,
-        ) -> impl std::future::Future<Output = Result<Vec<u64>, TestError>> + std::marker::Send
-        {
-            println!("{}", tag);
-            async move { Ok(self.tags.get(&tag).cloned().unwrap_or(vec![])) }
-        }
-        fn get_tags_from_phrase(
-            &self,
-            phrase: &str,
-        ) -> impl std::future::Future<Output = Result<Vec<u8>, TestError>> + std::marker::Send
-        {
-            println!("{}", phrase);
-            async move { Ok(self.aliases.get(phrase).cloned().unwrap_or(vec![])) }
-        }
+    // Tag weights double as term frequencies.
+    async fn index(searcher: &TestRepo, item: TestItem, tags: &[(&str, usize)]) {
+        let tags: Vec<_> = tags
+            .iter()
+            .map(|(tag, weight)| (tag.to_string(), *weight))
+            .collect();
+        insert_and_index_item(searcher, item.id, item, tags.clone(), tags)
+            .await
+            .unwrap();
     }
 
-    impl ItemRepo<u8, u64, TestItem, TestError> for TestRepo {
-        fn get_cache(&self) -> impl SearchCache<u64, TestError> {
-            self.cache.clone()
-        }
-        fn get_db(&self) -> impl SearchDb<u8, u64, TestItem, TestError> {
-            self.db.clone()
-        }
-    }
     #[tokio::test]
     async fn test_search_cached_single() {
         let query = "butter";
-        let item = TestItem {
-            name: "Butter".to_string(),
-            description: "Some butter".to_string(),
+        let searcher = test_repo(Scoring::Weights);
+        let item = item(1001, "Butter", 0);
+        // Only the cache knows of the item.
+        searcher.insert_item(item.clone()).await.unwrap();
+        let options = SearchOptions {
+            word_max: 1,
+            phrase_max: 1,
+            ..Default::default()
         };
-        let mut searcher = TestRepo {
-            db: TestDB {
-                aliases: HashMap::new(),
-                tags: HashMap::new(),
-                items: HashMap::new(),
-            },
-            cache: TestCache {
-                correct_input: Some(query.to_owned()),
-                retval: vec![1001],
-            },
-        };
-        searcher.db.items.insert(1001, item.clone());
+        let generation = searcher.get_generation().await.unwrap();
+        searcher
+            .cache_search(&options.cache_key(query), vec![], vec![1001], generation)
+            .await
+            .unwrap();
 
         let research = searcher
             .get_items_for_search(query, 1, 1, 1, 1)
@@ -293,24 +788,9 @@ mod tests {
     #[tokio::test]
     async fn test_search_single_cache_miss() {
         let query = "butter";
-        let item = TestItem {
-            name: "Butter".to_string(),
-            description: "Some butter".to_string(),
-        };
-        let mut searcher = TestRepo {
-            db: TestDB {
-                aliases: HashMap::new(),
-                tags: HashMap::new(),
-                items: HashMap::new(),
-            },
-            cache: TestCache {
-                correct_input: Some(query.to_owned()),
-                retval: vec![],
-            },
-        };
-        searcher.db.aliases.insert("butter".to_owned(), vec![1]);
-        searcher.db.tags.insert(1, vec![1001]);
-        searcher.db.items.insert(1001, item.clone());
+        let searcher = test_repo(Scoring::Weights);
+        let item = item(1001, "Butter", 0);
+        index(&searcher, item.clone(), &[("butter", 1)]).await;
 
         let research = searcher
             .get_items_for_search(query, 1, 1, 1, 1)
@@ -318,35 +798,69 @@ mod tests {
             .unwrap();
 
         assert_eq!(research, (vec![item], 1));
+        // The search was cached on the way.
+        assert_eq!(
+            searcher
+                .get_cached_page(&SearchOptions::default().cache_key(query), 0, 10)
+                .await
+                .unwrap(),
+            Some((vec![1001], 1))
+        );
     }
 
     #[tokio::test]
-    async fn test_two_words_exact_match() {
-        let query = "butter flour";
-        let item = TestItem {
-            name: "Butter flour".to_string(),
-            description: "Some butter flour".to_string(),
+    async fn test_pages_skip_hidden_items() {
+        let searcher = ranking_repo(Scoring::Weights).await;
+        let page = |page_num| {
+            searcher.get_visible_items_for_search(
+                "salt",
+                SearchOptions::default(),
+                1,
+                page_num,
+                |item: &TestItem| item.id != 1008,
+            )
         };
-        let mut searcher = TestRepo {
-            db: TestDB {
-                aliases: HashMap::new(),
-                tags: HashMap::new(),
-                items: HashMap::new(),
-            },
-            cache: TestCache {
-                correct_input: Some(query.to_owned()),
-                retval: vec![],
-            },
+        let ids = |(items, total): (Vec<TestItem>, usize)| {
+            (
+                items.into_iter().map(|item| item.id).collect::<Vec<_>>(),
+                total,
+            )
         };
-        searcher.db.aliases.insert("butter".to_owned(), vec![1]); // Bad, should not use
-        searcher.db.aliases.insert("flour".to_owned(), vec![2]); // Bad, should not use
+        assert_eq!(ids(page(1).await.unwrap()), (vec![1009], 2));
+        // Read a chunk at a time from the cache.
+        assert_eq!(ids(page(2).await.unwrap()), (vec![1007], 2));
+        assert_eq!(ids(page(3).await.unwrap()), (vec![], 2));
+    }
+
+    #[tokio::test]
+    async fn test_searches_racing_an_invalidation_are_not_cached() {
+        let query = "butter";
+        let searcher = test_repo(Scoring::Weights);
+        let key = SearchOptions::default().cache_key(query);
+        let generation = searcher.get_generation().await.unwrap();
         searcher
-            .db
-            .aliases
-            .insert("butter flour".to_owned(), vec![3]);
+            .invalidate_tags(vec!["butter".to_string()])
+            .await
+            .unwrap();
+        searcher
+            .cache_search(&key, vec!["butter".to_string()], vec![1001], generation)
+            .await
+            .unwrap();
+        assert_eq!(searcher.get_cached_page(&key, 0, 10).await.unwrap(), None);
 
-        searcher.db.tags.insert(3, vec![1001]);
-        searcher.db.items.insert(1001, item.clone());
+        // Searches matching nothing are cached too.
+        assert!(search_refs(query, searcher.clone()).await.is_empty());
+        let cached = searcher.get_cached_page(&key, 0, 10).await.unwrap();
+        assert_eq!(cached, Some((vec![], 0)));
+    }
+
+    #[tokio::test]
+    async fn test_two_words_exact_match() {
+        let query = "butter flour";
+        let searcher = test_repo(Scoring::Weights);
+        let item = item(1001, "Butter flour", 0);
+        // Only indexed under the phrase, not under its words.
+        index(&searcher, item.clone(), &[("butter flour", 1)]).await;
 
         let research = searcher
             .get_items_for_search(query, 2, 2, 1, 1)
@@ -359,39 +873,262 @@ mod tests {
     #[tokio::test]
     async fn test_two_words_partial_match_join() {
         let query = "butter flour";
-        let first_item = TestItem {
-            name: "Butter".to_string(),
-            description: "Some butter".to_string(),
+        let searcher = test_repo(Scoring::Weights);
+        let first_item = item(1001, "Butter", 0);
+        let second_item = item(1002, "Flour", 0);
+        index(&searcher, first_item.clone(), &[("butter", 1)]).await;
+        index(&searcher, second_item.clone(), &[("flour", 1)]).await;
+
+        let research = searcher
+            .get_items_for_search(query, 2, 2, 2, 1)
+            .await
+            .unwrap();
+
+        assert_eq!(research, (vec![first_item, second_item], 2));
+    }
+
+    #[tokio::test]
+    async fn test_same_score_newest_first() {
+        let query = "butter";
+        let searcher = test_repo(Scoring::Weights);
+        let old_item = item(1001, "Old butter", 100);
+        let new_item = item(1002, "Fresh butter", 200);
+        index(&searcher, old_item.clone(), &[("butter", 1)]).await;
+        index(&searcher, new_item.clone(), &[("butter", 1)]).await;
+
+        let research = searcher
+            .get_items_for_search(query, 1, 1, 2, 1)
+            .await
+            .unwrap();
+
+        assert_eq!(research, (vec![new_item, old_item], 2));
+    }
+
+    // Every ranking case runs over the same items. For "butter flour", 1001
+    // matches the whole query, 1002 and 1003 only one word of it. 1004 has
+    // sugar in its title, the newer 1005 only in its body. Salt is in most of
+    // 1006 to 1009, pepper only in the oldest of them.
+    async fn ranking_repo(scoring: Scoring) -> TestRepo {
+        let searcher = test_repo(scoring);
+        index(
+            &searcher,
+            item(1001, "Butter flour", 0),
+            &[("butter", 1), ("flour", 1), ("butter flour", 1)],
+        )
+        .await;
+        index(&searcher, item(1002, "Butter", 100), &[("butter", 1)]).await;
+        index(&searcher, item(1003, "Flour", 200), &[("flour", 1)]).await;
+        index(&searcher, item(1004, "Sugar", 300), &[("sugar", 3)]).await;
+        index(&searcher, item(1005, "Syrup", 400), &[("sugar", 1)]).await;
+        index(&searcher, item(1006, "Pepper", 500), &[("pepper", 1)]).await;
+        index(&searcher, item(1007, "Salt", 600), &[("salt", 1)]).await;
+        index(&searcher, item(1008, "Salt", 700), &[("salt", 1)]).await;
+        index(&searcher, item(1009, "Salt", 800), &[("salt", 1)]).await;
+        searcher
+    }
+
+    #[tokio::test]
+    async fn test_partial_matches_ranked_below_full_match() {
+        let query = "butter flour";
+        let searcher = ranking_repo(Scoring::Weights).await;
+        let options = SearchOptions {
+            word_max: 2,
+            phrase_max: 2,
+            ..Default::default()
         };
-        let second_item = TestItem {
-            name: "Flour".to_string(),
-            description: "Some flour".to_string(),
+
+        let ranked = searcher
+            .get_item_refs_search_query(query, options)
+            .await
+            .unwrap();
+
+        assert_eq!(ranked, vec![1001, 1003, 1002]);
+    }
+
+    #[tokio::test]
+    async fn test_min_score_drops_weak_matches() {
+        let query = "butter flour";
+        let searcher = ranking_repo(Scoring::Weights).await;
+        let options = SearchOptions {
+            word_max: 2,
+            phrase_max: 2,
+            min_score: 12,
+            ..Default::default()
         };
-        let mut searcher = TestRepo {
-            db: TestDB {
-                aliases: HashMap::new(),
-                tags: HashMap::new(),
-                items: HashMap::new(),
-            },
-            cache: TestCache {
-                correct_input: Some(query.to_owned()),
-                retval: vec![],
-            },
+
+        let ranked = searcher
+            .get_item_refs_search_query(query, options)
+            .await
+            .unwrap();
+
+        assert_eq!(ranked, vec![1001]);
+    }
+
+    #[tokio::test]
+    async fn test_top_n_keeps_best_results() {
+        let query = "butter flour";
+        let searcher = ranking_repo(Scoring::Weights).await;
+        let options = SearchOptions {
+            word_max: 2,
+            phrase_max: 2,
+            top_n: Some(2),
+            ..Default::default()
         };
-        searcher.db.aliases.insert("butter".to_owned(), vec![1]);
-        searcher.db.aliases.insert("flour".to_owned(), vec![2]);
 
-        searcher.db.tags.insert(1, vec![1001]);
-        searcher.db.tags.insert(2, vec![1002]);
+        let ranked = searcher
+            .get_item_refs_search_query(query, options)
+            .await
+            .unwrap();
 
-        searcher.db.items.insert(1001, first_item.clone());
-        searcher.db.items.insert(1002, second_item.clone());
+        assert_eq!(ranked, vec![1001, 1003]);
+    }
 
-        let research = searcher
-            .get_items_for_search(query, 2, 2, 2, 1)
+    #[tokio::test]
+    async fn test_heavier_field_ranks_first() {
+        let query = "sugar";
+        let searcher = ranking_repo(Scoring::Weights).await;
+
+        let ranked = searcher
+            .get_item_refs_search_query(query, SearchOptions::default())
             .await
             .unwrap();
 
-        assert_eq!(research, (vec![first_item, second_item], 2));
+        assert_eq!(ranked, vec![1004, 1005]);
+    }
+
+    #[tokio::test]
+    async fn test_recency_sort_ignores_scores() {
+        let query = "butter flour";
+        let searcher = ranking_repo(Scoring::Weights).await;
+        let options = SearchOptions {
+            word_max: 2,
+            phrase_max: 2,
+            sort: SortOrder::Recency,
+            ..Default::default()
+        };
+
+        let ranked = searcher
+            .get_item_refs_search_query(query, options)
+            .await
+            .unwrap();
+
+        // The full match is the oldest, and comes last.
+        assert_eq!(ranked, vec![1003, 1002, 1001]);
+    }
+
+    #[tokio::test]
+    async fn test_typo_ranked_below_exact_match() {
+        let query = "buter flour";
+        let searcher = ranking_repo(Scoring::Weights).await;
+        let options = SearchOptions {
+            word_max: 2,
+            phrase_max: 2,
+            ..Default::default()
+        };
+
+        let ranked = searcher
+            .get_item_refs_search_query(query, options)
+            .await
+            .unwrap();
+
+        // 1002 only matches the misspelt butter, and comes last.
+        assert_eq!(ranked, vec![1001, 1003, 1002]);
+    }
+
+    async fn search_refs(query: &str, searcher: TestRepo) -> Vec<u64> {
+        let options = SearchOptions {
+            word_max: 2,
+            phrase_max: 2,
+            ..Default::default()
+        };
+        searcher
+            .get_item_refs_search_query(query, options)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_query_field_filter_ranked_by_words() {
+        let query = "butter author:bob";
+        let searcher = ranking_repo(Scoring::Weights).await;
+        for item_ref in [1002, 1003] {
+            searcher
+                .insert_tags(vec![("author:bob".to_string(), 0)], item_ref)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(search_refs(query, searcher).await, vec![1002, 1003]);
+    }
+
+    #[tokio::test]
+    async fn test_query_exclusion() {
+        let query = "flour -butter";
+        let searcher = ranking_repo(Scoring::Weights).await;
+        assert_eq!(search_refs(query, searcher).await, vec![1003]);
+    }
+
+    #[tokio::test]
+    async fn test_query_exact_phrase() {
+        let searcher = ranking_repo(Scoring::Weights).await;
+        index(
+            &searcher,
+            item(1010, "Butter, then flour", 900),
+            &[("butter", 1), ("flour", 1)],
+        )
+        .await;
+        // The words must be next to each other, in order.
+        let adjacent = search_refs("\"butter flour\"", searcher.clone()).await;
+        assert_eq!(adjacent, vec![1001]);
+        assert!(search_refs("\"flour butter\"", searcher).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_query_or() {
+        let query = "butter OR flour";
+        let searcher = ranking_repo(Scoring::Weights).await;
+        // Equal scores, so newest first.
+        assert_eq!(search_refs(query, searcher).await, vec![1003, 1002, 1001]);
+    }
+
+    #[tokio::test]
+    async fn test_set_operations_fallback() {
+        let db = ranking_repo(Scoring::Weights).await;
+        let tags = |tags: &[&str]| ItemSet::Tags(tags.iter().map(|tag| tag.to_string()).collect());
+        let both = ItemSet::Inter(
+            vec![
+                (tags(&["butter"]), 1),
+                (tags(&["flour", "butter flour"]), 1),
+            ],
+            Aggregate::Min,
+        );
+        let mut in_all = db.get_scored_items(both).await.unwrap();
+        in_all.sort();
+        assert_eq!(in_all, vec![(1001, 1)]);
+
+        let weighted = ItemSet::Union(
+            vec![(tags(&["butter"]), 11), (tags(&["butter flour"]), 24)],
+            Aggregate::Sum,
+        );
+        let mut scores = db.get_scored_items(weighted.clone()).await.unwrap();
+        scores.sort();
+        assert_eq!(scores, vec![(1001, 35), (1002, 11)]);
+
+        let without = ItemSet::Diff(Box::new(weighted), Box::new(tags(&["flour"])));
+        let scores = db.get_scored_items(without).await.unwrap();
+        assert_eq!(scores, vec![(1002, 11)]);
+    }
+
+    #[tokio::test]
+    async fn test_bm25_ranks_rare_words_first() {
+        let query = "salt pepper";
+        let searcher = ranking_repo(Scoring::Bm25).await;
+
+        let ranked = searcher
+            .get_item_refs_search_query(query, SearchOptions::default())
+            .await
+            .unwrap();
+
+        assert_eq!(ranked, vec![1006, 1009, 1008, 1007]);
     }
 }
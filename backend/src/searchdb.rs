@@ -1,9 +1,27 @@
-use redis::AsyncCommands;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use futures::future::{BoxFuture, FutureExt};
+use redis::{AsyncCommands, ExistenceCheck, SetExpiry, SetOptions};
 use spow::pow::Pow;
+use uuid::Uuid;
 
+use crate::aliases::{Alias, AliasStore};
+use crate::authors::AuthorStore;
+use crate::backend::Backend;
+use crate::config::RedisConfig;
+use crate::groups::GroupStore;
 use crate::pow::PowValidator;
-use crate::schemas::PostEntity;
-use crate::search::{ItemRepo, SearchDb};
+use crate::query::tags_from_field;
+use crate::schemas::{
+    AuthorEntity, AuthorId, ErrorKind, GroupEntity, GroupId, PostEntity, UserEntity, UserId,
+};
+use crate::scoring::{CorpusStats, DocStats, Scorer, Scoring};
+use crate::search::{Aggregate, ItemRepo, ItemSet, SearchDb, SearchOptions, SortOrder};
+use crate::suggest::{SuggestStore, TitleSuggestion};
+use crate::threads::ThreadStore;
+use crate::tokenizer::{bigrams, is_typo_of};
+use crate::users::UserStore;
 use crate::{schemas::AppError, search::SearchCache};
 
 #[derive(Debug, Clone)]
@@ -13,107 +31,464 @@ pub struct RepositoryDb {
 
 impl From<redis::RedisError> for AppError {
     fn from(value: redis::RedisError) -> Self {
-        return Self {
-            reason: value.to_string(),
-        };
+        Self::new(ErrorKind::Internal, value.to_string())
+    }
+}
+
+impl From<serde_json::Error> for AppError {
+    fn from(value: serde_json::Error) -> Self {
+        Self::new(ErrorKind::Internal, value.to_string())
     }
 }
 
 impl RepositoryDb {
-    pub async fn new(redka_host: &str) -> Self {
-        Self {
-            client: redis::Client::open(format!("redis://{redka_host}").as_str())
-                .unwrap()
-                .get_multiplexed_tokio_connection()
-                .await
-                .unwrap(),
-        }
+    pub async fn connect(config: &RedisConfig) -> Result<Self, AppError> {
+        Ok(Self {
+            client: connect(config, "redka").await?,
+        })
+    }
+}
+
+// Neither the url nor the error is printed, they can hold the password.
+async fn connect(
+    config: &RedisConfig,
+    name: &str,
+) -> Result<redis::aio::MultiplexedConnection, AppError> {
+    let cant_connect = |err: redis::RedisError| {
+        AppError::new(
+            ErrorKind::Internal,
+            format!("can't connect to {name}: {:?}", err.kind()),
+        )
+    };
+    let info = config.connection_info().map_err(|err| {
+        AppError::new(ErrorKind::BadRequest, format!("{name}.url: {}", err.reason))
+    })?;
+    redis::Client::open(info)
+        .map_err(cant_connect)?
+        .get_multiplexed_tokio_connection()
+        .await
+        .map_err(cant_connect)
+}
+
+// Cached searches expire even without changes, in case one was missed.
+pub const SEARCH_CACHE_TTL: i64 = 600;
+
+// Seconds a save holds the version of a group or user it replaces, longer
+// than any save takes and short enough to recover from a crashed one.
+const SAVE_CLAIM_TTL: u64 = 60;
+
+// Search cache lookups since startup.
+#[derive(Debug, Default)]
+pub struct CacheMetrics {
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+}
+
+impl CacheMetrics {
+    // Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        format!(
+            "# TYPE ribbit_search_cache_hits_total counter\n\
+             ribbit_search_cache_hits_total {}\n\
+             # TYPE ribbit_search_cache_misses_total counter\n\
+             ribbit_search_cache_misses_total {}\n",
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed)
+        )
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct RepositoryCache {
     pub cache: redis::aio::MultiplexedConnection,
+    pub metrics: Arc<CacheMetrics>,
+    pub ttl: i64, // Seconds cached searches live
 }
 
 #[derive(Debug, Clone)]
 pub struct Repository {
     pub redis: RepositoryCache,
     pub redka: RepositoryDb,
+    pub scoring: Scoring,
 }
 
 impl Repository {
-    pub async fn new(redis_host: &str, redka_host: &str) -> Self {
-        Self {
-            redis: RepositoryCache::new(redis_host).await,
-            redka: RepositoryDb::new(redka_host).await,
-        }
+    pub async fn connect(redis: &RedisConfig, redka: &RedisConfig) -> Result<Self, AppError> {
+        Ok(Self {
+            redis: RepositoryCache::connect(redis).await?,
+            redka: RepositoryDb::connect(redka).await?,
+            scoring: Scoring::default(),
+        })
     }
 }
 
 impl RepositoryCache {
-    pub async fn new(redis_host: &str) -> Self {
-        Self {
-            cache: redis::Client::open(format!("redis://{redis_host}").as_str())
-                .unwrap()
-                .get_multiplexed_tokio_connection()
-                .await
-                .unwrap(),
-        }
+    pub async fn connect(config: &RedisConfig) -> Result<Self, AppError> {
+        Ok(Self {
+            cache: connect(config, "redis").await?,
+            metrics: Arc::new(CacheMetrics::default()),
+            ttl: SEARCH_CACHE_TTL,
+        })
     }
 }
 
-impl SearchCache<String, AppError> for RepositoryCache {
-    async fn get_cached_search(&self, _search_tags: &str) -> Result<Vec<String>, AppError> {
-        // Enable cache
-        /*
+// Bumped by every invalidation, see SearchCache::cache_search.
+const SEARCH_GENERATION: &str = "search_generation";
+
+// Caches a search unless the generation moved on, at once. The cache is a
+// real redis, unlike the store, so it runs scripts. KEYS are the generation,
+// the result count, the result list and the tag reverse indexes, ARGV the
+// generation read, the ttl, the search and its results.
+const CACHE_SEARCH_SCRIPT: &str = r"
+if (redis.call('GET', KEYS[1]) or '0') ~= ARGV[1] then
+    return 0
+end
+redis.call('DEL', KEYS[3])
+for i = 4, #ARGV, 1000 do
+    redis.call('RPUSH', KEYS[3], unpack(ARGV, i, math.min(i + 999, #ARGV)))
+end
+redis.call('EXPIRE', KEYS[3], ARGV[2])
+redis.call('SET', KEYS[2], #ARGV - 3, 'EX', ARGV[2])
+for i = 4, #KEYS do
+    redis.call('SADD', KEYS[i], ARGV[3])
+    redis.call('EXPIRE', KEYS[i], ARGV[2])
+end
+return 1
+";
 
+impl SearchCache<String, String, AppError> for RepositoryCache {
+    async fn get_generation(&self) -> Result<u64, AppError> {
         Ok(self
             .cache
             .clone()
-            .smembers::<_, Option<Vec<String>>>(format!("search.{search_tags}"))
+            .get::<_, Option<u64>>(SEARCH_GENERATION)
             .await?
-            .unwrap_or(vec![]))
-        */
-        //Disabled cache
-        Ok(vec![])
+            .unwrap_or(0))
     }
 
-    async fn cache_search(&self, search_tags: &str, results: Vec<String>) -> Result<(), AppError> {
-        if results.is_empty() {
-            return Ok(());
+    // The count tells a search with no results from one not cached, as redis
+    // keeps no empty lists. Only the slice asked for is sent over.
+    async fn get_cached_page(
+        &self,
+        search_tags: &str,
+        start: usize,
+        count: usize,
+    ) -> Result<Option<(Vec<String>, usize)>, AppError> {
+        let stop = start.saturating_add(count).min(isize::MAX as usize) as isize - 1;
+        let (total, results): (Option<usize>, Vec<String>) = redis::pipe()
+            .get(format!("search_count.{search_tags}"))
+            .lrange(format!("search.{search_tags}"), start as isize, stop)
+            .query_async(&mut self.cache.clone())
+            .await?;
+        // LRANGE reads a stop of -1 as the end of the list.
+        let results = if count == 0 { vec![] } else { results };
+        let page = total.map(|total| (results, total));
+        // A search counts once, when its first page is read.
+        if start == 0 {
+            let counter = if page.is_none() {
+                &self.metrics.misses
+            } else {
+                &self.metrics.hits
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
         }
-        self.cache
-            .clone()
-            .sadd(format!("search.{search_tags}"), results.clone())
+        Ok(page)
+    }
+
+    // Results are kept in a list, as they are cached already ranked.
+    async fn cache_search(
+        &self,
+        search_tags: &str,
+        tags: Vec<String>,
+        results: Vec<String>,
+        generation: u64,
+    ) -> Result<(), AppError> {
+        let script = redis::Script::new(CACHE_SEARCH_SCRIPT);
+        let mut invocation = script.prepare_invoke();
+        invocation
+            .key(SEARCH_GENERATION)
+            .key(format!("search_count.{search_tags}"))
+            .key(format!("search.{search_tags}"))
+            .arg(generation)
+            .arg(self.ttl)
+            .arg(search_tags)
+            .arg(results);
+        // Reverse index, so that changes to a tag find the searches to drop.
+        for tag in tags {
+            invocation.key(format!("search_tags.{tag}"));
+        }
+        invocation
+            .invoke_async::<()>(&mut self.cache.clone())
             .await?;
+        Ok(())
+    }
+
+    // The generation is bumped first, so that searches run on the index
+    // before the change aren't cached once their entries are dropped.
+    async fn invalidate_tags(&self, tags: Vec<String>) -> Result<(), AppError> {
         self.cache
             .clone()
-            .expire::<_, String>(format!("search.{search_tags}"), 600)
+            .incr::<_, _, ()>(SEARCH_GENERATION, 1)
             .await?;
+        for tag in tags {
+            let searches = self
+                .cache
+                .clone()
+                .smembers::<_, Vec<String>>(format!("search_tags.{tag}"))
+                .await?;
+            let keys: Vec<_> = searches
+                .into_iter()
+                .flat_map(|search| [format!("search.{search}"), format!("search_count.{search}")])
+                .chain(std::iter::once(format!("search_tags.{tag}")))
+                .collect();
+            self.cache.clone().del::<_, ()>(keys).await?;
+        }
         Ok(())
     }
 }
 
+// Holds the result of a set operation until it is read, in the same pipeline.
+fn scratch_key() -> String {
+    format!("scratch.{}", Uuid::new_v4())
+}
+
+// Scratch keys are dropped once read, they expire in case a search fails before.
+const SCRATCH_TTL: i64 = 60;
+
+// Ranks are scores times this plus the date, higher than any date until 2106,
+// so that items rank by score, then newest first.
+const SCORE_FACTOR: f64 = 4_294_967_296.0;
+
+fn tag_keys(tags: &[String]) -> Vec<String> {
+    tags.iter().map(|tag| format!("tag.{tag}")).collect()
+}
+
+fn weights(members: Vec<(String, f64)>) -> Vec<(String, usize)> {
+    members
+        .into_iter()
+        .map(|(slug, weight)| (slug, weight as usize))
+        .collect()
+}
+
+// Set operations queued to run in the store, and the scratch keys they write.
+struct SetPlan {
+    pipe: redis::Pipeline,
+    scratch: Vec<String>,
+}
+
+impl SetPlan {
+    fn new() -> Self {
+        Self {
+            pipe: redis::pipe(),
+            scratch: vec![],
+        }
+    }
+
+    fn scratch_key(&mut self) -> String {
+        let key = scratch_key();
+        self.scratch.push(key.clone());
+        key
+    }
+
+    // To be called once the command writing the scratch key is queued.
+    fn stored(&mut self, key: &str) {
+        self.pipe.expire(key, SCRATCH_TTL).ignore();
+    }
+}
+
+impl RepositoryDb {
+    // Runs the plan, its last command reading the result, and drops every
+    // scratch key.
+    async fn run_plan<T: redis::FromRedisValue>(&self, plan: SetPlan) -> Result<T, AppError> {
+        let SetPlan { mut pipe, scratch } = plan;
+        if !scratch.is_empty() {
+            pipe.del(scratch).ignore();
+        }
+        let (result,): (T,) = pipe.query_async(&mut self.client.clone()).await?;
+        Ok(result)
+    }
+
+    // Queues the operations storing the set, returning the key it ends up in.
+    // Redka has no ZDIFFSTORE, and scores may be 0, so a difference reads
+    // the members to remove first.
+    fn store_set<'a>(
+        &'a self,
+        set: ItemSet<String, String>,
+        plan: &'a mut SetPlan,
+    ) -> BoxFuture<'a, Result<String, AppError>> {
+        async move {
+            let key = match set {
+                ItemSet::Tags(tags) => match tags.as_slice() {
+                    [tag] => format!("tag.{tag}"),
+                    _ => {
+                        let dst = plan.scratch_key();
+                        if !tags.is_empty() {
+                            plan.pipe.zunionstore_max(&dst, tag_keys(&tags)).ignore();
+                            plan.stored(&dst);
+                        }
+                        dst
+                    }
+                },
+                ItemSet::Scored(scores) => {
+                    let dst = plan.scratch_key();
+                    if !scores.is_empty() {
+                        let members: Vec<_> = scores
+                            .into_iter()
+                            .map(|(slug, score)| (score, slug))
+                            .collect();
+                        plan.pipe.zadd_multiple(&dst, &members).ignore();
+                        plan.stored(&dst);
+                    }
+                    dst
+                }
+                // Nothing is stored for an empty operation, its key stays empty.
+                ItemSet::Union(sets, _) | ItemSet::Inter(sets, _) if sets.is_empty() => {
+                    plan.scratch_key()
+                }
+                ItemSet::Union(sets, aggregate) => {
+                    let mut keys = vec![];
+                    for (set, factor) in sets {
+                        keys.push((self.store_set(set, plan).await?, factor));
+                    }
+                    let dst = plan.scratch_key();
+                    match aggregate {
+                        Aggregate::Sum => plan.pipe.zunionstore_weights(&dst, &keys),
+                        Aggregate::Min => plan.pipe.zunionstore_min_weights(&dst, &keys),
+                        Aggregate::Max => plan.pipe.zunionstore_max_weights(&dst, &keys),
+                    }
+                    .ignore();
+                    plan.stored(&dst);
+                    dst
+                }
+                ItemSet::Inter(sets, aggregate) => {
+                    let mut keys = vec![];
+                    for (set, factor) in sets {
+                        keys.push((self.store_set(set, plan).await?, factor));
+                    }
+                    let dst = plan.scratch_key();
+                    match aggregate {
+                        Aggregate::Sum => plan.pipe.zinterstore_weights(&dst, &keys),
+                        Aggregate::Min => plan.pipe.zinterstore_min_weights(&dst, &keys),
+                        Aggregate::Max => plan.pipe.zinterstore_max_weights(&dst, &keys),
+                    }
+                    .ignore();
+                    plan.stored(&dst);
+                    dst
+                }
+                ItemSet::Diff(set, excluded) => {
+                    let kept = self.store_set(*set, plan).await?;
+                    let excluded = self.store_set(*excluded, plan).await?;
+                    let both = plan.scratch_key();
+                    plan.pipe
+                        .zinterstore_weights(&both, &[(&kept, 1), (&excluded, 0)])
+                        .ignore();
+                    plan.stored(&both);
+                    plan.pipe.zrange(&both, 0, -1);
+                    let pipe = std::mem::replace(&mut plan.pipe, redis::pipe());
+                    let (members,): (Vec<String>,) =
+                        pipe.query_async(&mut self.client.clone()).await?;
+                    let dst = plan.scratch_key();
+                    plan.pipe.zunionstore(&dst, &[&kept]).ignore();
+                    plan.stored(&dst);
+                    if !members.is_empty() {
+                        plan.pipe.zrem(&dst, members).ignore();
+                    }
+                    dst
+                }
+            };
+            Ok(key)
+        }
+        .boxed()
+    }
+}
+
+// Set operations run on the tag indexes in the store, only items matching
+// are sent over, already scored.
 impl SearchDb<String, String, PostEntity, AppError> for RepositoryDb {
-    async fn get_item_refs_from_tag(&self, tag: String) -> Result<Vec<String>, AppError> {
+    async fn get_item_refs_from_tag(&self, tag: String) -> Result<Vec<(String, usize)>, AppError> {
         let key = format!("tag.{tag}");
         let members = self
             .client
             .clone()
-            .smembers::<&str, Vec<String>>(key.as_str())
+            .zrange_withscores::<&str, Vec<(String, f64)>>(key.as_str(), 0, -1)
             .await?;
-        Ok(members)
+        Ok(weights(members))
+    }
+
+    async fn has_item_refs(&self, tags: Vec<String>) -> Result<bool, AppError> {
+        let mut pipe = redis::pipe();
+        for key in tag_keys(&tags) {
+            pipe.zcard(key);
+        }
+        let counts: Vec<usize> = pipe.query_async(&mut self.client.clone()).await?;
+        Ok(counts.into_iter().any(|count| count > 0))
+    }
+
+    async fn get_item_refs_from_tags(
+        &self,
+        tags: Vec<String>,
+    ) -> Result<Vec<(String, usize)>, AppError> {
+        self.get_scored_items(ItemSet::Tags(tags)).await
+    }
+
+    async fn get_scored_items(
+        &self,
+        set: ItemSet<String, String>,
+    ) -> Result<Vec<(String, usize)>, AppError> {
+        let mut plan = SetPlan::new();
+        let dst = self.store_set(set, &mut plan).await?;
+        plan.pipe.zrange_withscores(&dst, 0, -1);
+        let members = self.run_plan::<Vec<(String, f64)>>(plan).await?;
+        Ok(weights(members))
+    }
+
+    // Ranked in the store, only the item refs are sent over. Items without a
+    // date are left out, every stored post has one.
+    async fn get_ranked_items(
+        &self,
+        set: ItemSet<String, String>,
+        options: SearchOptions,
+    ) -> Result<Vec<String>, AppError> {
+        let mut plan = SetPlan::new();
+        let scored = self.store_set(set, &mut plan).await?;
+        let mut ranked = plan.scratch_key();
+        let by_date = "posts_by_date".to_string();
+        plan.pipe
+            .zinterstore_weights(&ranked, &[(scored, SCORE_FACTOR), (by_date.clone(), 1.0)])
+            .ignore();
+        plan.stored(&ranked);
+        if options.min_score > 0 {
+            let min = format!("({}", options.min_score as f64 * SCORE_FACTOR);
+            plan.pipe.zrembyscore(&ranked, "-inf", min).ignore();
+        }
+        if options.sort == SortOrder::Recency {
+            let dated = plan.scratch_key();
+            plan.pipe
+                .zinterstore_weights(&dated, &[(ranked, 0.0), (by_date, 1.0)])
+                .ignore();
+            plan.stored(&dated);
+            ranked = dated;
+        }
+        if let Some(top_n) = options.top_n {
+            plan.pipe
+                .zremrangebyrank(&ranked, 0, -(top_n as isize) - 1)
+                .ignore();
+        }
+        plan.pipe.zrevrange(&ranked, 0, -1);
+        self.run_plan::<Vec<String>>(plan).await
     }
 
     async fn get_item_from_ref(&self, slug: String) -> Result<PostEntity, AppError> {
-        let key = format!("post.{slug}");
-        let json_str: String = self
+        let json_str = self
             .client
             .clone()
-            .get::<&str, String>(key.as_str())
-            .await?;
-        Ok(serde_json::from_str(json_str.as_str()).unwrap())
+            .get::<_, Option<String>>(format!("post.{slug}"))
+            .await?
+            .ok_or(AppError::new(
+                ErrorKind::NotFound,
+                format!("no post {slug}"),
+            ))?;
+        Ok(serde_json::from_str(json_str.as_str())?)
     }
 
     async fn get_tags_from_phrase(&self, w: &str) -> Result<Vec<String>, AppError> {
@@ -127,20 +502,361 @@ impl SearchDb<String, String, PostEntity, AppError> for RepositoryDb {
         }
         Ok(tags)
     }
+
+    async fn get_tags_from_field(&self, field: &str, value: &str) -> Result<Vec<String>, AppError> {
+        Ok(tags_from_field(field, value))
+    }
+
+    async fn get_similar_tags(&self, word: &str) -> Result<Vec<String>, AppError> {
+        let keys: Vec<_> = bigrams(word)
+            .into_iter()
+            .map(|gram| format!("tag_grams.{gram}"))
+            .collect();
+        let candidates = self.client.clone().sunion::<_, Vec<String>>(keys).await?;
+        Ok(candidates
+            .into_iter()
+            .filter(|tag| is_typo_of(tag, word))
+            .collect())
+    }
+
+    async fn get_items_recency(&self, slugs: Vec<String>) -> Result<Vec<i64>, AppError> {
+        let mut pipe = redis::pipe();
+        for slug in slugs.iter() {
+            pipe.zscore("posts_by_date", slug);
+        }
+        let dates: Vec<Option<i64>> = pipe.query_async(&mut self.client.clone()).await?;
+        Ok(dates.into_iter().map(|date| date.unwrap_or(0)).collect())
+    }
+
+    async fn get_corpus_stats(&self) -> Result<CorpusStats, AppError> {
+        let (docs, length): (usize, Option<usize>) = redis::pipe()
+            .zcard("doc_lengths")
+            .get("corpus_length")
+            .query_async(&mut self.client.clone())
+            .await?;
+        Ok(CorpusStats {
+            docs,
+            length: length.unwrap_or(0),
+        })
+    }
+
+    async fn get_doc_frequencies(&self, tags: Vec<String>) -> Result<Vec<usize>, AppError> {
+        if tags.is_empty() {
+            return Ok(vec![]);
+        }
+        // HMGET, as HGET would not answer a list for a single tag.
+        let counts: Vec<Option<usize>> = redis::cmd("HMGET")
+            .arg("doc_freq")
+            .arg(&tags)
+            .query_async(&mut self.client.clone())
+            .await?;
+        Ok(counts.into_iter().map(|count| count.unwrap_or(0)).collect())
+    }
+
+    async fn get_term_frequencies(
+        &self,
+        slugs: Vec<String>,
+        tags: Vec<String>,
+    ) -> Result<Vec<DocStats>, AppError> {
+        if slugs.is_empty() || tags.is_empty() {
+            return Ok(slugs.iter().map(|_| DocStats::default()).collect());
+        }
+        let mut lengths = redis::pipe();
+        let mut frequencies = redis::pipe();
+        for slug in slugs.iter() {
+            lengths.zscore("doc_lengths", slug);
+            frequencies
+                .cmd("HMGET")
+                .arg(format!("tf.{slug}"))
+                .arg(&tags);
+        }
+        let (mut client, mut other_client) = (self.client.clone(), self.client.clone());
+        let (lengths, frequencies): (Vec<Option<f64>>, Vec<Vec<Option<usize>>>) = futures::try_join!(
+            lengths.query_async(&mut client),
+            frequencies.query_async(&mut other_client)
+        )?;
+        Ok(lengths
+            .into_iter()
+            .zip(frequencies)
+            .map(|(length, frequencies)| DocStats {
+                length: length.unwrap_or(0.0) as usize,
+                frequencies: frequencies.into_iter().map(|f| f.unwrap_or(0)).collect(),
+            })
+            .collect())
+    }
+}
+
+impl UserStore for RepositoryDb {
+    async fn get_user(&self, user_id: UserId) -> Result<UserEntity, AppError> {
+        let json_str = self
+            .client
+            .clone()
+            .get::<_, Option<String>>(format!("user.{user_id}"))
+            .await?
+            .ok_or(AppError::new(
+                ErrorKind::NotFound,
+                format!("no user {user_id}"),
+            ))?;
+        Ok(serde_json::from_str(json_str.as_str())?)
+    }
+
+    async fn get_user_id_from_handle(&self, handle: &str) -> Result<Option<UserId>, AppError> {
+        let user_id = self
+            .client
+            .clone()
+            .get::<_, Option<String>>(format!("login.{handle}"))
+            .await?;
+        Ok(user_id.and_then(|id| id.parse().ok()))
+    }
+
+    async fn insert_user(&self, user: UserEntity) -> Result<bool, AppError> {
+        // The handle is claimed first so that two sign-ups can't race for it.
+        if !self
+            .client
+            .clone()
+            .set_nx::<_, _, bool>(format!("login.{}", user.handle), user.id.to_string())
+            .await?
+        {
+            return Ok(false);
+        }
+        self.client
+            .clone()
+            .set::<_, _, ()>(
+                format!("user.{}", user.id),
+                serde_json::to_string(&user).unwrap(),
+            )
+            .await?;
+        Ok(true)
+    }
+
+    // Claims the version it replaces first, as save_group does.
+    async fn save_user(&self, user: UserEntity) -> Result<bool, AppError> {
+        let mut redka = self.client.clone();
+        let claim = redka
+            .set_options::<_, _, Option<String>>(
+                format!("user_save.{}.{}", user.id, user.version),
+                1,
+                SetOptions::default()
+                    .conditional_set(ExistenceCheck::NX)
+                    .with_expiration(SetExpiry::EX(SAVE_CLAIM_TTL)),
+            )
+            .await?;
+        if claim.is_none() || self.get_user(user.id).await?.version != user.version {
+            return Ok(false);
+        }
+        redka
+            .set::<_, _, ()>(
+                format!("user.{}", user.id),
+                serde_json::to_string(&UserEntity {
+                    version: user.version + 1,
+                    ..user
+                })
+                .unwrap(),
+            )
+            .await?;
+        Ok(true)
+    }
+
+    async fn insert_session(&self, token: &str, user_id: UserId, ttl: u64) -> Result<(), AppError> {
+        self.client
+            .clone()
+            .set_ex::<_, _, ()>(format!("session.{token}"), user_id.to_string(), ttl)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_session(&self, token: &str) -> Result<Option<UserId>, AppError> {
+        let user_id = self
+            .client
+            .clone()
+            .get::<_, Option<String>>(format!("session.{token}"))
+            .await?;
+        Ok(user_id.and_then(|id| id.parse().ok()))
+    }
+}
+
+impl AuthorStore for RepositoryDb {
+    async fn get_author(&self, author_id: &str) -> Result<Option<AuthorEntity>, AppError> {
+        let json_str = self
+            .client
+            .clone()
+            .get::<_, Option<String>>(format!("author.{author_id}"))
+            .await?;
+        Ok(json_str
+            .map(|json_str| serde_json::from_str(json_str.as_str()))
+            .transpose()?)
+    }
+
+    async fn get_authors(
+        &self,
+        author_ids: Vec<AuthorId>,
+    ) -> Result<Vec<Option<AuthorEntity>>, AppError> {
+        let keys: Vec<_> = author_ids
+            .iter()
+            .map(|author_id| format!("author.{author_id}"))
+            .collect();
+        let json_strs = self
+            .client
+            .clone()
+            .mget::<_, Vec<Option<String>>>(keys)
+            .await?;
+        Ok(json_strs
+            .into_iter()
+            .map(|json_str| {
+                json_str
+                    .map(|json_str| serde_json::from_str(json_str.as_str()))
+                    .transpose()
+            })
+            .collect::<Result<_, _>>()?)
+    }
+
+    async fn insert_author(&self, author: AuthorEntity) -> Result<bool, AppError> {
+        Ok(self
+            .client
+            .clone()
+            .set_nx::<_, _, bool>(
+                format!("author.{}", author.author_id),
+                serde_json::to_string(&author).unwrap(),
+            )
+            .await?)
+    }
+
+    async fn remove_author(&self, author_id: &str) -> Result<(), AppError> {
+        self.client
+            .clone()
+            .del::<_, ()>(format!("author.{author_id}"))
+            .await?;
+        Ok(())
+    }
+
+    async fn insert_author_post(&self, author_id: &str, item_ref: String) -> Result<(), AppError> {
+        self.client
+            .clone()
+            .sadd::<_, _, ()>(format!("author_posts.{author_id}"), item_ref)
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_author_post(&self, author_id: &str, item_ref: String) -> Result<(), AppError> {
+        self.client
+            .clone()
+            .srem::<_, _, ()>(format!("author_posts.{author_id}"), item_ref)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_author_post_refs(&self, author_id: &str) -> Result<Vec<String>, AppError> {
+        Ok(self
+            .client
+            .clone()
+            .smembers::<_, Vec<String>>(format!("author_posts.{author_id}"))
+            .await?)
+    }
+}
+
+impl GroupStore for RepositoryDb {
+    async fn get_group(&self, group_id: GroupId) -> Result<GroupEntity, AppError> {
+        let json_str = self
+            .client
+            .clone()
+            .get::<_, Option<String>>(format!("group.{group_id}"))
+            .await?
+            .ok_or(AppError::new(
+                ErrorKind::NotFound,
+                format!("no group {group_id}"),
+            ))?;
+        Ok(serde_json::from_str(json_str.as_str())?)
+    }
+
+    // Redka has no WATCH, so a save first claims the version it replaces,
+    // of which only one of concurrent saves gets the claim, then checks it is
+    // still the stored one.
+    async fn save_group(&self, group: GroupEntity) -> Result<bool, AppError> {
+        let mut redka = self.client.clone();
+        let claim = redka
+            .set_options::<_, _, Option<String>>(
+                format!("group_save.{}.{}", group.id, group.version),
+                1,
+                SetOptions::default()
+                    .conditional_set(ExistenceCheck::NX)
+                    .with_expiration(SetExpiry::EX(SAVE_CLAIM_TTL)),
+            )
+            .await?;
+        if claim.is_none() {
+            return Ok(false);
+        }
+        let version = match self.get_group(group.id).await {
+            Ok(stored) => stored.version,
+            Err(err) if err.kind == ErrorKind::NotFound => 0,
+            Err(err) => return Err(err),
+        };
+        if version != group.version {
+            return Ok(false);
+        }
+        redka
+            .set::<_, _, ()>(
+                format!("group.{}", group.id),
+                serde_json::to_string(&GroupEntity {
+                    version: version + 1,
+                    ..group
+                })
+                .unwrap(),
+            )
+            .await?;
+        Ok(true)
+    }
+}
+
+impl ThreadStore for RepositoryDb {
+    async fn insert_reply(&self, parent_ref: &str, item_ref: String) -> Result<(), AppError> {
+        self.client
+            .clone()
+            .rpush::<_, _, ()>(format!("replies.{parent_ref}"), item_ref)
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_reply(&self, parent_ref: &str, item_ref: String) -> Result<(), AppError> {
+        self.client
+            .clone()
+            .lrem::<_, _, ()>(format!("replies.{parent_ref}"), 0, item_ref)
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_replies(&self, parent_ref: &str) -> Result<(), AppError> {
+        self.client
+            .clone()
+            .del::<_, ()>(format!("replies.{parent_ref}"))
+            .await?;
+        Ok(())
+    }
+
+    async fn get_reply_refs(&self, parent_ref: &str) -> Result<Vec<String>, AppError> {
+        Ok(self
+            .client
+            .clone()
+            .lrange::<_, Vec<String>>(format!("replies.{parent_ref}"), 0, -1)
+            .await?)
+    }
 }
 
 impl ItemRepo<String, String, PostEntity, AppError> for Repository {
-    fn get_cache(&self) -> impl SearchCache<String, AppError> {
+    fn get_cache(&self) -> impl SearchCache<String, String, AppError> {
         self.redis.clone()
     }
 
     fn get_db(&self) -> impl SearchDb<String, String, PostEntity, AppError> {
         self.redka.clone()
     }
+
+    fn get_scorer(&self) -> impl Scorer<String, String, PostEntity, AppError> {
+        self.scoring
+    }
 }
 
 impl PowValidator for RepositoryCache {
-    async fn is_valid_pow(&self, challenges: [String; 16]) -> bool {
+    async fn is_valid_pow(&self, challenges: Vec<String>, valid_seconds: u64) -> bool {
         for challenge in challenges {
             if Pow::validate(&challenge).is_err() {
                 return false;
@@ -157,15 +873,286 @@ impl PowValidator for RepositoryCache {
             let _ = self
                 .cache
                 .clone()
-                .set_ex::<_, _, String>(challenge, true, 600)
+                .set_ex::<_, _, String>(challenge, true, valid_seconds)
                 .await;
         }
         true
     }
 }
 
-impl Repository {
-    pub fn get_pow_validator(&self) -> impl PowValidator {
-        return self.redis.clone();
+impl AliasStore for RepositoryDb {
+    async fn get_aliases(&self) -> Result<Vec<Alias>, AppError> {
+        let mut phrases = self
+            .client
+            .clone()
+            .smembers::<_, Vec<String>>("alias_phrases")
+            .await?;
+        phrases.sort();
+        let mut pipe = redis::pipe();
+        for phrase in phrases.iter() {
+            pipe.smembers(format!("aliases.{phrase}"));
+        }
+        let tags: Vec<Vec<String>> = pipe.query_async(&mut self.client.clone()).await?;
+        Ok(phrases
+            .into_iter()
+            .zip(tags)
+            .map(|(phrase, mut tags)| {
+                tags.sort();
+                Alias { phrase, tags }
+            })
+            .collect())
+    }
+}
+
+// Lexicographic range of the members starting with the prefix.
+fn prefix_range(prefix: &str) -> (String, String) {
+    (format!("[{prefix}"), format!("[{prefix}{}", char::MAX))
+}
+
+impl SuggestStore for RepositoryDb {
+    // Words are scored by minus their uses, see insert_words.
+    async fn get_tag_completions(
+        &self,
+        prefix: &str,
+        limit: usize,
+    ) -> Result<Vec<(String, usize)>, AppError> {
+        if limit == 0 {
+            return Ok(vec![]);
+        }
+        let words = self
+            .client
+            .clone()
+            .zrange_withscores::<_, Vec<(String, f64)>>(
+                format!("word_prefix.{prefix}"),
+                0,
+                limit as isize - 1,
+            )
+            .await?;
+        // A word being removed may still be listed, without uses.
+        Ok(words
+            .into_iter()
+            .map(|(word, score)| (word, -score))
+            .filter(|(_, uses)| *uses > 0.0)
+            .map(|(word, uses)| (word, uses as usize))
+            .collect())
+    }
+
+    async fn get_title_completions(
+        &self,
+        prefix: &str,
+        limit: usize,
+    ) -> Result<Vec<TitleSuggestion>, AppError> {
+        let (min, max) = prefix_range(prefix);
+        let members = self
+            .client
+            .clone()
+            .zrangebylex_limit::<_, _, _, Vec<String>>(
+                "title_prefixes",
+                min,
+                max,
+                0,
+                limit as isize,
+            )
+            .await?;
+        Ok(members
+            .into_iter()
+            .filter_map(|member| {
+                let mut parts = member.splitn(3, '\t').skip(1);
+                Some(TitleSuggestion {
+                    slug: parts.next()?.to_string(),
+                    title: parts.next()?.to_string(),
+                })
+            })
+            .collect())
+    }
+}
+
+impl Backend for Repository {
+    fn get_pow_validator(&self) -> impl PowValidator + Send + Sync {
+        self.redis.clone()
+    }
+
+    fn get_user_store(&self) -> impl UserStore {
+        self.redka.clone()
+    }
+
+    fn get_author_store(&self) -> impl AuthorStore {
+        self.redka.clone()
+    }
+
+    fn get_group_store(&self) -> impl GroupStore {
+        self.redka.clone()
+    }
+
+    fn get_thread_store(&self) -> impl ThreadStore {
+        self.redka.clone()
+    }
+
+    fn get_suggest_store(&self) -> impl SuggestStore {
+        self.redka.clone()
+    }
+
+    fn get_alias_store(&self) -> impl AliasStore {
+        self.redka.clone()
+    }
+    fn render_metrics(&self) -> String {
+        self.redis.metrics.render()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::{Mutex, MutexGuard};
+
+    use super::*;
+    use crate::backend::tests;
+
+    // Tests sharing the live stores take turns, as some of them empty them.
+    static STORES: Mutex<()> = Mutex::const_new(());
+
+    fn test_config(var: &str) -> Option<RedisConfig> {
+        Some(RedisConfig {
+            url: std::env::var(var).ok()?,
+            username: None,
+            password: None,
+        })
+    }
+
+    // A live store, as redka lacks some of the commands redis has. Skipped
+    // unless RIBBIT_TEST_REDKA_URL is set, to a store only tests use.
+    async fn test_db() -> Option<(RepositoryDb, MutexGuard<'static, ()>)> {
+        let config = test_config("RIBBIT_TEST_REDKA_URL")?;
+        let turn = STORES.lock().await;
+        Some((RepositoryDb::connect(&config).await.unwrap(), turn))
+    }
+
+    // Both stores emptied, for the checks shared with the other backends. The
+    // cache is RIBBIT_TEST_REDIS_URL when set, or else the redka store too.
+    async fn test_repo() -> Option<(Repository, MutexGuard<'static, ()>)> {
+        let redka = test_config("RIBBIT_TEST_REDKA_URL")?;
+        let redis = test_config("RIBBIT_TEST_REDIS_URL").unwrap_or(redka.clone());
+        let turn = STORES.lock().await;
+        let repo = Repository::connect(&redis, &redka).await.unwrap();
+        for mut client in [repo.redis.cache.clone(), repo.redka.client.clone()] {
+            redis::cmd("FLUSHDB")
+                .query_async::<()>(&mut client)
+                .await
+                .unwrap();
+        }
+        Some((repo, turn))
+    }
+
+    #[tokio::test]
+    async fn test_post_round_trip() {
+        if let Some((repo, _turn)) = test_repo().await {
+            tests::post_round_trip(repo).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_follows_edits() {
+        if let Some((repo, _turn)) = test_repo().await {
+            tests::search_follows_edits(repo).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tag_completions_ranked_by_use() {
+        if let Some((repo, _turn)) = test_repo().await {
+            tests::tag_completions_ranked_by_use(repo).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dangling_replies_are_skipped() {
+        if let Some((repo, _turn)) = test_repo().await {
+            tests::dangling_replies_are_skipped(repo).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_users_and_groups() {
+        if let Some((repo, _turn)) = test_repo().await {
+            tests::users_and_groups(repo).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_set_operations_in_redka() {
+        let Some((db, _turn)) = test_db().await else {
+            return;
+        };
+        let run = Uuid::new_v4().simple().to_string();
+        let tag = |name: &str| format!("{name}{run}");
+        let slug = |n: usize| format!("{run}-{n}");
+        let mut client = db.client.clone();
+        // Dated in the order of their number.
+        let indexed = [
+            (1, vec![("butter", 3), ("flour", 1)]),
+            (2, vec![("butter", 1)]),
+            (3, vec![("flour", 3)]),
+        ];
+        for (n, tags) in indexed {
+            for (name, weight) in tags {
+                client
+                    .zadd::<_, _, _, ()>(format!("tag.{}", tag(name)), slug(n), weight)
+                    .await
+                    .unwrap();
+            }
+            client
+                .zadd::<_, _, _, ()>("posts_by_date", slug(n), n)
+                .await
+                .unwrap();
+        }
+        let tags = |names: &[&str]| ItemSet::Tags(names.iter().map(|name| tag(name)).collect());
+
+        let both = ItemSet::Inter(
+            vec![(tags(&["butter"]), 1), (tags(&["flour"]), 2)],
+            Aggregate::Sum,
+        );
+        assert_eq!(db.get_scored_items(both).await.unwrap(), vec![(slug(1), 5)]);
+        let either = ItemSet::Union(
+            vec![(tags(&["butter"]), 1), (tags(&["flour"]), 1)],
+            Aggregate::Max,
+        );
+        let mut scores = db.get_scored_items(either.clone()).await.unwrap();
+        scores.sort();
+        assert_eq!(scores, vec![(slug(1), 3), (slug(2), 1), (slug(3), 3)]);
+        let without = ItemSet::Diff(Box::new(either.clone()), Box::new(tags(&["flour"])));
+        assert_eq!(
+            db.get_scored_items(without).await.unwrap(),
+            vec![(slug(2), 1)]
+        );
+
+        // Best score first, newest first among equals.
+        let ranked = db
+            .get_ranked_items(either.clone(), SearchOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(ranked, vec![slug(3), slug(1), slug(2)]);
+        let options = SearchOptions {
+            sort: SortOrder::Recency,
+            min_score: 2,
+            top_n: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            db.get_ranked_items(either, options).await.unwrap(),
+            vec![slug(3)]
+        );
+        let scratch = client.keys::<_, Vec<String>>("scratch.*").await.unwrap();
+        assert!(scratch.is_empty());
+
+        for name in ["butter", "flour"] {
+            client
+                .del::<_, ()>(format!("tag.{}", tag(name)))
+                .await
+                .unwrap();
+        }
+        let slugs: Vec<_> = (1..=3).map(slug).collect();
+        client
+            .zrem::<_, _, ()>("posts_by_date", slugs)
+            .await
+            .unwrap();
     }
 }
@@ -1,81 +1,489 @@
-use std::usize;
-
-use crate::indexing::{insert_and_index_item, InsertHandle};
-use crate::schemas::{AppError, AuthorInfo, Page, Post};
-use crate::schemas::{AuthorEntity, PostEntity};
-use crate::search::{ItemRepo, SearchDb};
+use crate::authorization::{can_edit, can_reply, can_view};
+use crate::authors::{resolve_authors, AuthorStore};
+use crate::blocking::{avoided_handles, avoids_publisher};
+use crate::indexing::{
+    insert_and_index_new_item, remove_and_unindex_item, update_and_reindex_item, InsertHandle,
+};
+use crate::schemas::{AppError, AuthorInfo, AuthorProfile, ErrorKind, Page, Post};
+use crate::schemas::{AuthorEntity, PostEntity, UserEntity};
+use crate::search::{ItemRepo, SearchDb, SearchOptions};
+use crate::threads::{find_replies, ThreadStore};
+use crate::users::UserStore;
 
+use chrono::Utc;
+use futures::future::try_join_all;
+use std::cmp::Reverse;
 use tokio::task::JoinError;
 
 impl From<JoinError> for AppError {
     fn from(value: JoinError) -> Self {
-        Self {
-            reason: value.to_string(),
-        }
+        Self::new(ErrorKind::Internal, value.to_string())
     }
 }
+const DATETIME_FORMAT: &str = "%Y-%m-%d %H:%M UTC";
+
 impl Post {
-    pub fn from_store(entity: PostEntity, author: AuthorEntity) -> Self {
+    pub fn from_store(
+        entity: PostEntity,
+        author: AuthorEntity,
+        viewer: Option<&UserEntity>,
+    ) -> Self {
         Self {
+            can_reply: can_reply(&entity, viewer),
+            replies: vec![],
             title: entity.title.clone(),
             slug: entity.slug.clone(),
             author: AuthorInfo {
+                handle: author.author_id,
                 name: author.name,
                 profile_picture: author.profile_picture,
             },
             body: entity.body.clone(),
-            can_reply: false,
+            created_at: entity.created_at.format(DATETIME_FORMAT).to_string(),
+            updated_at: (entity.updated_at != entity.created_at)
+                .then(|| entity.updated_at.format(DATETIME_FORMAT).to_string()),
         }
     }
 }
 
+async fn posts_with_authors(
+    authors: &impl AuthorStore,
+    viewer: Option<&UserEntity>,
+    posts: Vec<PostEntity>,
+) -> Result<Vec<Post>, AppError> {
+    let resolved = resolve_authors(authors, posts.iter().map(|p| p.author.clone())).await?;
+    Ok(posts
+        .into_iter()
+        .map(|p| {
+            let author = resolved
+                .get(&p.author)
+                .cloned()
+                .unwrap_or_else(|| AuthorEntity::unknown(p.author.clone()));
+            Post::from_store(p, author, viewer)
+        })
+        .collect())
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn find_posts(
     db: impl ItemRepo<String, String, PostEntity, AppError>,
+    authors: &impl AuthorStore,
+    users: &impl UserStore,
+    viewer: Option<&UserEntity>,
     search_query: &str,
+    options: SearchOptions,
     page_num: usize,
+    per_page: usize,
 ) -> Result<Page<Post>, AppError> {
+    let avoided = avoided_handles(users, viewer).await?;
     let (posts, nb_items) = db
-        .get_items_for_search(search_query, 20, 1, 20, page_num)
+        .get_visible_items_for_search(search_query, options, per_page, page_num, |post| {
+            can_view(post, viewer)
+                && !avoided.contains(&post.author)
+                && !avoids_publisher(viewer, post)
+        })
         .await?;
 
     Ok(Page {
-        objects: posts
-            .into_iter()
-            .map(|r| {
-                Post::from_store(
-                    r.clone(),
-                    AuthorEntity {
-                        author_id: r.author,
-                        name: "sample name".to_string(),
-                        profile_picture: "https://example.com".to_string(),
-                    },
-                )
-            })
-            .collect(),
+        objects: posts_with_authors(authors, viewer, posts).await?,
         total_objects: nb_items,
         current_page: page_num,
-        per_page: 20,
+        per_page,
     })
 }
 
 pub async fn find_post(
     db: impl SearchDb<String, String, PostEntity, AppError>,
+    authors: &impl AuthorStore,
+    threads: &impl ThreadStore,
+    users: &impl UserStore,
+    viewer: Option<&UserEntity>,
     slug: String,
 ) -> Result<Post, AppError> {
-    let entity = db.get_item_from_ref(slug).await?;
-    Ok(Post::from_store(
-        entity.clone(),
-        AuthorEntity {
-            author_id: entity.author,
-            name: "sample name".to_string(),
-            profile_picture: "https://example.com".to_string(),
+    let entity = db.get_item_from_ref(slug.clone()).await?;
+    let avoided = avoided_handles(users, viewer).await?;
+    // Hidden posts are reported missing so their existence doesn't leak.
+    if !can_view(&entity, viewer)
+        || avoided.contains(&entity.author)
+        || avoids_publisher(viewer, &entity)
+    {
+        return Err(AppError::new(
+            ErrorKind::NotFound,
+            format!("no post {slug}"),
+        ));
+    }
+    let author = authors
+        .get_author(&entity.author)
+        .await?
+        .unwrap_or_else(|| AuthorEntity::unknown(entity.author.clone()));
+    let replies = find_replies(&db, authors, threads, viewer, &avoided, slug).await?;
+    let mut post = Post::from_store(entity, author, viewer);
+    post.replies = replies;
+    Ok(post)
+}
+
+pub async fn find_author_profile(
+    db: impl SearchDb<String, String, PostEntity, AppError>,
+    authors: &impl AuthorStore,
+    users: &impl UserStore,
+    viewer: Option<&UserEntity>,
+    handle: String,
+    page_num: usize,
+    per_page: usize,
+) -> Result<AuthorProfile, AppError> {
+    let not_found = || AppError::new(ErrorKind::NotFound, format!("no author {handle}"));
+    let author = authors.get_author(&handle).await?.ok_or_else(not_found)?;
+    if avoided_handles(users, viewer).await?.contains(&handle) {
+        return Err(not_found());
+    }
+    let post_refs = authors.get_author_post_refs(&handle).await?;
+    let mut posts: Vec<_> = try_join_all(
+        post_refs
+            .into_iter()
+            .map(|post_ref| db.get_item_from_ref(post_ref)),
+    )
+    .await?
+    .into_iter()
+    // Replies were listed too before they were kept off profiles.
+    .filter(|post| post.parent.is_none() && can_view(post, viewer))
+    .filter(|post| !avoids_publisher(viewer, post))
+    .collect();
+    posts.sort_by_key(|post| Reverse(post.created_at));
+    let total_objects = posts.len();
+    Ok(AuthorProfile {
+        author: AuthorInfo {
+            handle: author.author_id.clone(),
+            name: author.name.clone(),
+            profile_picture: author.profile_picture.clone(),
         },
-    ))
+        posts: Page {
+            objects: posts
+                .into_iter()
+                .skip(page_num.saturating_sub(1) * per_page)
+                .take(per_page)
+                .map(|p| Post::from_store(p, author.clone(), viewer))
+                .collect(),
+            total_objects,
+            current_page: page_num,
+            per_page,
+        },
+    })
 }
 
+// Past this many same-titled posts, a random suffix is used instead of a counter.
+const SLUG_ATTEMPTS: usize = 64;
+
+// Stores the post under the first free slug among `slug`, `slug-2`, `slug-3`...
+// and returns that slug.
 pub async fn register_post(
     db: impl InsertHandle<String, String, PostEntity, AppError>,
+    authors: &impl AuthorStore,
     form: PostEntity,
+) -> Result<String, AppError> {
+    let base = if form.slug.is_empty() {
+        "post".to_string()
+    } else {
+        form.slug.clone()
+    };
+    let candidates = (1..=SLUG_ATTEMPTS)
+        .map(|n| match n {
+            1 => base.clone(),
+            n => format!("{base}-{n}"),
+        })
+        .chain(std::iter::once(format!(
+            "{base}-{}",
+            &uuid::Uuid::new_v4().simple().to_string()[..8]
+        )))
+        .map(|slug| {
+            let mut post = form.clone();
+            post.slug = slug.clone();
+            (slug, post)
+        });
+    let slug = insert_and_index_new_item(
+        &db,
+        candidates,
+        form.indexed_tags(),
+        form.term_frequencies(),
+    )
+    .await?
+    .ok_or(AppError::new(
+        ErrorKind::Conflict,
+        format!("no free slug for {base}"),
+    ))?;
+    authors
+        .insert_author_post(&form.author, slug.clone())
+        .await?;
+    Ok(slug)
+}
+
+async fn find_editable_post(
+    db: &impl ItemRepo<String, String, PostEntity, AppError>,
+    user: &UserEntity,
+    slug: String,
+) -> Result<PostEntity, AppError> {
+    let post = db.get_db().get_item_from_ref(slug).await?;
+    if !can_edit(&post, user) {
+        return Err(AppError::new(
+            ErrorKind::Forbidden,
+            "only the author can change a post",
+        ));
+    }
+    Ok(post)
+}
+
+pub async fn edit_post(
+    db: &(impl ItemRepo<String, String, PostEntity, AppError>
+          + InsertHandle<String, String, PostEntity, AppError>),
+    user: &UserEntity,
+    slug: String,
+    title: String,
+    body: &str,
+    tags: &str,
+) -> Result<PostEntity, AppError> {
+    let old = find_editable_post(db, user, slug.clone()).await?;
+    let mut post = old.clone();
+    // Replies have no title of their own.
+    if old.parent.is_none() {
+        post.title = title;
+        post.search_tags = tags.split(" ").map(|s| s.to_string()).collect();
+    }
+    post.body = markdown::to_html(body);
+    post.updated_at = Utc::now();
+    update_and_reindex_item(
+        db,
+        slug,
+        post.clone(),
+        old.indexed_tags(),
+        post.indexed_tags(),
+        post.term_frequencies(),
+    )
+    .await?;
+    Ok(post)
+}
+
+pub async fn delete_post(
+    db: &(impl ItemRepo<String, String, PostEntity, AppError>
+          + InsertHandle<String, String, PostEntity, AppError>),
+    authors: &impl AuthorStore,
+    threads: &impl ThreadStore,
+    user: &UserEntity,
+    slug: String,
 ) -> Result<(), AppError> {
-    insert_and_index_item(&db, form.slug.clone(), form.clone(), form.search_tags()).await
+    let post = find_editable_post(db, user, slug.clone()).await?;
+    if let Some(parent) = &post.parent {
+        threads.remove_reply(parent, slug.clone()).await?;
+    }
+    // Replies go with the post, or a new post taking its slug over would
+    // inherit them.
+    let mut frontier = vec![slug];
+    let mut removed = vec![post];
+    while !frontier.is_empty() {
+        let reply_refs =
+            try_join_all(frontier.iter().map(|slug| threads.get_reply_refs(slug))).await?;
+        let replies = try_join_all(reply_refs.into_iter().flatten().map(|reply_ref| {
+            let db = db.get_db();
+            async move {
+                match db.get_item_from_ref(reply_ref).await {
+                    Ok(reply) => Ok(Some(reply)),
+                    Err(err) if err.kind == ErrorKind::NotFound => Ok(None),
+                    Err(err) => Err(err),
+                }
+            }
+        }))
+        .await?;
+        for parent in frontier {
+            threads.remove_replies(&parent).await?;
+        }
+        frontier = replies
+            .iter()
+            .flatten()
+            .map(|reply| reply.slug.clone())
+            .collect();
+        removed.extend(replies.into_iter().flatten());
+    }
+    for post in removed {
+        remove_and_unindex_item(
+            db,
+            post.slug.clone(),
+            post.indexed_tags()
+                .into_iter()
+                .map(|(tag, _)| tag)
+                .collect(),
+        )
+        .await?;
+        authors.remove_author_post(&post.author, post.slug).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::InMemoryRepository;
+    use crate::schemas::TITLE_WEIGHT;
+    use crate::threads::reply_to_post;
+    use crate::tokenizer::Language;
+
+    async fn user(db: &InMemoryRepository, handle: &str) -> UserEntity {
+        let user = UserEntity {
+            id: uuid::Uuid::new_v4(),
+            handle: handle.to_string(),
+            password_hash: String::new(),
+            groups: vec![],
+            block_list: vec![],
+            blocked_by: vec![],
+            version: 0,
+        };
+        db.insert_user(user.clone()).await.unwrap();
+        user
+    }
+
+    async fn publish(
+        db: &InMemoryRepository,
+        user: &UserEntity,
+        title: &str,
+        tags: &str,
+    ) -> String {
+        let now = Utc::now();
+        register_post(
+            db.clone(),
+            db,
+            PostEntity {
+                title: title.to_string(),
+                slug: slug::slugify(title),
+                author: user.handle.clone(),
+                search_tags: tags.split(" ").map(|s| s.to_string()).collect(),
+                body: "<p>Mix, then bake.</p>".to_string(),
+                space: None,
+                reply_scope: None,
+                visibility_scope: None,
+                parent: None,
+                publisher: Some(user.id),
+                created_at: now,
+                updated_at: now,
+                lang: Language::En,
+            },
+        )
+        .await
+        .unwrap()
+    }
+
+    async fn search_titles(db: &InMemoryRepository, query: &str) -> Vec<String> {
+        find_posts(
+            db.clone(),
+            db,
+            db,
+            None,
+            query,
+            SearchOptions::default(),
+            1,
+            20,
+        )
+        .await
+        .unwrap()
+        .objects
+        .into_iter()
+        .map(|post| post.title)
+        .collect()
+    }
+
+    #[tokio::test]
+    async fn test_only_the_publisher_changes_a_post() {
+        let db = InMemoryRepository::default();
+        let (alice, bob) = (user(&db, "alice").await, user(&db, "bob").await);
+        let slug = publish(&db, &alice, "Brown butter cake", "dessert").await;
+
+        let err = edit_post(&db, &bob, slug.clone(), "Mine".to_string(), "", "")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Forbidden);
+        let err = delete_post(&db, &db, &db, &bob, slug.clone())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Forbidden);
+        let post = db.get_item_from_ref(slug).await.unwrap();
+        assert_eq!(post.title, "Brown butter cake");
+    }
+
+    #[tokio::test]
+    async fn test_edit_replaces_stale_tags() {
+        let db = InMemoryRepository::default();
+        let alice = user(&db, "alice").await;
+        let slug = publish(&db, &alice, "Brown butter cake", "dessert").await;
+        // Cached, so stale results would come back if not invalidated.
+        assert_eq!(
+            search_titles(&db, "butter").await,
+            vec!["Brown butter cake"]
+        );
+        assert_eq!(
+            search_titles(&db, "dessert").await,
+            vec!["Brown butter cake"]
+        );
+
+        let post = edit_post(
+            &db,
+            &alice,
+            slug.clone(),
+            "Olive oil cake".to_string(),
+            "Whisk, then *bake*.",
+            "savory",
+        )
+        .await
+        .unwrap();
+        assert_eq!(post.body, "<p>Whisk, then <em>bake</em>.</p>\n");
+        assert!(search_titles(&db, "butter").await.is_empty());
+        assert!(search_titles(&db, "dessert").await.is_empty());
+        assert_eq!(search_titles(&db, "savory").await, vec!["Olive oil cake"]);
+        assert!(db
+            .get_item_refs_from_tag("butter".to_string())
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            db.get_item_refs_from_tag("oliv".to_string()).await.unwrap(),
+            vec![(slug, TITLE_WEIGHT)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_takes_the_replies() {
+        let db = InMemoryRepository::default();
+        let (alice, bob) = (user(&db, "alice").await, user(&db, "bob").await);
+        let slug = publish(&db, &alice, "Brown butter cake", "dessert").await;
+        let reply = reply_to_post(&db, &db, &db, &bob, slug.clone(), "Needs salt.")
+            .await
+            .unwrap();
+        let nested = reply_to_post(&db, &db, &db, &alice, reply.slug.clone(), "How much?")
+            .await
+            .unwrap();
+        // Deleting a reply leaves the rest of the thread.
+        let other = reply_to_post(&db, &db, &db, &bob, slug.clone(), "Or honey.")
+            .await
+            .unwrap();
+        delete_post(&db, &db, &db, &bob, other.slug.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            db.get_reply_refs(&slug).await.unwrap(),
+            vec![reply.slug.clone()]
+        );
+
+        delete_post(&db, &db, &db, &alice, slug.clone())
+            .await
+            .unwrap();
+        for slug in [&slug, &reply.slug, &nested.slug, &other.slug] {
+            let err = db.get_item_from_ref(slug.clone()).await.unwrap_err();
+            assert_eq!(err.kind, ErrorKind::NotFound);
+        }
+        assert!(search_titles(&db, "butter").await.is_empty());
+        assert!(db.get_author_post_refs("alice").await.unwrap().is_empty());
+
+        // A post taking the slug over starts a thread of its own.
+        assert_eq!(publish(&db, &alice, "Brown butter cake", "").await, slug);
+        let post = find_post(db.clone(), &db, &db, &db, None, slug)
+            .await
+            .unwrap();
+        assert!(post.replies.is_empty());
+        assert!(db.get_reply_refs(&reply.slug).await.unwrap().is_empty());
+    }
 }
@@ -0,0 +1,1430 @@
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use spow::pow::Pow;
+use sqlx::any::{install_default_drivers, AnyPoolOptions, AnyRow};
+use sqlx::{Any, AnyPool, Row, Transaction};
+use uuid::Uuid;
+
+use crate::aliases::{Alias, AliasStore};
+use crate::authors::AuthorStore;
+use crate::backend::Backend;
+use crate::groups::GroupStore;
+use crate::indexing::InsertHandle;
+use crate::insertdb::is_term;
+use crate::pow::PowValidator;
+use crate::query::tags_from_field;
+use crate::schemas::{
+    AppError, AuthorEntity, AuthorId, ErrorKind, GroupEntity, GroupId, PostEntity, UserEntity,
+    UserId,
+};
+use crate::scoring::{CorpusStats, DocStats, Scorer, Scoring};
+use crate::search::{ItemRepo, SearchCache, SearchDb};
+use crate::searchdb::{CacheMetrics, SEARCH_CACHE_TTL};
+use crate::suggest::{SuggestStore, TitleSuggestion};
+use crate::threads::ThreadStore;
+use crate::tokenizer::{bigrams, fold, is_typo_of, Language};
+use crate::users::UserStore;
+
+// A file next to the server, created on first start.
+pub const DEFAULT_DATABASE_URL: &str = "sqlite://ribbit.db?mode=rwc";
+
+// Created when missing on connection. Only portable SQL, so that the same
+// statements run on SQLite and Postgres. Ids and dates are stored as text,
+// booleans as integers. Rows go with the post, user, group or search they
+// belong to, sqlx turns foreign keys on for SQLite.
+const SCHEMA: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS posts (
+        slug TEXT PRIMARY KEY,
+        title TEXT NOT NULL,
+        title_key TEXT,
+        author TEXT NOT NULL,
+        body TEXT NOT NULL,
+        space TEXT,
+        reply_scope TEXT,
+        visibility_scope TEXT,
+        parent TEXT,
+        publisher TEXT,
+        created_at TEXT NOT NULL,
+        updated_at TEXT NOT NULL,
+        lang TEXT NOT NULL
+    )",
+    "CREATE TABLE IF NOT EXISTS post_search_tags (
+        slug TEXT NOT NULL REFERENCES posts (slug) ON DELETE CASCADE,
+        position BIGINT NOT NULL,
+        tag TEXT NOT NULL,
+        PRIMARY KEY (slug, position)
+    )",
+    "CREATE INDEX IF NOT EXISTS posts_title_key ON posts (title_key)",
+    "CREATE TABLE IF NOT EXISTS post_tags (
+        tag TEXT NOT NULL,
+        slug TEXT NOT NULL REFERENCES posts (slug) ON DELETE CASCADE,
+        weight BIGINT NOT NULL,
+        PRIMARY KEY (tag, slug)
+    )",
+    "CREATE TABLE IF NOT EXISTS post_words (
+        word TEXT NOT NULL,
+        slug TEXT NOT NULL REFERENCES posts (slug) ON DELETE CASCADE,
+        PRIMARY KEY (word, slug)
+    )",
+    "CREATE TABLE IF NOT EXISTS tag_grams (
+        gram TEXT NOT NULL,
+        tag TEXT NOT NULL,
+        PRIMARY KEY (gram, tag)
+    )",
+    "CREATE TABLE IF NOT EXISTS term_frequencies (
+        slug TEXT NOT NULL REFERENCES posts (slug) ON DELETE CASCADE,
+        tag TEXT NOT NULL,
+        frequency BIGINT NOT NULL,
+        PRIMARY KEY (slug, tag)
+    )",
+    "CREATE INDEX IF NOT EXISTS term_frequencies_tag ON term_frequencies (tag)",
+    "CREATE TABLE IF NOT EXISTS aliases (
+        phrase TEXT NOT NULL,
+        tag TEXT NOT NULL,
+        PRIMARY KEY (phrase, tag)
+    )",
+    "CREATE TABLE IF NOT EXISTS searches (
+        search TEXT PRIMARY KEY,
+        results TEXT NOT NULL,
+        expires_at BIGINT NOT NULL
+    )",
+    "CREATE TABLE IF NOT EXISTS search_tags (
+        tag TEXT NOT NULL,
+        search TEXT NOT NULL REFERENCES searches (search) ON DELETE CASCADE,
+        PRIMARY KEY (tag, search)
+    )",
+    // A single row, bumped by every invalidation.
+    "CREATE TABLE IF NOT EXISTS search_generation (
+        id INTEGER PRIMARY KEY,
+        generation BIGINT NOT NULL
+    )",
+    "INSERT INTO search_generation (id, generation) VALUES (1, 0) ON CONFLICT DO NOTHING",
+    "CREATE TABLE IF NOT EXISTS pow_challenges (
+        challenge TEXT PRIMARY KEY,
+        expires_at BIGINT NOT NULL
+    )",
+    "CREATE TABLE IF NOT EXISTS users (
+        id TEXT PRIMARY KEY,
+        handle TEXT NOT NULL UNIQUE,
+        password_hash TEXT NOT NULL,
+        version BIGINT NOT NULL
+    )",
+    "CREATE TABLE IF NOT EXISTS groups (
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        management TEXT NOT NULL,
+        allow_member_posting BIGINT NOT NULL,
+        face TEXT,
+        version BIGINT NOT NULL
+    )",
+    "CREATE TABLE IF NOT EXISTS user_groups (
+        user_id TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        group_id TEXT NOT NULL REFERENCES groups (id) ON DELETE CASCADE,
+        position BIGINT NOT NULL,
+        PRIMARY KEY (user_id, group_id)
+    )",
+    "CREATE TABLE IF NOT EXISTS user_blocks (
+        user_id TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        blocked_id TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        position BIGINT NOT NULL,
+        PRIMARY KEY (user_id, blocked_id)
+    )",
+    "CREATE INDEX IF NOT EXISTS user_blocks_blocked_id ON user_blocks (blocked_id)",
+    "CREATE TABLE IF NOT EXISTS sessions (
+        token TEXT PRIMARY KEY,
+        user_id TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        expires_at BIGINT NOT NULL
+    )",
+    "CREATE TABLE IF NOT EXISTS authors (
+        author_id TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        profile_picture TEXT NOT NULL
+    )",
+    "CREATE TABLE IF NOT EXISTS author_posts (
+        author_id TEXT NOT NULL,
+        slug TEXT NOT NULL REFERENCES posts (slug) ON DELETE CASCADE,
+        PRIMARY KEY (author_id, slug)
+    )",
+    "CREATE TABLE IF NOT EXISTS group_members (
+        group_id TEXT NOT NULL REFERENCES groups (id) ON DELETE CASCADE,
+        role TEXT NOT NULL,
+        user_id TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        position BIGINT NOT NULL,
+        PRIMARY KEY (group_id, role, user_id)
+    )",
+    "CREATE TABLE IF NOT EXISTS replies (
+        parent TEXT NOT NULL REFERENCES posts (slug) ON DELETE CASCADE,
+        slug TEXT NOT NULL REFERENCES posts (slug) ON DELETE CASCADE,
+        position BIGINT NOT NULL,
+        PRIMARY KEY (parent, slug)
+    )",
+];
+
+const POST_COLUMNS: &str = "slug, title, title_key, author, body, space, reply_scope, \
+     visibility_scope, parent, publisher, created_at, updated_at, lang";
+
+// Every store over SQLite or Postgres, through the driver picked by the url.
+#[derive(Debug, Clone)]
+pub struct SqlRepository {
+    pool: AnyPool,
+    metrics: Arc<CacheMetrics>,
+    pub scoring: Scoring,
+    pub cache_ttl: i64, // Seconds cached searches live
+}
+
+impl From<sqlx::Error> for AppError {
+    fn from(value: sqlx::Error) -> Self {
+        Self::new(ErrorKind::Internal, value.to_string())
+    }
+}
+
+impl SqlRepository {
+    pub async fn connect(url: &str) -> Result<Self, AppError> {
+        install_default_drivers();
+        let pool = AnyPoolOptions::new().connect(url).await.map_err(|err| {
+            AppError::new(ErrorKind::Internal, format!("can't open {url}: {err}"))
+        })?;
+        let mut tx = pool.begin().await?;
+        for statement in SCHEMA {
+            sqlx::query(statement).execute(&mut *tx).await?;
+        }
+        tx.commit().await?;
+        Ok(Self {
+            pool,
+            metrics: Arc::new(CacheMetrics::default()),
+            scoring: Scoring::default(),
+            cache_ttl: SEARCH_CACHE_TTL,
+        })
+    }
+}
+
+fn now() -> i64 {
+    Utc::now().timestamp()
+}
+
+// "$first, $first + 1, ..." for a list of `count` values.
+fn placeholders(first: usize, count: usize) -> String {
+    (first..first + count)
+        .map(|n| format!("${n}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn parse_id(id: &str) -> Result<Uuid, AppError> {
+    id.parse()
+        .map_err(|_| AppError::new(ErrorKind::Internal, format!("bad id {id}")))
+}
+
+fn parse_ids(ids: Vec<String>) -> Result<Vec<Uuid>, AppError> {
+    ids.iter().map(|id| parse_id(id)).collect()
+}
+
+fn parse_date(date: &str) -> Result<DateTime<Utc>, AppError> {
+    DateTime::parse_from_rfc3339(date)
+        .map(|date| date.with_timezone(&Utc))
+        .map_err(|_| AppError::new(ErrorKind::Internal, format!("bad date {date}")))
+}
+
+// Folded title, only set on public thread roots, the ones suggested.
+fn title_key(item: &PostEntity) -> Option<String> {
+    if item.parent.is_some() || item.visibility_group().is_some() {
+        return None;
+    }
+    Some(fold(&item.title))
+}
+
+fn post_from_row(row: &AnyRow, search_tags: Vec<String>) -> Result<PostEntity, AppError> {
+    let id = |column: &str| -> Result<Option<Uuid>, AppError> {
+        row.try_get::<Option<String>, _>(column)?
+            .map(|id| parse_id(&id))
+            .transpose()
+    };
+    Ok(PostEntity {
+        title: row.try_get("title")?,
+        slug: row.try_get("slug")?,
+        author: row.try_get("author")?,
+        search_tags,
+        body: row.try_get("body")?,
+        space: id("space")?,
+        reply_scope: id("reply_scope")?,
+        visibility_scope: id("visibility_scope")?,
+        parent: row.try_get("parent")?,
+        publisher: id("publisher")?,
+        created_at: parse_date(row.try_get("created_at")?)?,
+        updated_at: parse_date(row.try_get("updated_at")?)?,
+        lang: Language::from_code(row.try_get("lang")?),
+    })
+}
+
+// Writes the post and its search tags, `on_conflict` deciding what happens
+// to an existing post. Returns whether the post was written.
+async fn write_post(
+    tx: &mut Transaction<'_, Any>,
+    item: &PostEntity,
+    on_conflict: &str,
+) -> Result<bool, AppError> {
+    let written = sqlx::query(&format!(
+        "INSERT INTO posts ({POST_COLUMNS}) VALUES ({}) {on_conflict}",
+        placeholders(1, 13)
+    ))
+    .bind(&item.slug)
+    .bind(&item.title)
+    .bind(title_key(item))
+    .bind(&item.author)
+    .bind(&item.body)
+    .bind(item.space.map(|id| id.to_string()))
+    .bind(item.reply_scope.map(|id| id.to_string()))
+    .bind(item.visibility_scope.map(|id| id.to_string()))
+    .bind(item.parent.clone())
+    .bind(item.publisher.map(|id| id.to_string()))
+    .bind(item.created_at.to_rfc3339())
+    .bind(item.updated_at.to_rfc3339())
+    .bind(item.lang.code())
+    .execute(&mut **tx)
+    .await?
+    .rows_affected()
+        > 0;
+    if !written {
+        return Ok(false);
+    }
+    sqlx::query("DELETE FROM post_search_tags WHERE slug = $1")
+        .bind(&item.slug)
+        .execute(&mut **tx)
+        .await?;
+    for (position, tag) in item.search_tags.iter().enumerate() {
+        sqlx::query("INSERT INTO post_search_tags (slug, position, tag) VALUES ($1, $2, $3)")
+            .bind(&item.slug)
+            .bind(position as i64)
+            .bind(tag)
+            .execute(&mut **tx)
+            .await?;
+    }
+    sqlx::query("DELETE FROM post_words WHERE slug = $1")
+        .bind(&item.slug)
+        .execute(&mut **tx)
+        .await?;
+    for word in item.completion_words() {
+        sqlx::query("INSERT INTO post_words (word, slug) VALUES ($1, $2)")
+            .bind(word)
+            .bind(&item.slug)
+            .execute(&mut **tx)
+            .await?;
+    }
+    Ok(true)
+}
+
+// Overwrites every column of a stored post.
+const UPSERT_POST: &str = "ON CONFLICT (slug) DO UPDATE SET title = excluded.title, \
+     title_key = excluded.title_key, author = excluded.author, body = excluded.body, \
+     space = excluded.space, reply_scope = excluded.reply_scope, \
+     visibility_scope = excluded.visibility_scope, parent = excluded.parent, \
+     publisher = excluded.publisher, created_at = excluded.created_at, \
+     updated_at = excluded.updated_at, lang = excluded.lang";
+
+async fn write_tags(
+    tx: &mut Transaction<'_, Any>,
+    tags: &[(String, usize)],
+    item_ref: &str,
+) -> Result<(), AppError> {
+    for (tag, weight) in tags {
+        // The weight is updated when indexing again.
+        sqlx::query(
+            "INSERT INTO post_tags (tag, slug, weight) VALUES ($1, $2, $3) \
+             ON CONFLICT (tag, slug) DO UPDATE SET weight = excluded.weight",
+        )
+        .bind(tag)
+        .bind(item_ref)
+        .bind(*weight as i64)
+        .execute(&mut **tx)
+        .await?;
+        if !is_term(tag) {
+            continue;
+        }
+        // Adds the tag to the term dictionary, looked up by letter pairs for typos.
+        for gram in bigrams(tag) {
+            sqlx::query("INSERT INTO tag_grams (gram, tag) VALUES ($1, $2) ON CONFLICT DO NOTHING")
+                .bind(gram)
+                .bind(tag)
+                .execute(&mut **tx)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+async fn delete_tags(
+    tx: &mut Transaction<'_, Any>,
+    tags: &[String],
+    item_ref: &str,
+) -> Result<(), AppError> {
+    for tag in tags {
+        sqlx::query("DELETE FROM post_tags WHERE tag = $1 AND slug = $2")
+            .bind(tag)
+            .bind(item_ref)
+            .execute(&mut **tx)
+            .await?;
+        // A tag nothing is indexed under anymore leaves the term dictionary.
+        sqlx::query(
+            "DELETE FROM tag_grams WHERE tag = $1 \
+             AND NOT EXISTS (SELECT 1 FROM post_tags WHERE tag = $1)",
+        )
+        .bind(tag)
+        .execute(&mut **tx)
+        .await?;
+    }
+    Ok(())
+}
+
+async fn write_term_stats(
+    tx: &mut Transaction<'_, Any>,
+    item_ref: &str,
+    terms: &[(String, usize)],
+) -> Result<(), AppError> {
+    sqlx::query("DELETE FROM term_frequencies WHERE slug = $1")
+        .bind(item_ref)
+        .execute(&mut **tx)
+        .await?;
+    for (tag, frequency) in terms {
+        sqlx::query("INSERT INTO term_frequencies (slug, tag, frequency) VALUES ($1, $2, $3)")
+            .bind(item_ref)
+            .bind(tag)
+            .bind(*frequency as i64)
+            .execute(&mut **tx)
+            .await?;
+    }
+    Ok(())
+}
+
+// Replaces the rows of an ordered list of ids, as in `user_groups`.
+async fn write_id_list(
+    tx: &mut Transaction<'_, Any>,
+    table: &str,
+    columns: (&str, &str),
+    owner: &str,
+    ids: &[Uuid],
+) -> Result<(), AppError> {
+    let (owner_column, id_column) = columns;
+    sqlx::query(&format!("DELETE FROM {table} WHERE {owner_column} = $1"))
+        .bind(owner)
+        .execute(&mut **tx)
+        .await?;
+    for (position, id) in ids.iter().enumerate() {
+        sqlx::query(&format!(
+            "INSERT INTO {table} ({owner_column}, {id_column}, position) VALUES ($1, $2, $3) \
+             ON CONFLICT DO NOTHING"
+        ))
+        .bind(owner)
+        .bind(id.to_string())
+        .bind(position as i64)
+        .execute(&mut **tx)
+        .await?;
+    }
+    Ok(())
+}
+
+impl SqlRepository {
+    async fn get_ids(&self, sql: &str, key: &str) -> Result<Vec<Uuid>, AppError> {
+        parse_ids(
+            sqlx::query_scalar::<_, String>(sql)
+                .bind(key)
+                .fetch_all(&self.pool)
+                .await?,
+        )
+    }
+}
+
+impl SearchCache<String, String, AppError> for SqlRepository {
+    async fn get_generation(&self) -> Result<u64, AppError> {
+        let generation =
+            sqlx::query_scalar::<_, i64>("SELECT generation FROM search_generation WHERE id = 1")
+                .fetch_one(&self.pool)
+                .await?;
+        Ok(generation as u64)
+    }
+
+    async fn get_cached_page(
+        &self,
+        search_tags: &str,
+        start: usize,
+        count: usize,
+    ) -> Result<Option<(Vec<String>, usize)>, AppError> {
+        let page = sqlx::query_scalar::<_, String>(
+            "SELECT results FROM searches WHERE search = $1 AND expires_at > $2",
+        )
+        .bind(search_tags)
+        .bind(now())
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|results| {
+            let results = serde_json::from_str::<Vec<String>>(&results).map_err(|err| {
+                AppError::new(ErrorKind::Internal, format!("bad cached search: {err}"))
+            })?;
+            let end = results.len().min(start.saturating_add(count));
+            Ok::<_, AppError>((results[start.min(end)..end].to_vec(), results.len()))
+        })
+        .transpose()?;
+        // A search counts once, when its first page is read.
+        if start == 0 {
+            let counter = if page.is_none() {
+                &self.metrics.misses
+            } else {
+                &self.metrics.hits
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(page)
+    }
+
+    // Ranked results are kept whole in one row, pages are sliced from it.
+    async fn cache_search(
+        &self,
+        search_tags: &str,
+        tags: Vec<String>,
+        results: Vec<String>,
+        generation: u64,
+    ) -> Result<(), AppError> {
+        let mut tx = self.pool.begin().await?;
+        // Locks the generation until commit, invalidations wait for it.
+        let current = sqlx::query(
+            "UPDATE search_generation SET generation = generation WHERE id = 1 AND generation = $1",
+        )
+        .bind(generation as i64)
+        .execute(&mut *tx)
+        .await?;
+        if current.rows_affected() == 0 {
+            return Ok(());
+        }
+        sqlx::query("DELETE FROM searches WHERE expires_at <= $1")
+            .bind(now())
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            "INSERT INTO searches (search, results, expires_at) VALUES ($1, $2, $3) \
+             ON CONFLICT (search) DO UPDATE SET results = excluded.results, \
+             expires_at = excluded.expires_at",
+        )
+        .bind(search_tags)
+        .bind(serde_json::to_string(&results).unwrap())
+        .bind(now() + self.cache_ttl)
+        .execute(&mut *tx)
+        .await?;
+        for tag in tags {
+            sqlx::query(
+                "INSERT INTO search_tags (tag, search) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            )
+            .bind(tag)
+            .bind(search_tags)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn invalidate_tags(&self, tags: Vec<String>) -> Result<(), AppError> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("UPDATE search_generation SET generation = generation + 1 WHERE id = 1")
+            .execute(&mut *tx)
+            .await?;
+        for tag in tags {
+            sqlx::query(
+                "DELETE FROM searches WHERE search IN (SELECT search FROM search_tags WHERE tag = $1)",
+            )
+            .bind(&tag)
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query("DELETE FROM search_tags WHERE tag = $1")
+                .bind(&tag)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+fn weights(rows: Vec<(String, i64)>) -> Vec<(String, usize)> {
+    rows.into_iter()
+        .map(|(slug, weight)| (slug, weight as usize))
+        .collect()
+}
+
+// Unions run in the database, the fallbacks for intersections and weighted
+// sums build on them.
+impl SearchDb<String, String, PostEntity, AppError> for SqlRepository {
+    async fn get_item_refs_from_tag(&self, tag: String) -> Result<Vec<(String, usize)>, AppError> {
+        let rows =
+            sqlx::query_as::<_, (String, i64)>("SELECT slug, weight FROM post_tags WHERE tag = $1")
+                .bind(tag)
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(weights(rows))
+    }
+
+    async fn has_item_refs(&self, tags: Vec<String>) -> Result<bool, AppError> {
+        if tags.is_empty() {
+            return Ok(false);
+        }
+        let sql = format!(
+            "SELECT slug FROM post_tags WHERE tag IN ({}) LIMIT 1",
+            placeholders(1, tags.len())
+        );
+        let mut query = sqlx::query_scalar::<_, String>(&sql);
+        for tag in tags.iter() {
+            query = query.bind(tag);
+        }
+        Ok(query.fetch_optional(&self.pool).await?.is_some())
+    }
+
+    async fn get_item_refs_from_tags(
+        &self,
+        tags: Vec<String>,
+    ) -> Result<Vec<(String, usize)>, AppError> {
+        if tags.is_empty() {
+            return Ok(vec![]);
+        }
+        let sql = format!(
+            "SELECT slug, MAX(weight) FROM post_tags WHERE tag IN ({}) GROUP BY slug",
+            placeholders(1, tags.len())
+        );
+        let mut query = sqlx::query_as::<_, (String, i64)>(&sql);
+        for tag in tags.iter() {
+            query = query.bind(tag);
+        }
+        Ok(weights(query.fetch_all(&self.pool).await?))
+    }
+
+    async fn get_item_from_ref(&self, slug: String) -> Result<PostEntity, AppError> {
+        let row = sqlx::query(&format!("SELECT {POST_COLUMNS} FROM posts WHERE slug = $1"))
+            .bind(&slug)
+            .fetch_optional(&self.pool)
+            .await?
+            .ok_or(AppError::new(
+                ErrorKind::NotFound,
+                format!("no post {slug}"),
+            ))?;
+        let search_tags = sqlx::query_scalar::<_, String>(
+            "SELECT tag FROM post_search_tags WHERE slug = $1 ORDER BY position",
+        )
+        .bind(&slug)
+        .fetch_all(&self.pool)
+        .await?;
+        post_from_row(&row, search_tags)
+    }
+
+    async fn get_tags_from_phrase(&self, phrase: &str) -> Result<Vec<String>, AppError> {
+        let tags = sqlx::query_scalar::<_, String>("SELECT tag FROM aliases WHERE phrase = $1")
+            .bind(phrase)
+            .fetch_all(&self.pool)
+            .await?;
+        if tags.is_empty() {
+            return Ok(vec![phrase.to_string()]);
+        }
+        Ok(tags)
+    }
+
+    async fn get_tags_from_field(&self, field: &str, value: &str) -> Result<Vec<String>, AppError> {
+        Ok(tags_from_field(field, value))
+    }
+
+    async fn get_similar_tags(&self, word: &str) -> Result<Vec<String>, AppError> {
+        let grams = bigrams(word);
+        if grams.is_empty() {
+            return Ok(vec![]);
+        }
+        let sql = format!(
+            "SELECT DISTINCT tag FROM tag_grams WHERE gram IN ({})",
+            placeholders(1, grams.len())
+        );
+        let mut query = sqlx::query_scalar::<_, String>(&sql);
+        for gram in grams.iter() {
+            query = query.bind(gram);
+        }
+        Ok(query
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .filter(|tag| is_typo_of(tag, word))
+            .collect())
+    }
+
+    async fn get_items_recency(&self, slugs: Vec<String>) -> Result<Vec<i64>, AppError> {
+        if slugs.is_empty() {
+            return Ok(vec![]);
+        }
+        let sql = format!(
+            "SELECT slug, created_at FROM posts WHERE slug IN ({})",
+            placeholders(1, slugs.len())
+        );
+        let mut query = sqlx::query_as::<_, (String, String)>(&sql);
+        for slug in slugs.iter() {
+            query = query.bind(slug);
+        }
+        let dates = query
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(|(slug, date)| Ok((slug, parse_date(&date)?.timestamp())))
+            .collect::<Result<HashMap<_, _>, AppError>>()?;
+        Ok(slugs
+            .iter()
+            .map(|slug| dates.get(slug).cloned().unwrap_or(0))
+            .collect())
+    }
+
+    async fn get_corpus_stats(&self) -> Result<CorpusStats, AppError> {
+        let (docs, length) = sqlx::query_as::<_, (i64, i64)>(
+            "SELECT COUNT(DISTINCT slug), CAST(COALESCE(SUM(frequency), 0) AS BIGINT) \
+             FROM term_frequencies",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(CorpusStats {
+            docs: docs as usize,
+            length: length as usize,
+        })
+    }
+
+    async fn get_doc_frequencies(&self, tags: Vec<String>) -> Result<Vec<usize>, AppError> {
+        if tags.is_empty() {
+            return Ok(vec![]);
+        }
+        let sql = format!(
+            "SELECT tag, COUNT(*) FROM term_frequencies WHERE tag IN ({}) GROUP BY tag",
+            placeholders(1, tags.len())
+        );
+        let mut query = sqlx::query_as::<_, (String, i64)>(&sql);
+        for tag in tags.iter() {
+            query = query.bind(tag);
+        }
+        let counts: HashMap<_, _> = query.fetch_all(&self.pool).await?.into_iter().collect();
+        Ok(tags
+            .iter()
+            .map(|tag| counts.get(tag).cloned().unwrap_or(0) as usize)
+            .collect())
+    }
+
+    async fn get_term_frequencies(
+        &self,
+        slugs: Vec<String>,
+        tags: Vec<String>,
+    ) -> Result<Vec<DocStats>, AppError> {
+        if slugs.is_empty() || tags.is_empty() {
+            return Ok(slugs.iter().map(|_| DocStats::default()).collect());
+        }
+        let sql = format!(
+            "SELECT slug, tag, frequency FROM term_frequencies WHERE slug IN ({})",
+            placeholders(1, slugs.len())
+        );
+        let mut query = sqlx::query_as::<_, (String, String, i64)>(&sql);
+        for slug in slugs.iter() {
+            query = query.bind(slug);
+        }
+        let mut terms: HashMap<String, HashMap<String, usize>> = HashMap::new();
+        for (slug, tag, frequency) in query.fetch_all(&self.pool).await? {
+            terms
+                .entry(slug)
+                .or_default()
+                .insert(tag, frequency as usize);
+        }
+        Ok(slugs
+            .iter()
+            .map(|slug| match terms.get(slug) {
+                Some(terms) => DocStats {
+                    length: terms.values().sum(),
+                    frequencies: tags
+                        .iter()
+                        .map(|tag| terms.get(tag).cloned().unwrap_or(0))
+                        .collect(),
+                },
+                None => DocStats::default(),
+            })
+            .collect())
+    }
+}
+
+impl ItemRepo<String, String, PostEntity, AppError> for SqlRepository {
+    fn get_cache(&self) -> impl SearchCache<String, String, AppError> {
+        self.clone()
+    }
+
+    fn get_db(&self) -> impl SearchDb<String, String, PostEntity, AppError> {
+        self.clone()
+    }
+
+    fn get_scorer(&self) -> impl Scorer<String, String, PostEntity, AppError> {
+        self.scoring
+    }
+}
+
+// Every write is a transaction starting with a write, so that concurrent
+// ones wait for each other on SQLite rather than failing to upgrade a read.
+impl InsertHandle<String, String, PostEntity, AppError> for SqlRepository {
+    async fn insert_tags(
+        &self,
+        tags: Vec<(String, usize)>,
+        item_ref: String,
+    ) -> Result<(), AppError> {
+        let mut tx = self.pool.begin().await?;
+        write_tags(&mut tx, &tags, &item_ref).await?;
+        tx.commit().await?;
+        self.invalidate_tags(tags.into_iter().map(|(tag, _)| tag).collect())
+            .await
+    }
+
+    async fn remove_tags(&self, tags: Vec<String>, item_ref: String) -> Result<(), AppError> {
+        let mut tx = self.pool.begin().await?;
+        delete_tags(&mut tx, &tags, &item_ref).await?;
+        tx.commit().await?;
+        self.invalidate_tags(tags).await
+    }
+
+    // The rows of the post go with it.
+    async fn remove_item(&self, item_ref: String) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM posts WHERE slug = $1")
+            .bind(&item_ref)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn insert_item(&self, item: PostEntity) -> Result<(), AppError> {
+        let mut tx = self.pool.begin().await?;
+        write_post(&mut tx, &item, UPSERT_POST).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn insert_new_item(&self, item: PostEntity) -> Result<bool, AppError> {
+        let mut tx = self.pool.begin().await?;
+        if !write_post(&mut tx, &item, "ON CONFLICT (slug) DO NOTHING").await? {
+            tx.rollback().await?;
+            return Ok(false);
+        }
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn insert_term_stats(
+        &self,
+        item_ref: String,
+        terms: Vec<(String, usize)>,
+    ) -> Result<(), AppError> {
+        let mut tx = self.pool.begin().await?;
+        write_term_stats(&mut tx, &item_ref, &terms).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn remove_term_stats(&self, item_ref: String) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM term_frequencies WHERE slug = $1")
+            .bind(item_ref)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn insert_alias(&self, phrase: String, tags: Vec<String>) -> Result<(), AppError> {
+        let mut tx = self.pool.begin().await?;
+        for tag in tags {
+            sqlx::query("INSERT INTO aliases (phrase, tag) VALUES ($1, $2) ON CONFLICT DO NOTHING")
+                .bind(&phrase)
+                .bind(tag)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn remove_alias(&self, phrase: String) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM aliases WHERE phrase = $1")
+            .bind(phrase)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn store_indexed_item(
+        &self,
+        item_ref: String,
+        item: PostEntity,
+        stale_tags: Vec<String>,
+        tags: Vec<(String, usize)>,
+        terms: Vec<(String, usize)>,
+    ) -> Result<(), AppError> {
+        let mut tx = self.pool.begin().await?;
+        write_post(&mut tx, &item, UPSERT_POST).await?;
+        delete_tags(&mut tx, &stale_tags, &item_ref).await?;
+        write_tags(&mut tx, &tags, &item_ref).await?;
+        write_term_stats(&mut tx, &item_ref, &terms).await?;
+        tx.commit().await?;
+        let changed = stale_tags
+            .into_iter()
+            .chain(tags.into_iter().map(|(tag, _)| tag))
+            .collect();
+        self.invalidate_tags(changed).await
+    }
+}
+
+impl PowValidator for SqlRepository {
+    async fn is_valid_pow(&self, challenges: Vec<String>, valid_seconds: u64) -> bool {
+        let _ = sqlx::query("DELETE FROM pow_challenges WHERE expires_at <= $1")
+            .bind(now())
+            .execute(&self.pool)
+            .await;
+        for challenge in challenges {
+            if Pow::validate(&challenge).is_err() {
+                return false;
+            }
+            // Each proof is good for a single use.
+            let inserted = sqlx::query(
+                "INSERT INTO pow_challenges (challenge, expires_at) VALUES ($1, $2) \
+                 ON CONFLICT DO NOTHING",
+            )
+            .bind(challenge)
+            .bind(now() + valid_seconds as i64)
+            .execute(&self.pool)
+            .await
+            .map(|done| done.rows_affected() > 0)
+            .unwrap_or(false);
+            if !inserted {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl UserStore for SqlRepository {
+    async fn get_user(&self, user_id: UserId) -> Result<UserEntity, AppError> {
+        let id = user_id.to_string();
+        let (handle, password_hash, version) = sqlx::query_as::<_, (String, String, i64)>(
+            "SELECT handle, password_hash, version FROM users WHERE id = $1",
+        )
+        .bind(&id)
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or(AppError::new(
+            ErrorKind::NotFound,
+            format!("no user {user_id}"),
+        ))?;
+        let (groups, block_list, blocked_by) = futures::try_join!(
+            self.get_ids(
+                "SELECT group_id FROM user_groups WHERE user_id = $1 ORDER BY position",
+                &id
+            ),
+            self.get_ids(
+                "SELECT blocked_id FROM user_blocks WHERE user_id = $1 ORDER BY position",
+                &id
+            ),
+            // Derived from the other side's block list, so both always agree.
+            self.get_ids(
+                "SELECT user_id FROM user_blocks WHERE blocked_id = $1 ORDER BY user_id",
+                &id
+            )
+        )?;
+        Ok(UserEntity {
+            id: user_id,
+            handle,
+            password_hash,
+            groups,
+            block_list,
+            blocked_by,
+            version: version as u64,
+        })
+    }
+
+    async fn get_user_id_from_handle(&self, handle: &str) -> Result<Option<UserId>, AppError> {
+        let user_id = sqlx::query_scalar::<_, String>("SELECT id FROM users WHERE handle = $1")
+            .bind(handle)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(user_id.and_then(|id| id.parse().ok()))
+    }
+
+    async fn insert_user(&self, user: UserEntity) -> Result<bool, AppError> {
+        let id = user.id.to_string();
+        let mut tx = self.pool.begin().await?;
+        // The unique handle keeps two sign-ups from racing for it.
+        let inserted = sqlx::query(
+            "INSERT INTO users (id, handle, password_hash, version) VALUES ($1, $2, $3, $4) \
+             ON CONFLICT DO NOTHING",
+        )
+        .bind(&id)
+        .bind(&user.handle)
+        .bind(&user.password_hash)
+        .bind(user.version as i64)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            > 0;
+        if !inserted {
+            tx.rollback().await?;
+            return Ok(false);
+        }
+        write_id_list(
+            &mut tx,
+            "user_groups",
+            ("user_id", "group_id"),
+            &id,
+            &user.groups,
+        )
+        .await?;
+        write_id_list(
+            &mut tx,
+            "user_blocks",
+            ("user_id", "blocked_id"),
+            &id,
+            &user.block_list,
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    // The row only changes from the version the user was read at.
+    async fn save_user(&self, user: UserEntity) -> Result<bool, AppError> {
+        let id = user.id.to_string();
+        let mut tx = self.pool.begin().await?;
+        let saved = sqlx::query(
+            "UPDATE users SET handle = $1, password_hash = $2, version = version + 1 \
+             WHERE id = $3 AND version = $4",
+        )
+        .bind(&user.handle)
+        .bind(&user.password_hash)
+        .bind(&id)
+        .bind(user.version as i64)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            > 0;
+        if !saved {
+            tx.rollback().await?;
+            return Ok(false);
+        }
+        write_id_list(
+            &mut tx,
+            "user_groups",
+            ("user_id", "group_id"),
+            &id,
+            &user.groups,
+        )
+        .await?;
+        write_id_list(
+            &mut tx,
+            "user_blocks",
+            ("user_id", "blocked_id"),
+            &id,
+            &user.block_list,
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn insert_session(&self, token: &str, user_id: UserId, ttl: u64) -> Result<(), AppError> {
+        sqlx::query(
+            "INSERT INTO sessions (token, user_id, expires_at) VALUES ($1, $2, $3) \
+             ON CONFLICT (token) DO UPDATE SET user_id = excluded.user_id, \
+             expires_at = excluded.expires_at",
+        )
+        .bind(token)
+        .bind(user_id.to_string())
+        .bind(now() + ttl as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_session(&self, token: &str) -> Result<Option<UserId>, AppError> {
+        let user_id = sqlx::query_scalar::<_, String>(
+            "SELECT user_id FROM sessions WHERE token = $1 AND expires_at > $2",
+        )
+        .bind(token)
+        .bind(now())
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(user_id.and_then(|id| id.parse().ok()))
+    }
+}
+
+impl AuthorStore for SqlRepository {
+    async fn get_author(&self, author_id: &str) -> Result<Option<AuthorEntity>, AppError> {
+        Ok(self
+            .get_authors(vec![author_id.to_string()])
+            .await?
+            .pop()
+            .flatten())
+    }
+
+    async fn get_authors(
+        &self,
+        author_ids: Vec<AuthorId>,
+    ) -> Result<Vec<Option<AuthorEntity>>, AppError> {
+        if author_ids.is_empty() {
+            return Ok(vec![]);
+        }
+        let sql = format!(
+            "SELECT author_id, name, profile_picture FROM authors WHERE author_id IN ({})",
+            placeholders(1, author_ids.len())
+        );
+        let mut query = sqlx::query_as::<_, (String, String, String)>(&sql);
+        for author_id in author_ids.iter() {
+            query = query.bind(author_id);
+        }
+        let authors: HashMap<_, _> = query
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(|(author_id, name, profile_picture)| {
+                (
+                    author_id.clone(),
+                    AuthorEntity {
+                        author_id,
+                        name,
+                        profile_picture,
+                    },
+                )
+            })
+            .collect();
+        Ok(author_ids
+            .iter()
+            .map(|author_id| authors.get(author_id).cloned())
+            .collect())
+    }
+
+    async fn insert_author(&self, author: AuthorEntity) -> Result<bool, AppError> {
+        Ok(sqlx::query(
+            "INSERT INTO authors (author_id, name, profile_picture) VALUES ($1, $2, $3) \
+             ON CONFLICT DO NOTHING",
+        )
+        .bind(&author.author_id)
+        .bind(&author.name)
+        .bind(&author.profile_picture)
+        .execute(&self.pool)
+        .await?
+        .rows_affected()
+            > 0)
+    }
+
+    async fn remove_author(&self, author_id: &str) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM authors WHERE author_id = $1")
+            .bind(author_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn insert_author_post(&self, author_id: &str, item_ref: String) -> Result<(), AppError> {
+        sqlx::query(
+            "INSERT INTO author_posts (author_id, slug) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        )
+        .bind(author_id)
+        .bind(item_ref)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn remove_author_post(&self, author_id: &str, item_ref: String) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM author_posts WHERE author_id = $1 AND slug = $2")
+            .bind(author_id)
+            .bind(item_ref)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_author_post_refs(&self, author_id: &str) -> Result<Vec<String>, AppError> {
+        Ok(
+            sqlx::query_scalar::<_, String>("SELECT slug FROM author_posts WHERE author_id = $1")
+                .bind(author_id)
+                .fetch_all(&self.pool)
+                .await?,
+        )
+    }
+}
+
+// Roles of the rows of `group_members`, admins are members too.
+const ADMIN: &str = "admin";
+const MEMBER: &str = "member";
+const INVITED: &str = "invited";
+
+impl GroupStore for SqlRepository {
+    async fn get_group(&self, group_id: GroupId) -> Result<GroupEntity, AppError> {
+        let id = group_id.to_string();
+        let (name, management, allow_member_posting, face, version) =
+            sqlx::query_as::<_, (String, String, i64, Option<String>, i64)>(
+                "SELECT name, management, allow_member_posting, face, version \
+                 FROM groups WHERE id = $1",
+            )
+            .bind(&id)
+            .fetch_optional(&self.pool)
+            .await?
+            .ok_or(AppError::new(
+                ErrorKind::NotFound,
+                format!("no group {group_id}"),
+            ))?;
+        let mut roles: HashMap<String, Vec<String>> = HashMap::new();
+        for (role, user_id) in sqlx::query_as::<_, (String, String)>(
+            "SELECT role, user_id FROM group_members WHERE group_id = $1 ORDER BY position",
+        )
+        .bind(&id)
+        .fetch_all(&self.pool)
+        .await?
+        {
+            roles.entry(role).or_default().push(user_id);
+        }
+        let mut members = |role: &str| parse_ids(roles.remove(role).unwrap_or_default());
+        Ok(GroupEntity {
+            id: group_id,
+            name,
+            management: serde_json::from_value(serde_json::Value::String(management)).map_err(
+                |err| AppError::new(ErrorKind::Internal, format!("bad group management: {err}")),
+            )?,
+            allow_member_posting: allow_member_posting != 0,
+            face,
+            admins: members(ADMIN)?,
+            members: members(MEMBER)?,
+            invited: members(INVITED)?,
+            version: version as u64,
+        })
+    }
+
+    // The row only changes from the version the group was read at, a new
+    // group being at version 0 until inserted.
+    async fn save_group(&self, group: GroupEntity) -> Result<bool, AppError> {
+        let id = group.id.to_string();
+        let management = serde_json::to_value(&group.management).unwrap();
+        let mut tx = self.pool.begin().await?;
+        let saved = sqlx::query(
+            "INSERT INTO groups (id, name, management, allow_member_posting, face, version) \
+             VALUES ($1, $2, $3, $4, $5, $6 + 1) \
+             ON CONFLICT (id) DO UPDATE SET name = excluded.name, \
+             management = excluded.management, \
+             allow_member_posting = excluded.allow_member_posting, face = excluded.face, \
+             version = excluded.version WHERE groups.version = $6",
+        )
+        .bind(&id)
+        .bind(&group.name)
+        .bind(management.as_str().unwrap_or_default().to_string())
+        .bind(group.allow_member_posting as i64)
+        .bind(group.face.clone())
+        .bind(group.version as i64)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            > 0;
+        if !saved {
+            tx.rollback().await?;
+            return Ok(false);
+        }
+        sqlx::query("DELETE FROM group_members WHERE group_id = $1")
+            .bind(&id)
+            .execute(&mut *tx)
+            .await?;
+        for (role, user_ids) in [
+            (ADMIN, &group.admins),
+            (MEMBER, &group.members),
+            (INVITED, &group.invited),
+        ] {
+            for (position, user_id) in user_ids.iter().enumerate() {
+                sqlx::query(
+                    "INSERT INTO group_members (group_id, role, user_id, position) \
+                     VALUES ($1, $2, $3, $4) ON CONFLICT DO NOTHING",
+                )
+                .bind(&id)
+                .bind(role)
+                .bind(user_id.to_string())
+                .bind(position as i64)
+                .execute(&mut *tx)
+                .await?;
+            }
+        }
+        tx.commit().await?;
+        Ok(true)
+    }
+}
+
+impl ThreadStore for SqlRepository {
+    async fn insert_reply(&self, parent_ref: &str, item_ref: String) -> Result<(), AppError> {
+        sqlx::query(
+            "INSERT INTO replies (parent, slug, position) \
+             SELECT $1, $2, COALESCE(MAX(position), 0) + 1 FROM replies WHERE parent = $1 \
+             ON CONFLICT DO NOTHING",
+        )
+        .bind(parent_ref)
+        .bind(item_ref)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn remove_reply(&self, parent_ref: &str, item_ref: String) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM replies WHERE parent = $1 AND slug = $2")
+            .bind(parent_ref)
+            .bind(item_ref)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_replies(&self, parent_ref: &str) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM replies WHERE parent = $1")
+            .bind(parent_ref)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_reply_refs(&self, parent_ref: &str) -> Result<Vec<String>, AppError> {
+        Ok(sqlx::query_scalar::<_, String>(
+            "SELECT slug FROM replies WHERE parent = $1 ORDER BY position, slug",
+        )
+        .bind(parent_ref)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+}
+
+impl AliasStore for SqlRepository {
+    async fn get_aliases(&self) -> Result<Vec<Alias>, AppError> {
+        let rows = sqlx::query_as::<_, (String, String)>(
+            "SELECT phrase, tag FROM aliases ORDER BY phrase, tag",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        let mut aliases: Vec<Alias> = vec![];
+        for (phrase, tag) in rows {
+            match aliases.last_mut() {
+                Some(alias) if alias.phrase == phrase => alias.tags.push(tag),
+                _ => aliases.push(Alias {
+                    phrase,
+                    tags: vec![tag],
+                }),
+            }
+        }
+        Ok(aliases)
+    }
+}
+
+// Prefixes are compared on a substring rather than with LIKE, which would
+// need '%' and '_' escaped, or a range, which depends on the collation.
+impl SuggestStore for SqlRepository {
+    async fn get_tag_completions(
+        &self,
+        prefix: &str,
+        limit: usize,
+    ) -> Result<Vec<(String, usize)>, AppError> {
+        let rows = sqlx::query_as::<_, (String, i64)>(
+            "SELECT word, COUNT(*) FROM post_words \
+             WHERE substr(word, 1, CAST($1 AS INTEGER)) = $2 \
+             GROUP BY word ORDER BY COUNT(*) DESC, word LIMIT $3",
+        )
+        .bind(prefix.chars().count() as i64)
+        .bind(prefix)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(weights(rows))
+    }
+
+    async fn get_title_completions(
+        &self,
+        prefix: &str,
+        limit: usize,
+    ) -> Result<Vec<TitleSuggestion>, AppError> {
+        let rows = sqlx::query_as::<_, (String, String)>(
+            "SELECT slug, title FROM posts \
+             WHERE substr(title_key, 1, CAST($1 AS INTEGER)) = $2 \
+             ORDER BY title_key, slug LIMIT $3",
+        )
+        .bind(prefix.chars().count() as i64)
+        .bind(prefix)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(slug, title)| TitleSuggestion { title, slug })
+            .collect())
+    }
+}
+
+impl Backend for SqlRepository {
+    fn get_pow_validator(&self) -> impl PowValidator + Send + Sync {
+        self.clone()
+    }
+
+    fn get_user_store(&self) -> impl UserStore {
+        self.clone()
+    }
+
+    fn get_author_store(&self) -> impl AuthorStore {
+        self.clone()
+    }
+
+    fn get_group_store(&self) -> impl GroupStore {
+        self.clone()
+    }
+
+    fn get_thread_store(&self) -> impl ThreadStore {
+        self.clone()
+    }
+
+    fn get_suggest_store(&self) -> impl SuggestStore {
+        self.clone()
+    }
+
+    fn get_alias_store(&self) -> impl AliasStore {
+        self.clone()
+    }
+
+    fn render_metrics(&self) -> String {
+        self.metrics.render()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::tests;
+
+    // A database file of its own, as every pooled connection to an in-memory
+    // SQLite database would get a different one.
+    async fn test_db() -> SqlRepository {
+        let path = std::env::temp_dir().join(format!("ribbit-{}.db", Uuid::new_v4()));
+        SqlRepository::connect(&format!("sqlite://{}?mode=rwc", path.display()))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_post_round_trip() {
+        tests::post_round_trip(test_db().await).await;
+    }
+
+    #[tokio::test]
+    async fn test_search_follows_edits() {
+        tests::search_follows_edits(test_db().await).await;
+    }
+
+    #[tokio::test]
+    async fn test_tag_completions_ranked_by_use() {
+        tests::tag_completions_ranked_by_use(test_db().await).await;
+    }
+
+    #[tokio::test]
+    async fn test_dangling_replies_are_skipped() {
+        tests::dangling_replies_are_skipped(test_db().await).await;
+    }
+
+    #[tokio::test]
+    async fn test_users_and_groups() {
+        tests::users_and_groups(test_db().await).await;
+    }
+}
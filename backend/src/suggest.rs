@@ -0,0 +1,138 @@
+use serde::Serialize;
+
+use crate::schemas::AppError;
+use crate::tokenizer::fold;
+
+// Completions returned for a prefix, of each kind.
+pub const SUGGEST_MAX: usize = 8;
+
+pub trait SuggestStore
+where
+    Self: Sync + Send,
+{
+    // Words of public thread roots starting with the prefix, folded but not
+    // stemmed, with the number of posts using each. The most used come first,
+    // alphabetically among equals, ranked across every word matching.
+    fn get_tag_completions(
+        &self,
+        prefix: &str,
+        limit: usize,
+    ) -> impl std::future::Future<Output = Result<Vec<(String, usize)>, AppError>> + std::marker::Send;
+    // Titles of public posts whose folded form starts with the prefix, with their slugs.
+    fn get_title_completions(
+        &self,
+        prefix: &str,
+        limit: usize,
+    ) -> impl std::future::Future<Output = Result<Vec<TitleSuggestion>, AppError>> + std::marker::Send;
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TitleSuggestion {
+    pub title: String,
+    pub slug: String,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Suggestions {
+    pub tags: Vec<String>,
+    pub titles: Vec<TitleSuggestion>,
+}
+
+// Tags complete the last word being typed, titles the whole query.
+pub async fn suggest(store: &impl SuggestStore, query: &str) -> Result<Suggestions, AppError> {
+    let prefix = fold(query);
+    if prefix.is_empty() {
+        return Ok(Suggestions {
+            tags: vec![],
+            titles: vec![],
+        });
+    }
+    let last_word = prefix.rsplit(' ').next().unwrap_or_default();
+    let (tags, titles) = tokio::try_join!(
+        store.get_tag_completions(last_word, SUGGEST_MAX),
+        store.get_title_completions(&prefix, SUGGEST_MAX)
+    )?;
+    Ok(Suggestions {
+        tags: tags.into_iter().map(|(tag, _)| tag).collect(),
+        titles,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::memory::InMemoryRepository;
+    use crate::schemas::PostEntity;
+    use crate::services::register_post;
+    use crate::tokenizer::Language;
+
+    struct TestStore {}
+
+    impl SuggestStore for TestStore {
+        async fn get_tag_completions(
+            &self,
+            prefix: &str,
+            limit: usize,
+        ) -> Result<Vec<(String, usize)>, AppError> {
+            assert_eq!((prefix, limit), ("bu", SUGGEST_MAX));
+            Ok(vec![
+                ("butter".to_string(), 5),
+                ("bulb".to_string(), 1),
+                ("bun".to_string(), 1),
+            ])
+        }
+        async fn get_title_completions(
+            &self,
+            prefix: &str,
+            _limit: usize,
+        ) -> Result<Vec<TitleSuggestion>, AppError> {
+            assert_eq!(prefix, "creme bu");
+            Ok(vec![])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tags_complete_the_last_word() {
+        let found = suggest(&TestStore {}, "Crème  BU").await.unwrap();
+
+        assert_eq!(found.tags, vec!["butter", "bulb", "bun"]);
+    }
+
+    #[tokio::test]
+    async fn test_only_public_words_are_suggested() {
+        let db = InMemoryRepository::default();
+        let post = |title: &str, body: &str| PostEntity {
+            title: title.to_string(),
+            slug: slug::slugify(title),
+            author: "alice".to_string(),
+            search_tags: vec![],
+            body: body.to_string(),
+            space: None,
+            reply_scope: None,
+            visibility_scope: None,
+            parent: None,
+            publisher: None,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            lang: Language::En,
+        };
+        register_post(db.clone(), &db, post("Baking bread", "<p>Bakes well.</p>"))
+            .await
+            .unwrap();
+        let secret = PostEntity {
+            visibility_scope: Some(uuid::Uuid::new_v4()),
+            ..post("Secret buttermilk", "<p>Baked for members.</p>")
+        };
+        register_post(db.clone(), &db, secret).await.unwrap();
+
+        // Words are suggested as written, not as the stems they are indexed under.
+        assert_eq!(
+            suggest(&db, "ba").await.unwrap().tags,
+            vec!["bakes", "baking"]
+        );
+        assert!(suggest(&db, "butter").await.unwrap().tags.is_empty());
+        assert!(suggest(&db, "secr").await.unwrap().tags.is_empty());
+    }
+}
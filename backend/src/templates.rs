@@ -1,4 +1,8 @@
+pub const AUTHOR_TPL: &str = include_str!("templates/author.html");
 pub const HOME_TPL: &str = include_str!("templates/home.html");
 pub const LIST_TPL: &str = include_str!("templates/list.html");
 pub const POST_TPL: &str = include_str!("templates/post.html");
+pub const REPLY_TPL: &str = include_str!("templates/reply.html");
 pub const PUBLISH_TPL: &str = include_str!("templates/publish.html");
+pub const SIGN_IN_TPL: &str = include_str!("templates/sign-in.html");
+pub const SIGN_UP_TPL: &str = include_str!("templates/sign-up.html");
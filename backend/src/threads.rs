@@ -0,0 +1,420 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::Utc;
+use futures::future::try_join_all;
+
+use crate::authorization::{can_reply, can_view};
+use crate::authors::{resolve_authors, AuthorStore};
+use crate::blocking::{avoids_author, avoids_publisher};
+use crate::indexing::InsertHandle;
+use crate::schemas::{AppError, AuthorEntity, AuthorId, ErrorKind, Post, PostEntity, UserEntity};
+use crate::search::{ItemRepo, SearchDb};
+use crate::users::UserStore;
+
+// Replies nest at most this deep under the thread root, deeper ones are refused.
+pub const MAX_THREAD_DEPTH: usize = 8;
+
+pub trait ThreadStore
+where
+    Self: Sync + Send,
+{
+    fn insert_reply(
+        &self,
+        parent_ref: &str,
+        item_ref: String,
+    ) -> impl std::future::Future<Output = Result<(), AppError>> + std::marker::Send;
+    fn remove_reply(
+        &self,
+        parent_ref: &str,
+        item_ref: String,
+    ) -> impl std::future::Future<Output = Result<(), AppError>> + std::marker::Send;
+    // Forgets every reply to the post, leaving the replies themselves.
+    fn remove_replies(
+        &self,
+        parent_ref: &str,
+    ) -> impl std::future::Future<Output = Result<(), AppError>> + std::marker::Send;
+    // Replies in the order they were posted.
+    fn get_reply_refs(
+        &self,
+        parent_ref: &str,
+    ) -> impl std::future::Future<Output = Result<Vec<String>, AppError>> + std::marker::Send;
+}
+
+// How deep the post is in its thread, the root being at 0. Stops counting
+// past MAX_THREAD_DEPTH.
+async fn thread_depth(
+    db: &impl SearchDb<String, String, PostEntity, AppError>,
+    post: &PostEntity,
+) -> Result<usize, AppError> {
+    let mut depth = 0;
+    let mut parent = post.parent.clone();
+    while let Some(parent_slug) = parent {
+        depth += 1;
+        if depth > MAX_THREAD_DEPTH {
+            break;
+        }
+        parent = db.get_item_from_ref(parent_slug).await?.parent;
+    }
+    Ok(depth)
+}
+
+pub async fn reply_to_post(
+    db: &(impl ItemRepo<String, String, PostEntity, AppError>
+          + InsertHandle<String, String, PostEntity, AppError>),
+    threads: &impl ThreadStore,
+    users: &impl UserStore,
+    user: &UserEntity,
+    parent_slug: String,
+    body: &str,
+) -> Result<PostEntity, AppError> {
+    let parent = db.get_db().get_item_from_ref(parent_slug.clone()).await?;
+    if !can_view(&parent, Some(user))
+        || avoids_publisher(Some(user), &parent)
+        || avoids_author(users, user, &parent.author).await?
+    {
+        return Err(AppError::new(
+            ErrorKind::NotFound,
+            format!("no post {parent_slug}"),
+        ));
+    }
+    if !can_reply(&parent, Some(user)) {
+        return Err(AppError::new(
+            ErrorKind::Forbidden,
+            "not allowed to reply to this post",
+        ));
+    }
+    if thread_depth(&db.get_db(), &parent).await? >= MAX_THREAD_DEPTH {
+        return Err(AppError::new(
+            ErrorKind::BadRequest,
+            "the thread is too deep to reply here",
+        ));
+    }
+    let reply_id = uuid::Uuid::new_v4().simple().to_string();
+    let now = Utc::now();
+    // Replies keep the scopes of the thread, and are neither indexed for
+    // search nor listed on their author's profile.
+    let reply = PostEntity {
+        title: parent.title.clone(),
+        slug: format!("{}-{}", parent.slug, &reply_id[..8]),
+        author: user.handle.clone(),
+        search_tags: vec![],
+        body: markdown::to_html(body),
+        space: parent.space,
+        reply_scope: parent.reply_scope,
+        visibility_scope: parent.visibility_scope,
+        parent: Some(parent.slug.clone()),
+        publisher: Some(user.id),
+        created_at: now,
+        updated_at: now,
+        lang: parent.lang,
+    };
+    if !db.insert_new_item(reply.clone()).await? {
+        return Err(AppError::new(
+            ErrorKind::Conflict,
+            format!("reply {} already exists", reply.slug),
+        ));
+    }
+    threads
+        .insert_reply(&parent.slug, reply.slug.clone())
+        .await?;
+    Ok(reply)
+}
+
+pub async fn find_replies(
+    db: &impl SearchDb<String, String, PostEntity, AppError>,
+    authors: &impl AuthorStore,
+    threads: &impl ThreadStore,
+    viewer: Option<&UserEntity>,
+    avoided: &HashSet<AuthorId>,
+    root_slug: String,
+) -> Result<Vec<Post>, AppError> {
+    // Fetch the thread level by level, then assemble it once every author is known.
+    let mut children: HashMap<String, Vec<PostEntity>> = HashMap::new();
+    let mut frontier = vec![root_slug.clone()];
+    for _depth in 0..MAX_THREAD_DEPTH {
+        if frontier.is_empty() {
+            break;
+        }
+        let reply_refs =
+            try_join_all(frontier.iter().map(|slug| threads.get_reply_refs(slug))).await?;
+        let mut next_frontier = vec![];
+        for (parent_slug, refs) in frontier.into_iter().zip(reply_refs) {
+            let replies: Vec<_> = try_join_all(refs.into_iter().map(|r| db.get_item_from_ref(r)))
+                .await?
+                .into_iter()
+                .filter(|reply| {
+                    can_view(reply, viewer)
+                        && !avoided.contains(&reply.author)
+                        && !avoids_publisher(viewer, reply)
+                })
+                .collect();
+            next_frontier.extend(replies.iter().map(|reply| reply.slug.clone()));
+            children.insert(parent_slug, replies);
+        }
+        frontier = next_frontier;
+    }
+
+    let resolved = resolve_authors(
+        authors,
+        children
+            .values()
+            .flatten()
+            .map(|reply| reply.author.clone()),
+    )
+    .await?;
+    Ok(build_replies(
+        &root_slug,
+        1,
+        &mut children,
+        &resolved,
+        viewer,
+    ))
+}
+
+fn build_replies(
+    slug: &str,
+    depth: usize,
+    children: &mut HashMap<String, Vec<PostEntity>>,
+    authors: &HashMap<String, AuthorEntity>,
+    viewer: Option<&UserEntity>,
+) -> Vec<Post> {
+    children
+        .remove(slug)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|reply| {
+            let reply_slug = reply.slug.clone();
+            let author = authors
+                .get(&reply.author)
+                .cloned()
+                .unwrap_or_else(|| AuthorEntity::unknown(reply.author.clone()));
+            let mut post = Post::from_store(reply, author, viewer);
+            post.can_reply &= depth < MAX_THREAD_DEPTH;
+            post.replies = build_replies(&reply_slug, depth + 1, children, authors, viewer);
+            post
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::body::{to_bytes, Body};
+    use axum::http::{header, Request, StatusCode};
+    use spow::pow::Pow;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::blocking::block_user;
+    use crate::config::{Config, PowConfig};
+    use crate::memory::InMemoryRepository;
+    use crate::services::{find_author_profile, find_post, find_posts, register_post};
+    use crate::tokenizer::Language;
+    use crate::users::sign_up;
+    use crate::{router, templates, Repositories};
+
+    async fn user(db: &InMemoryRepository, handle: &str) -> UserEntity {
+        let user = UserEntity {
+            id: uuid::Uuid::new_v4(),
+            handle: handle.to_string(),
+            password_hash: String::new(),
+            groups: vec![],
+            block_list: vec![],
+            blocked_by: vec![],
+            version: 0,
+        };
+        db.insert_user(user.clone()).await.unwrap();
+        user
+    }
+
+    async fn thread(db: &InMemoryRepository, author: &UserEntity) -> String {
+        let now = Utc::now();
+        register_post(
+            db.clone(),
+            db,
+            PostEntity {
+                title: "Brown butter cake".to_string(),
+                slug: "brown-butter-cake".to_string(),
+                author: author.handle.clone(),
+                search_tags: vec![],
+                body: "<p>Mix, then bake.</p>".to_string(),
+                space: None,
+                reply_scope: None,
+                visibility_scope: None,
+                parent: None,
+                publisher: Some(author.id),
+                created_at: now,
+                updated_at: now,
+                lang: Language::En,
+            },
+        )
+        .await
+        .unwrap()
+    }
+
+    async fn reply(
+        db: &InMemoryRepository,
+        user: &UserEntity,
+        parent_slug: &str,
+        body: &str,
+    ) -> Result<PostEntity, AppError> {
+        reply_to_post(db, db, db, user, parent_slug.to_string(), body).await
+    }
+
+    async fn read(db: &InMemoryRepository, viewer: &UserEntity, slug: &str) -> Post {
+        find_post(db.clone(), db, db, db, Some(viewer), slug.to_string())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_replies_nest_in_order() {
+        let db = InMemoryRepository::default();
+        let (alice, bob) = (user(&db, "alice").await, user(&db, "bob").await);
+        let root = thread(&db, &alice).await;
+        let first = reply(&db, &bob, &root, "Needs *salt*.").await.unwrap();
+        reply(&db, &alice, &root, "Thanks!").await.unwrap();
+        reply(&db, &alice, &first.slug, "How much?").await.unwrap();
+
+        let post = read(&db, &bob, &root).await;
+        assert_eq!(post.replies.len(), 2);
+        assert_eq!(post.replies[0].body, "<p>Needs <em>salt</em>.</p>\n");
+        assert_eq!(post.replies[0].author.handle, "bob");
+        assert_eq!(post.replies[0].replies[0].body, "<p>How much?</p>\n");
+        assert_eq!(post.replies[1].body, "<p>Thanks!</p>\n");
+        assert!(post.replies[1].replies.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_replies_stay_in_their_thread() {
+        let db = InMemoryRepository::default();
+        let (alice, bob) = (user(&db, "alice").await, user(&db, "bob").await);
+        let root = thread(&db, &alice).await;
+        reply(&db, &bob, &root, "Needs salt.").await.unwrap();
+        db.insert_author(AuthorEntity::unknown("bob".to_string()))
+            .await
+            .unwrap();
+
+        let found = find_posts(
+            db.clone(),
+            &db,
+            &db,
+            None,
+            "salt",
+            Default::default(),
+            1,
+            20,
+        )
+        .await
+        .unwrap();
+        assert_eq!(found.total_objects, 0);
+        let profile = find_author_profile(db.clone(), &db, &db, None, "bob".to_string(), 1, 20)
+            .await
+            .unwrap();
+        assert!(profile.posts.objects.is_empty());
+        assert!(db.get_author_post_refs("bob").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_thread_depth_is_capped() {
+        let db = InMemoryRepository::default();
+        let alice = user(&db, "alice").await;
+        let root = thread(&db, &alice).await;
+        let mut parent = root.clone();
+        for _ in 0..MAX_THREAD_DEPTH {
+            parent = reply(&db, &alice, &parent, "Again.").await.unwrap().slug;
+        }
+        let err = reply(&db, &alice, &parent, "Too deep.").await.unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadRequest);
+
+        // Every reply is rendered, the deepest without a reply form.
+        let mut post = read(&db, &alice, &root).await;
+        for depth in 1..=MAX_THREAD_DEPTH {
+            assert_eq!(post.replies.len(), 1, "at depth {depth}");
+            post = post.replies.remove(0);
+            assert_eq!(post.can_reply, depth < MAX_THREAD_DEPTH);
+        }
+        assert!(post.replies.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_replies_of_avoided_users_are_hidden() {
+        let db = InMemoryRepository::default();
+        let (alice, bob, carol) = (
+            user(&db, "alice").await,
+            user(&db, "bob").await,
+            user(&db, "carol").await,
+        );
+        let root = thread(&db, &alice).await;
+        let from_bob = reply(&db, &bob, &root, "Needs salt.").await.unwrap();
+        reply(&db, &carol, &from_bob.slug, "Agreed.").await.unwrap();
+        block_user(&db, &db, carol.clone(), "bob").await.unwrap();
+        let carol = db.get_user(carol.id).await.unwrap();
+
+        assert!(read(&db, &carol, &root).await.replies.is_empty());
+        assert_eq!(read(&db, &alice, &root).await.replies.len(), 1);
+        let err = reply(&db, &carol, &from_bob.slug, "Again.")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_reply_needs_pow() {
+        Pow::init_random().unwrap();
+        let db = InMemoryRepository::default();
+        let alice = user(&db, "alice").await;
+        let root = thread(&db, &alice).await;
+        let token = sign_up(&db, &db, "bob", "correct horse").await.unwrap();
+        let pow = PowConfig {
+            difficulty: 10,
+            challenges: 2,
+            valid_seconds: 60,
+        };
+        let app = router(Repositories {
+            db: db.clone(),
+            hb: templates(),
+            config: Arc::new(Config {
+                pow: pow.clone(),
+                ..Config::default()
+            }),
+        });
+        let post_reply = |challenges: Vec<String>| {
+            Request::post(format!("/en/post/{root}/reply"))
+                .header(header::COOKIE, format!("session={token}"))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    serde_json::json!({"body": "Needs salt.", "challenges": challenges})
+                        .to_string(),
+                ))
+                .unwrap()
+        };
+        let solved = || -> Vec<String> {
+            (0..pow.challenges)
+                .map(|_| {
+                    let challenge = Pow::with_difficulty(pow.difficulty, pow.valid_seconds)
+                        .unwrap()
+                        .to_string();
+                    Pow::work(&challenge).unwrap()
+                })
+                .collect()
+        };
+
+        let response = app.clone().oneshot(post_reply(vec![])).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let challenges = solved();
+        let response = app
+            .clone()
+            .oneshot(post_reply(challenges.clone()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let slug = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(String::from_utf8_lossy(&slug).starts_with(&root));
+        // Solved challenges are good for a single reply.
+        let response = app.oneshot(post_reply(challenges)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(db.get_reply_refs(&root).await.unwrap().len(), 1);
+    }
+}
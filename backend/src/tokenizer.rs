@@ -0,0 +1,220 @@
+use rust_stemmers::{Algorithm, Stemmer};
+use serde::{Deserialize, Serialize};
+use strsim::osa_distance;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Language {
+    #[default]
+    En,
+    Fr,
+}
+
+impl Language {
+    // Unknown codes fall back to English rather than failing the request.
+    pub fn from_code(code: &str) -> Self {
+        match code {
+            "fr" => Language::Fr,
+            _ => Language::En,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Language::En => "en",
+            Language::Fr => "fr",
+        }
+    }
+
+    fn stemmer(&self) -> Stemmer {
+        match self {
+            Language::En => Stemmer::create(Algorithm::English),
+            Language::Fr => Stemmer::create(Algorithm::French),
+        }
+    }
+
+    fn stopwords(&self) -> &'static [&'static str] {
+        match self {
+            Language::En => EN_STOPWORDS,
+            Language::Fr => FR_STOPWORDS,
+        }
+    }
+}
+
+// Stored folded, as they are compared against normalized words.
+const EN_STOPWORDS: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "from", "has", "have", "he",
+    "her", "his", "i", "if", "in", "into", "is", "it", "its", "of", "on", "or", "our", "s", "she",
+    "so", "t", "that", "the", "their", "them", "then", "there", "these", "they", "this", "to",
+    "was", "we", "were", "what", "when", "which", "who", "will", "with", "you", "your",
+];
+
+const FR_STOPWORDS: &[&str] = &[
+    "a", "au", "aux", "avec", "ce", "ces", "cette", "dans", "de", "des", "du", "elle", "en", "est",
+    "et", "eux", "il", "ils", "je", "l", "la", "le", "les", "leur", "lui", "ma", "mais", "me",
+    "mes", "moi", "mon", "ne", "nos", "notre", "nous", "on", "ou", "par", "pas", "pour", "qu",
+    "que", "qui", "s", "sa", "se", "ses", "son", "sur", "ta", "te", "tes", "toi", "ton", "tu",
+    "un", "une", "vos", "votre", "vous", "d", "j", "m", "n", "t", "c", "y",
+];
+
+// Lowercases, folds diacritics and strips punctuation, "Crème-Brûlée!" gives "cremebrulee".
+pub fn normalize(word: &str) -> String {
+    word.nfd()
+        .filter(|c| !is_combining_mark(*c))
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+// Normalized words kept in order, stopwords included, to match text by prefix.
+pub fn fold(text: &str) -> String {
+    text.unicode_words()
+        .map(normalize)
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Text content of rendered html, tags are replaced by spaces so words don't merge.
+pub fn strip_html(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => {
+                in_tag = false;
+                text.push(' ');
+            }
+            _ if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text.replace("&amp;", "&")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&nbsp;", " ")
+}
+
+// Lowercased words of the text, accents kept for the stemmer, stopwords dropped.
+fn words(text: &str, lang: Language) -> impl Iterator<Item = String> + '_ {
+    // Elisions are split too, so "l'été" gives "été".
+    text.unicode_words()
+        .flat_map(|word| word.split(['\'', '’']))
+        .map(|word| {
+            word.chars()
+                .filter(|c| c.is_alphanumeric())
+                .flat_map(char::to_lowercase)
+                .collect::<String>()
+        })
+        .filter(move |word| {
+            let folded = normalize(word);
+            !folded.is_empty() && !lang.stopwords().contains(&folded.as_str())
+        })
+}
+
+// Splits text into normalized words, dropping the stopwords of the language.
+pub fn tokenize(text: &str, lang: Language) -> Vec<String> {
+    words(text, lang).map(|word| normalize(&word)).collect()
+}
+
+// Tokenizes then stems, so "cakes" and "cake" give the same tag.
+// Used on both sides of the index, so posts and queries always agree on tags.
+pub fn analyze(text: &str, lang: Language) -> Vec<String> {
+    let stemmer = lang.stemmer();
+    words(text, lang)
+        .map(|word| normalize(&stemmer.stem(&word)))
+        .collect()
+}
+
+// Edits tolerated in a word, short words have to be spelled right.
+fn typo_budget(word: &str) -> usize {
+    match word.chars().count() {
+        0..=3 => 0,
+        4..=7 => 1,
+        _ => 2,
+    }
+}
+
+// Whether `candidate` is `word` with a few letters changed, added, dropped or swapped.
+pub fn is_typo_of(candidate: &str, word: &str) -> bool {
+    candidate != word && osa_distance(candidate, word) <= typo_budget(word)
+}
+
+// Letter pairs of the word, ends included, used to look up similar words.
+pub fn bigrams(word: &str) -> Vec<String> {
+    let chars: Vec<_> = format!("^{word}$").chars().collect();
+    let mut grams: Vec<String> = chars.windows(2).map(|w| w.iter().collect()).collect();
+    grams.sort();
+    grams.dedup();
+    grams
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_folds_and_strips() {
+        assert_eq!(normalize("Crème-Brûlée!"), "cremebrulee");
+        assert_eq!(normalize("ÉTÉ"), "ete");
+        assert_eq!(normalize("..."), "");
+    }
+
+    #[test]
+    fn test_strip_html_keeps_words_apart() {
+        assert_eq!(
+            tokenize(
+                &strip_html("<h1>Cake</h1><p>Salt &amp; <em>butter</em></p>"),
+                Language::En
+            ),
+            vec!["cake", "salt", "butter"]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_splits_on_punctuation() {
+        assert_eq!(
+            tokenize("Butter,flour & sugar: a recipe", Language::En),
+            vec!["butter", "flour", "sugar", "recipe"]
+        );
+    }
+
+    #[test]
+    fn test_analyze_stems_plurals() {
+        assert_eq!(
+            analyze("Cakes", Language::En),
+            analyze("cake", Language::En)
+        );
+        assert_eq!(
+            analyze("Les gâteaux", Language::Fr),
+            analyze("gâteau", Language::Fr)
+        );
+    }
+
+    #[test]
+    fn test_typos() {
+        assert!(is_typo_of("cake", "caek"));
+        assert!(is_typo_of("butter", "buter"));
+        assert!(!is_typo_of("cake", "cake"));
+        assert!(!is_typo_of("tea", "sea"));
+        assert!(!is_typo_of("butter", "bitten"));
+    }
+
+    #[test]
+    fn test_tokenize_language_stopwords() {
+        assert_eq!(
+            tokenize("La crème de l'été", Language::Fr),
+            vec!["creme", "ete"]
+        );
+        assert_eq!(
+            tokenize("La crème de l'été", Language::En),
+            vec!["la", "creme", "de", "l", "ete"]
+        );
+    }
+}
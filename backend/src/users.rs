@@ -0,0 +1,331 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use slug::slugify;
+use std::sync::LazyLock;
+
+use crate::authors::AuthorStore;
+use crate::groups::SAVE_ATTEMPTS;
+use crate::schemas::{AppError, AuthorEntity, ErrorKind, UserEntity, UserId};
+
+pub const SESSION_TTL: u64 = 30 * 24 * 3600;
+
+pub trait UserStore
+where
+    Self: Sync + Send,
+{
+    fn get_user(
+        &self,
+        user_id: UserId,
+    ) -> impl std::future::Future<Output = Result<UserEntity, AppError>> + std::marker::Send;
+    fn get_user_id_from_handle(
+        &self,
+        handle: &str,
+    ) -> impl std::future::Future<Output = Result<Option<UserId>, AppError>> + std::marker::Send;
+    // Returns false, and stores nothing, if the handle is already taken.
+    fn insert_user(
+        &self,
+        user: UserEntity,
+    ) -> impl std::future::Future<Output = Result<bool, AppError>> + std::marker::Send;
+    // Stores the user with its version bumped, only from the version it was
+    // read at. Returns whether it did.
+    fn save_user(
+        &self,
+        user: UserEntity,
+    ) -> impl std::future::Future<Output = Result<bool, AppError>> + std::marker::Send;
+    fn insert_session(
+        &self,
+        token: &str,
+        user_id: UserId,
+        ttl: u64,
+    ) -> impl std::future::Future<Output = Result<(), AppError>> + std::marker::Send;
+    fn get_session(
+        &self,
+        token: &str,
+    ) -> impl std::future::Future<Output = Result<Option<UserId>, AppError>> + std::marker::Send;
+}
+
+impl From<argon2::password_hash::Error> for AppError {
+    fn from(value: argon2::password_hash::Error) -> Self {
+        Self::new(ErrorKind::Internal, value.to_string())
+    }
+}
+
+fn argon2_hash(password: &str) -> Result<String, AppError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+// Checked against when the handle is unknown, so that signing in takes as
+// long whether the handle exists or not.
+static DUMMY_PASSWORD_HASH: LazyLock<String> =
+    LazyLock::new(|| argon2_hash("dummy password").unwrap());
+
+// Argon2 is slow on purpose, so it runs off the async workers.
+async fn hash_password(password: &str) -> Result<String, AppError> {
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || argon2_hash(&password)).await?
+}
+
+// No hash is checked against the dummy one, and never matches.
+async fn verify_password(password: &str, password_hash: Option<String>) -> Result<bool, AppError> {
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || {
+        let parsed = PasswordHash::new(password_hash.as_deref().unwrap_or(&DUMMY_PASSWORD_HASH))?;
+        let matches = Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok();
+        Ok(matches && password_hash.is_some())
+    })
+    .await?
+}
+
+async fn open_session(db: &impl UserStore, user_id: UserId) -> Result<String, AppError> {
+    let token = uuid::Uuid::new_v4().simple().to_string();
+    db.insert_session(&token, user_id, SESSION_TTL).await?;
+    Ok(token)
+}
+
+// Creates the account and returns a session token for it.
+pub async fn sign_up(
+    db: &impl UserStore,
+    authors: &impl AuthorStore,
+    handle: &str,
+    password: &str,
+) -> Result<String, AppError> {
+    let handle = slugify(handle);
+    if handle.is_empty() {
+        return Err(AppError::new(ErrorKind::BadRequest, "empty handle"));
+    }
+    if password.chars().count() < 8 {
+        return Err(AppError::new(ErrorKind::BadRequest, "password too short"));
+    }
+    let taken = || AppError::new(ErrorKind::Conflict, format!("handle {handle} is taken"));
+    // Handles share their namespace with every other author, such as group faces.
+    if !authors
+        .insert_author(AuthorEntity {
+            author_id: handle.clone(),
+            name: handle.clone(),
+            profile_picture: "".to_string(),
+        })
+        .await?
+    {
+        return Err(taken());
+    }
+    let user = UserEntity {
+        id: uuid::Uuid::new_v4(),
+        handle: handle.clone(),
+        password_hash: hash_password(password).await?,
+        groups: vec![],
+        block_list: vec![],
+        blocked_by: vec![],
+        version: 0,
+    };
+    let user_id = user.id;
+    // The author claimed above is given back if the user can't be stored.
+    match db.insert_user(user).await {
+        Ok(true) => open_session(db, user_id).await,
+        Ok(false) => {
+            authors.remove_author(&handle).await?;
+            Err(taken())
+        }
+        Err(err) => {
+            authors.remove_author(&handle).await?;
+            Err(err)
+        }
+    }
+}
+
+// Checks the credentials and returns a new session token.
+pub async fn sign_in(
+    db: &impl UserStore,
+    handle: &str,
+    password: &str,
+) -> Result<String, AppError> {
+    let bad_credentials = || AppError::new(ErrorKind::Unauthorized, "bad credentials");
+    let user = match db.get_user_id_from_handle(&slugify(handle)).await? {
+        Some(user_id) => Some(db.get_user(user_id).await?),
+        None => None,
+    };
+    let password_hash = user.as_ref().map(|user| user.password_hash.clone());
+    // Checked even for an unknown handle, against the dummy hash.
+    let matches = verify_password(password, password_hash).await?;
+    match user {
+        Some(user) if matches => open_session(db, user.id).await,
+        _ => Err(bad_credentials()),
+    }
+}
+
+// A session outliving its user, as after a failed sign-up, is signed out.
+pub async fn find_session_user(
+    db: &impl UserStore,
+    token: &str,
+) -> Result<Option<UserEntity>, AppError> {
+    let Some(user_id) = db.get_session(token).await? else {
+        return Ok(None);
+    };
+    match db.get_user(user_id).await {
+        Ok(user) => Ok(Some(user)),
+        Err(err) if err.kind == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+// Applies the change to a fresh read of the user and saves it, as
+// update_group does for groups.
+pub async fn update_user(
+    users: &impl UserStore,
+    user_id: UserId,
+    mut change: impl FnMut(&mut UserEntity) -> Result<bool, AppError>,
+) -> Result<UserEntity, AppError> {
+    for _ in 0..SAVE_ATTEMPTS {
+        let mut user = users.get_user(user_id).await?;
+        if !change(&mut user)? {
+            return Ok(user);
+        }
+        if users.save_user(user.clone()).await? {
+            user.version += 1;
+            return Ok(user);
+        }
+    }
+    Err(AppError::new(
+        ErrorKind::Conflict,
+        format!("user {user_id} is changing too often, try again"),
+    ))
+}
+
+pub async fn find_user_by_handle(
+    db: &impl UserStore,
+    handle: &str,
+) -> Result<UserEntity, AppError> {
+    let user_id = db
+        .get_user_id_from_handle(handle)
+        .await?
+        .ok_or(AppError::new(
+            ErrorKind::NotFound,
+            format!("no user {handle}"),
+        ))?;
+    db.get_user(user_id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::InMemoryRepository;
+
+    #[tokio::test]
+    async fn test_sign_up_then_sign_in() {
+        let db = InMemoryRepository::default();
+        let token = sign_up(&db, &db, "Alice", "correct horse").await.unwrap();
+        let user = find_session_user(&db, &token).await.unwrap().unwrap();
+        assert_eq!(user.handle, "alice");
+        assert_ne!(user.password_hash, "correct horse");
+        assert!(db.get_author("alice").await.unwrap().is_some());
+
+        let token = sign_in(&db, "alice", "correct horse").await.unwrap();
+        assert_eq!(
+            find_session_user(&db, &token).await.unwrap().unwrap().id,
+            user.id
+        );
+        let err = sign_in(&db, "alice", "wrong horse").await.unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Unauthorized);
+        let err = sign_in(&db, "bob", "correct horse").await.unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Unauthorized);
+    }
+
+    #[tokio::test]
+    async fn test_sign_up_rejects() {
+        let db = InMemoryRepository::default();
+        sign_up(&db, &db, "alice", "correct horse").await.unwrap();
+        let err = sign_up(&db, &db, "Alice", "another horse")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Conflict);
+        let err = sign_up(&db, &db, "bob", "short").await.unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadRequest);
+        let err = sign_up(&db, &db, "!!", "correct horse").await.unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadRequest);
+    }
+
+    // Fails every insert, as a store going down halfway through a sign-up.
+    struct FailingUsers(InMemoryRepository);
+
+    impl UserStore for FailingUsers {
+        async fn get_user(&self, user_id: UserId) -> Result<UserEntity, AppError> {
+            self.0.get_user(user_id).await
+        }
+
+        async fn get_user_id_from_handle(&self, handle: &str) -> Result<Option<UserId>, AppError> {
+            self.0.get_user_id_from_handle(handle).await
+        }
+
+        async fn insert_user(&self, _user: UserEntity) -> Result<bool, AppError> {
+            Err(AppError::new(ErrorKind::Internal, "store down"))
+        }
+
+        async fn save_user(&self, user: UserEntity) -> Result<bool, AppError> {
+            self.0.save_user(user).await
+        }
+
+        async fn insert_session(
+            &self,
+            token: &str,
+            user_id: UserId,
+            ttl: u64,
+        ) -> Result<(), AppError> {
+            self.0.insert_session(token, user_id, ttl).await
+        }
+
+        async fn get_session(&self, token: &str) -> Result<Option<UserId>, AppError> {
+            self.0.get_session(token).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_sign_up_frees_the_handle() {
+        let db = InMemoryRepository::default();
+        let err = sign_up(&FailingUsers(db.clone()), &db, "alice", "correct horse")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Internal);
+        assert!(db.get_author("alice").await.unwrap().is_none());
+        sign_up(&db, &db, "alice", "correct horse").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_salts_differ() {
+        let (first, second) = (
+            hash_password("correct horse").await.unwrap(),
+            hash_password("correct horse").await.unwrap(),
+        );
+        assert_ne!(first, second);
+        assert!(verify_password("correct horse", Some(first)).await.unwrap());
+        assert!(!verify_password("wrong horse", Some(second)).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_handle_never_matches_the_dummy_hash() {
+        assert!(!verify_password("dummy password", None).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_expired_session_is_signed_out() {
+        let db = InMemoryRepository::default();
+        let token = sign_up(&db, &db, "alice", "correct horse").await.unwrap();
+        let user_id = db.get_session(&token).await.unwrap().unwrap();
+        db.insert_session(&token, user_id, 0).await.unwrap();
+        assert!(find_session_user(&db, &token).await.unwrap().is_none());
+        assert!(find_session_user(&db, "unknown").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_session_of_missing_user_is_signed_out() {
+        let db = InMemoryRepository::default();
+        db.insert_session("token", uuid::Uuid::new_v4(), SESSION_TTL)
+            .await
+            .unwrap();
+        assert!(find_session_user(&db, "token").await.unwrap().is_none());
+    }
+}